@@ -0,0 +1,1590 @@
+use crate::basic_block::LoweringError;
+use crate::block_order::block_order;
+use crate::cfg::{Function, Program, Terminator};
+use crate::const_fold;
+use crate::debug_info::{
+    variable_places, DebugInfo, InstructionInfo, Location, Place, Scope, Variable,
+};
+use crate::dialect::{builtin, parse_literal_builtin, EvmVersion, Unavailable, EVM_BUILTINS};
+use crate::reorder::StatementReordering;
+use crate::scheduler::Op;
+use crate::source_map::{compress, op_statements, Jump, SourceLocation};
+use crate::spill::SpillBackend;
+use crate::ssa_block::{Block, Statement, Value};
+use crate::stack_scheduler::MAX_REACH;
+use crate::strategy::{Schedule, ScheduleError, StrategySelection};
+use ir::Literal;
+use ruint::aliases::U256;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::Range;
+
+pub const STOP: u8 = 0x00;
+pub const ADD: u8 = 0x01;
+pub const ISZERO: u8 = 0x15;
+pub const POP: u8 = 0x50;
+pub const MSTORE: u8 = 0x52;
+pub const JUMP: u8 = 0x56;
+pub const JUMPI: u8 = 0x57;
+pub const JUMPDEST: u8 = 0x5b;
+pub const PUSH0: u8 = 0x5f;
+pub const PUSH1: u8 = 0x60;
+pub const DUP1: u8 = 0x80;
+pub const SWAP1: u8 = 0x90;
+
+/// Largest deployed code accepted from Spurious Dragon on, EIP-170.
+pub const MAX_CODE_SIZE: usize = 0x6000;
+
+pub type Label = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    /// An opcode without immediate.
+    Opcode(u8),
+    /// Pushes the literal with the shortest `PUSHn`, `PUSH0` for zero from Shanghai.
+    Push(Literal),
+    /// Pushes the code offset of the label, with the shortest `PUSHn` found by relaxation.
+    PushLabel(Label),
+    /// Places the label, as a `JUMPDEST` if any push refers to it.
+    Label(Label),
+    /// Pushes the code offset of a sub-object or data section, `dataoffset`.
+    DataOffset(String),
+    /// Pushes the length of a sub-object or data section, `datasize`.
+    DataSize(String),
+    /// `PUSH32` placeholder of an immutable, `loadimmutable`.
+    Immutable(String),
+    /// `PUSH32` placeholder of a library address, `linkersymbol`.
+    LinkerSymbol(String),
+    /// Stores the value below the memory offset on top of the stack at every reference to the
+    /// immutable in a sub-object copied to that offset, consuming both, `setimmutable`.
+    SetImmutable(String),
+}
+
+/// Placeholder bytes in the code, like solc's `linkReferences` and `immutableReferences`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reference {
+    pub start: usize,
+    pub length: usize,
+}
+
+/// Immediate sizes of the pushes whose values depend on the layout of the code.
+struct Sizes<'a> {
+    /// Per label, zero for labels never pushed.
+    labels: Vec<usize>,
+    data_offsets: usize,
+    /// Sub-objects and data sections, relative to the end of the code.
+    data: &'a BTreeMap<String, Range<usize>>,
+    /// References to immutables within the sub-objects, relative to their start.
+    immutables: &'a BTreeMap<String, Vec<Reference>>,
+    version: EvmVersion,
+}
+
+impl Sizes<'_> {
+    /// Names are checked up front in `assemble_with`.
+    fn data(&self, name: &str) -> &Range<usize> {
+        &self.data[name]
+    }
+}
+
+impl Instruction {
+    fn size(&self, sizes: &Sizes) -> usize {
+        match self {
+            Instruction::Opcode(_) => 1,
+            Instruction::Label(label) => (sizes.labels[*label] > 0) as usize,
+            Instruction::Push(lit) => 1 + push_size(lit, sizes.version),
+            Instruction::PushLabel(label) => 1 + sizes.labels[*label],
+            Instruction::DataOffset(_) => 1 + sizes.data_offsets,
+            Instruction::DataSize(name) => {
+                1 + push_size(&to_literal(sizes.data(name).len()), sizes.version)
+            }
+            Instruction::Immutable(_) | Instruction::LinkerSymbol(_) => 33,
+            Instruction::SetImmutable(name) => set_immutable(sizes.immutables, name).len(),
+        }
+    }
+}
+
+/// Code of `setimmutable`: `mstore(add(offset, start), value)` for every reference, keeping
+/// the operands for all but the last.
+fn set_immutable(immutables: &BTreeMap<String, Vec<Reference>>, name: &str) -> Vec<u8> {
+    let references = immutables.get(name).map_or(&[][..], Vec::as_slice);
+    let mut code = vec![];
+    for (i, reference) in references.iter().enumerate() {
+        if i + 1 < references.len() {
+            code.extend([DUP1 + 1, DUP1 + 1]);
+        }
+        push(&mut code, reference.start, offset_size(reference.start));
+        code.extend([ADD, MSTORE]);
+    }
+    if references.is_empty() {
+        code.extend([POP, POP]);
+    }
+    code
+}
+
+/// Bytes of the immediate of the shortest push of `lit`, zero only if `version` has `PUSH0`.
+fn push_size(lit: &Literal, version: EvmVersion) -> usize {
+    let size = lit.iter().position(|byte| *byte != 0).map_or(0, |i| 32 - i);
+    size.max(!version.has_push0() as usize)
+}
+
+/// Bytes of the immediate of a push of the code offset, at least one so no `PUSH0` is needed.
+fn offset_size(offset: usize) -> usize {
+    (usize::BITS - offset.leading_zeros()).div_ceil(8).max(1) as usize
+}
+
+fn to_literal(value: usize) -> Literal {
+    const_fold::to_literal(U256::from(value))
+}
+
+/// Linked code with the offsets of its labels.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bytecode {
+    pub code: Vec<u8>,
+    /// Offset of each label's `JUMPDEST`, `None` for labels never placed.
+    pub labels: Vec<Option<usize>>,
+    /// Sub-objects and data sections appended to the code, nested ones by their dotted path.
+    pub data: BTreeMap<String, Range<usize>>,
+    /// Placeholders of library addresses still to be linked, including those in sub-objects.
+    pub links: BTreeMap<String, Vec<Reference>>,
+    /// Placeholders of immutables, filled by `setimmutable` of the parent object when it is
+    /// deployed, or patched with `set_immutable`.
+    pub immutables: BTreeMap<String, Vec<Reference>>,
+    /// Location of every instruction of the code, without those of sub-objects.
+    pub source_map: Vec<SourceLocation>,
+    /// Variables and function scopes of the code, without those of sub-objects.
+    pub debug_info: DebugInfo,
+    /// The metadata trailer ending the code, if appended.
+    pub metadata: Option<Range<usize>>,
+}
+
+/// Failure to assemble code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssemblyError {
+    /// More than one sub-object refers to the immutable, so `setimmutable` cannot tell which
+    /// one to patch.
+    DuplicateImmutable(String),
+    /// `dataoffset` or `datasize` of a name that is neither a sub-object nor a data section.
+    UnknownData(String),
+    Schedule(ScheduleError),
+    /// Call of a builtin the targeted version does not have.
+    Unavailable(Unavailable),
+    /// Yul code that has no `cfg::Program`, see `Object::from_yul`.
+    Lowering(LoweringError),
+    /// Call of a name that is neither a builtin nor a function of the program.
+    UnknownFunction(String),
+    /// The function calls itself, directly or through others, while using spill slots, which
+    /// the inner call would overwrite.
+    Recursion(String),
+    /// A `memoryguard` claims the memory up to `claimed` for the code, past the first spill
+    /// slot at `first_slot`.
+    MemoryGuard {
+        claimed: U256,
+        first_slot: usize,
+    },
+}
+
+impl From<ScheduleError> for AssemblyError {
+    fn from(err: ScheduleError) -> Self {
+        AssemblyError::Schedule(err)
+    }
+}
+
+impl From<LoweringError> for AssemblyError {
+    fn from(err: LoweringError) -> Self {
+        AssemblyError::Lowering(err)
+    }
+}
+
+impl From<Unavailable> for AssemblyError {
+    fn from(err: Unavailable) -> Self {
+        AssemblyError::Unavailable(err)
+    }
+}
+
+/// Code larger than `MAX_CODE_SIZE`, not counting its metadata trailer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeTooLarge {
+    pub size: usize,
+}
+
+impl From<Vec<u8>> for Bytecode {
+    fn from(code: Vec<u8>) -> Self {
+        Self {
+            code,
+            ..Default::default()
+        }
+    }
+}
+
+impl Bytecode {
+    pub fn size(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Fails if the code without its metadata trailer exceeds `MAX_CODE_SIZE`.
+    pub fn check_size(&self) -> Result<(), CodeTooLarge> {
+        let size = self.size() - self.metadata.as_ref().map_or(0, Range::len);
+        match size <= MAX_CODE_SIZE {
+            true => Ok(()),
+            false => Err(CodeTooLarge { size }),
+        }
+    }
+
+    /// Appends `trailer` after all code and data, see `Metadata::trailer`.
+    pub fn append_metadata(&mut self, trailer: &[u8]) {
+        assert!(self.metadata.is_none(), "Metadata appended twice");
+        self.metadata = Some(self.size()..self.size() + trailer.len());
+        self.code.extend(trailer);
+    }
+
+    pub fn listing(&self) -> String {
+        disassemble(&self.code)
+    }
+
+    /// The source map in solc's compressed format.
+    pub fn compressed_source_map(&self) -> String {
+        compress(&self.source_map)
+    }
+
+    fn patch(&mut self, references: &[Reference], value: &[u8]) {
+        for reference in references {
+            let end = reference.start + reference.length;
+            self.code[reference.start..end]
+                .copy_from_slice(&value[value.len() - reference.length..]);
+        }
+    }
+
+    /// Patches the address of the library `name` into its placeholders. Returns whether the
+    /// code refers to the library.
+    pub fn link(&mut self, name: &str, address: [u8; 20]) -> bool {
+        let Some(references) = self.links.remove(name) else {
+            return false;
+        };
+        let mut value = [0; 32];
+        value[12..].copy_from_slice(&address);
+        self.patch(&references, &value);
+        true
+    }
+
+    /// Patches the value of the immutable `name` into its placeholders, for code deployed
+    /// without a parent running `setimmutable`. Returns whether the code refers to it.
+    pub fn set_immutable(&mut self, name: &str, value: Literal) -> bool {
+        let Some(references) = self.immutables.remove(name) else {
+            return false;
+        };
+        self.patch(&references, &value);
+        true
+    }
+}
+
+/// Collects instructions and assembles them into EVM bytecode.
+///
+/// User functions are called by jumping to their label and return by a `JUMP` to the return
+/// address, following the calling convention of `cfg::Program`.
+#[derive(Debug, Clone, Default)]
+pub struct Assembler {
+    /// Where the `MemVar*` slots of the ops live.
+    pub spill: SpillBackend,
+    pub version: EvmVersion,
+    /// Whether `program` puts the statements of every block in `statement_order` before
+    /// scheduling them.
+    pub reorder: bool,
+    pub instructions: Vec<Instruction>,
+    /// Location of each instruction, instructions pushed directly having none.
+    locations: Vec<SourceLocation>,
+    /// Location of the instructions appended next.
+    location: SourceLocation,
+    /// Variables visible before each instruction, known at the start of scheduled ops only.
+    variables: Vec<Option<Vec<Variable>>>,
+    /// Variables visible before the next instruction appended.
+    next_variables: Option<Vec<Variable>>,
+    /// Ops `memoryguard` calls become, keeping the spill area of the program being appended
+    /// out of the memory its code allocates.
+    memory_guard: Vec<Op<'static>>,
+    /// Functions appended, with their ranges of instructions.
+    scopes: Vec<Scope>,
+    labels: usize,
+    functions: BTreeMap<String, Label>,
+}
+
+impl Assembler {
+    pub fn new(spill: SpillBackend) -> Self {
+        Self {
+            spill,
+            ..Default::default()
+        }
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    /// Label of the entry of the user function `name`.
+    pub fn function_label(&mut self, name: &str) -> Label {
+        if let Some(label) = self.functions.get(name) {
+            return *label;
+        }
+        let label = self.new_label();
+        self.functions.insert(name.to_owned(), label);
+        label
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.locations
+            .resize(self.instructions.len(), SourceLocation::default());
+        self.variables.resize(self.instructions.len(), None);
+        self.instructions.push(instruction);
+        self.locations.push(self.location);
+        self.variables.push(self.next_variables.take());
+    }
+
+    /// Appends a `JUMP` marked as calling or returning from a function in the source map.
+    fn function_jump(&mut self, jump: Jump) {
+        let location = self.location;
+        self.location.jump = jump;
+        self.opcode(JUMP);
+        self.location = location;
+    }
+
+    pub fn place(&mut self, label: Label) {
+        self.emit(Instruction::Label(label));
+    }
+
+    pub fn opcode(&mut self, opcode: u8) {
+        self.emit(Instruction::Opcode(opcode));
+    }
+
+    pub fn jump(&mut self, to: Label) {
+        self.emit(Instruction::PushLabel(to));
+        self.opcode(JUMP);
+    }
+
+    /// Jumps to `to` if the top of the stack is non-zero, consuming it.
+    pub fn jump_if(&mut self, to: Label) {
+        self.emit(Instruction::PushLabel(to));
+        self.opcode(JUMPI);
+    }
+
+    /// Appends scheduled ops, slots becoming accesses of the spill backend, like `MLOAD`/`MSTORE`
+    /// of their address in memory. Fails without appending anything on builtins the targeted
+    /// version does not have, those the spill backend lowers to included.
+    pub fn ops(&mut self, ops: &[Op]) -> Result<(), Unavailable> {
+        let lowered: Vec<Op> = self
+            .spill
+            .lower(ops, self.version)
+            .into_iter()
+            .flat_map(|op| match op {
+                Op::CallFn("memoryguard") => self.memory_guard.clone(),
+                op => vec![op],
+            })
+            .collect();
+        for op in lowered.iter() {
+            if let Op::CallFn(name) = op {
+                self.version.check(name)?;
+            }
+        }
+        for op in lowered {
+            match op {
+                Op::Swap(n) => self.opcode(SWAP1 + reach(n) - 1),
+                Op::Dup(n) => self.opcode(DUP1 + reach(n) - 1),
+                Op::Pop => self.opcode(POP),
+                Op::Push(lit) => self.emit(Instruction::Push(lit)),
+                Op::CallFn(name) => match (builtin(name), parse_literal_builtin(name)) {
+                    (Some(builtin), _) => self.opcode(builtin.opcode),
+                    (None, Some(("dataoffset", input))) => {
+                        self.emit(Instruction::DataOffset(input.to_owned()))
+                    }
+                    (None, Some(("datasize", input))) => {
+                        self.emit(Instruction::DataSize(input.to_owned()))
+                    }
+                    (None, Some(("loadimmutable", input))) => {
+                        self.emit(Instruction::Immutable(input.to_owned()))
+                    }
+                    (None, Some(("linkersymbol", input))) => {
+                        self.emit(Instruction::LinkerSymbol(input.to_owned()))
+                    }
+                    (None, Some(("setimmutable", input))) => {
+                        self.emit(Instruction::SetImmutable(input.to_owned()))
+                    }
+                    (None, Some((other, _))) => unreachable!("Unhandled builtin {}", other),
+                    (None, None) => {
+                        let ret = self.new_label();
+                        let function = self.function_label(name);
+                        self.emit(Instruction::PushLabel(ret));
+                        self.emit(Instruction::PushLabel(function));
+                        self.function_jump(Jump::Into);
+                        self.place(ret);
+                    }
+                },
+                op => unreachable!("{:?} left after lowering memory ops", op),
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends ops scheduled for `block`, each located at the span of the statement it belongs
+    /// to, see `op_statements`. The first instruction of each op records the variables visible
+    /// before it, but for `hidden`, a return address. Runs of `MemCopy` are appended together so
+    /// they can still be lowered to `mcopy`.
+    fn located_ops(
+        &mut self,
+        block: &Block,
+        ops: &[Op],
+        hidden: Option<&str>,
+    ) -> Result<(), Unavailable> {
+        let statements = op_statements(block, ops);
+        let places = variable_places(block, ops);
+        let mut start = 0;
+        while start < ops.len() {
+            let statement = statements[start];
+            let end = start
+                + 1
+                + ops[start + 1..]
+                    .iter()
+                    .zip(&statements[start + 1..])
+                    .take_while(|(op, other)| {
+                        **other == statement
+                            && matches!((&ops[start], op), (Op::MemCopy { .. }, Op::MemCopy { .. }))
+                    })
+                    .count();
+            self.location.span = statement.and_then(|i| block.statements[i].span());
+            self.next_variables = Some(
+                places[start]
+                    .iter()
+                    .filter(|(name, _)| Some(name.as_str()) != hidden)
+                    .map(|(name, place)| Variable {
+                        name: name.clone(),
+                        location: match *place {
+                            Place::Stack(depth) => Location::Stack { depth },
+                            Place::Slot(slot) => self.spill.location(slot),
+                        },
+                    })
+                    .collect(),
+            );
+            self.ops(&ops[start..end])?;
+            self.next_variables = None;
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Offsets of the labels and the size of the code, with pushes sized by `sizes`.
+    fn offsets(&self, sizes: &Sizes) -> (Vec<Option<usize>>, usize) {
+        let mut offsets: Vec<Option<usize>> = vec![None; self.labels];
+        let mut offset = 0;
+        for instruction in self.instructions.iter() {
+            if let Instruction::Label(label) = instruction {
+                assert!(offsets[*label].is_none(), "Label {} placed twice", label);
+                offsets[*label] = Some(offset);
+            }
+            offset += instruction.size(sizes);
+        }
+        (offsets, offset)
+    }
+
+    /// Assembles code without sub-objects or data sections, failing only on `dataoffset` and
+    /// `datasize` of them.
+    pub fn assemble(&self) -> Result<Bytecode, AssemblyError> {
+        self.assemble_with(&[])
+    }
+
+    /// Places the labels and encodes the instructions, followed by the `blobs`, the
+    /// sub-objects and data sections `DataOffset` and `DataSize` refer to. Pushes of labels and
+    /// data offsets start out as `PUSH1` and are widened until every offset fits, as widening a
+    /// push moves all later code. Sizes only ever grow, so this reaches a fixpoint.
+    pub fn assemble_with(&self, blobs: &[(String, Bytecode)]) -> Result<Bytecode, AssemblyError> {
+        let mut data: BTreeMap<String, Range<usize>> = BTreeMap::new();
+        let mut immutables: BTreeMap<String, Vec<Reference>> = BTreeMap::new();
+        let mut end = 0;
+        for (name, blob) in blobs {
+            data.insert(name.clone(), end..end + blob.size());
+            for (immutable, references) in blob.immutables.iter() {
+                if immutables
+                    .insert(immutable.clone(), references.clone())
+                    .is_some()
+                {
+                    return Err(AssemblyError::DuplicateImmutable(immutable.clone()));
+                }
+            }
+            for (nested, range) in blob.data.iter() {
+                data.insert(
+                    format!("{}.{}", name, nested),
+                    end + range.start..end + range.end,
+                );
+            }
+            end += blob.size();
+        }
+
+        for instruction in self.instructions.iter() {
+            if let Instruction::DataOffset(name) | Instruction::DataSize(name) = instruction {
+                if !data.contains_key(name) {
+                    return Err(AssemblyError::UnknownData(name.clone()));
+                }
+            }
+        }
+
+        let mut sizes = Sizes {
+            labels: vec![0; self.labels],
+            data_offsets: 1,
+            data: &data,
+            immutables: &immutables,
+            version: self.version,
+        };
+        for instruction in self.instructions.iter() {
+            if let Instruction::PushLabel(label) = instruction {
+                sizes.labels[*label] = 1;
+            }
+        }
+        let (offsets, code_size) = loop {
+            let (offsets, code_size) = self.offsets(&sizes);
+            let mut changed = false;
+            for (size, offset) in sizes.labels.iter_mut().zip(offsets.iter()) {
+                if *size == 0 {
+                    continue;
+                }
+                let needed = offset.map_or(1, offset_size);
+                if needed > *size {
+                    *size = needed;
+                    changed = true;
+                }
+            }
+            let needed = self
+                .instructions
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    Instruction::DataOffset(name) => {
+                        Some(offset_size(code_size + sizes.data(name).start))
+                    }
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(1);
+            if needed > sizes.data_offsets {
+                sizes.data_offsets = needed;
+                changed = true;
+            }
+            if !changed {
+                break (offsets, code_size);
+            }
+        };
+
+        let mut code = vec![];
+        let mut links: BTreeMap<String, Vec<Reference>> = BTreeMap::new();
+        let mut own_immutables: BTreeMap<String, Vec<Reference>> = BTreeMap::new();
+        let placeholder = |code: &mut Vec<u8>| {
+            code.push(PUSH0 + 32);
+            code.extend([0; 32]);
+            Reference {
+                start: code.len() - 32,
+                length: 32,
+            }
+        };
+        let mut source_map = vec![];
+        let mut instruction_infos = vec![];
+        let mut starts = vec![];
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            let start = code.len();
+            starts.push(start);
+            match instruction {
+                Instruction::Opcode(opcode) => code.push(*opcode),
+                Instruction::Label(label) if sizes.labels[*label] > 0 => code.push(JUMPDEST),
+                Instruction::Label(_) => (),
+                Instruction::Push(lit) => {
+                    let size = push_size(lit, self.version);
+                    code.push(PUSH0 + size as u8);
+                    code.extend(&lit[32 - size..]);
+                }
+                Instruction::PushLabel(label) => {
+                    let offset =
+                        offsets[*label].unwrap_or_else(|| panic!("Label {} never placed", label));
+                    push(&mut code, offset, sizes.labels[*label]);
+                }
+                Instruction::DataOffset(name) => push(
+                    &mut code,
+                    code_size + sizes.data(name).start,
+                    sizes.data_offsets,
+                ),
+                Instruction::DataSize(name) => {
+                    let size = sizes.data(name).len();
+                    push(&mut code, size, push_size(&to_literal(size), self.version));
+                }
+                Instruction::Immutable(name) => own_immutables
+                    .entry(name.clone())
+                    .or_default()
+                    .push(placeholder(&mut code)),
+                Instruction::LinkerSymbol(name) => links
+                    .entry(name.clone())
+                    .or_default()
+                    .push(placeholder(&mut code)),
+                Instruction::SetImmutable(name) => code.extend(set_immutable(&immutables, name)),
+            }
+            let location = self.locations.get(i).copied().unwrap_or_default();
+            let variables = self.variables.get(i).cloned().flatten();
+            for (j, offset) in opcode_offsets(&code[start..]).into_iter().enumerate() {
+                source_map.push(location);
+                instruction_infos.push(InstructionInfo {
+                    offset: start + offset,
+                    variables: if j == 0 { variables.clone() } else { None },
+                });
+            }
+        }
+        starts.push(code.len());
+        debug_assert_eq!(code.len(), code_size);
+        let debug_info = DebugInfo {
+            instructions: instruction_infos,
+            scopes: self
+                .scopes
+                .iter()
+                .map(|scope| Scope {
+                    start: starts[scope.start],
+                    end: starts[scope.end],
+                    ..scope.clone()
+                })
+                .collect(),
+        };
+        for (_, blob) in blobs {
+            for (name, references) in blob.links.iter() {
+                links
+                    .entry(name.clone())
+                    .or_default()
+                    .extend(references.iter().map(|reference| Reference {
+                        start: code.len() + reference.start,
+                        length: reference.length,
+                    }));
+            }
+            code.extend(&blob.code);
+        }
+        Ok(Bytecode {
+            code,
+            labels: offsets,
+            links,
+            immutables: own_immutables,
+            source_map,
+            debug_info,
+            metadata: None,
+            data: data
+                .into_iter()
+                .map(|(name, range)| (name, code_size + range.start..code_size + range.end))
+                .collect(),
+        })
+    }
+
+    /// Appends the nodes of a function in `block_order`, each scheduled by the corresponding
+    /// entry of `schedules`, followed by the jumps of its terminator. Jumps to the next node
+    /// fall through instead, a branch inverting its condition if its `non_zero` side is next.
+    /// A named function's entry gets the function's label, so calls to it resolve. Returns the
+    /// labels of the nodes, failing without appending anything if the function calls builtins
+    /// the targeted version does not have or the spill backend needs a later version. The
+    /// instructions are located at the spans of the statements their ops belong to and make up
+    /// a scope of the debug info, `main` if unnamed.
+    pub fn function(
+        &mut self,
+        name: Option<&str>,
+        function: &Function,
+        schedules: &[Schedule],
+    ) -> Result<Vec<Label>, Unavailable> {
+        function
+            .callees()
+            .try_for_each(|callee| self.version.check(callee))?;
+        self.spill.check(self.version)?;
+        let labels: Vec<Label> = (0..function.nodes.len())
+            .map(|id| match (id, name) {
+                (0, Some(name)) => self.function_label(name),
+                _ => self.new_label(),
+            })
+            .collect();
+        let entry = &function.nodes[0].block.start_stack;
+        let (parameters, ret) = match name {
+            Some(_) => entry.split_at(entry.len().saturating_sub(1)),
+            None => (&entry[..], &[][..]),
+        };
+        let hidden = ret.first().map(String::as_str);
+        let mut scope = Scope {
+            name: name.unwrap_or("main").to_owned(),
+            start: self.instructions.len(),
+            end: 0,
+            parameters: parameters.iter().rev().cloned().collect(),
+        };
+        let order = block_order(function);
+        for (i, id) in order.iter().enumerate() {
+            let next = order.get(i + 1).copied();
+            let block = &function.nodes[*id].block;
+            self.location = SourceLocation {
+                span: block.statements.first().and_then(Statement::span),
+                jump: Jump::Regular,
+            };
+            self.place(labels[*id]);
+            self.located_ops(block, &schedules[*id].ops, hidden)?;
+            match function.nodes[*id].exit {
+                Terminator::Jump(to) if next == Some(to) => (),
+                Terminator::Jump(to) => self.jump(labels[to]),
+                Terminator::Branch { non_zero, zero } if next == Some(zero) => {
+                    self.jump_if(labels[non_zero])
+                }
+                Terminator::Branch { non_zero, zero } if next == Some(non_zero) => {
+                    self.opcode(ISZERO);
+                    self.jump_if(labels[zero]);
+                }
+                Terminator::Branch { non_zero, zero } => {
+                    self.jump_if(labels[non_zero]);
+                    self.jump(labels[zero]);
+                }
+                Terminator::Leave => self.function_jump(Jump::Out),
+                Terminator::Halt if ends_in_halt(&function.nodes[*id].block) => (),
+                Terminator::Halt => self.opcode(STOP),
+            }
+        }
+        self.location = SourceLocation::default();
+        scope.end = self.instructions.len();
+        self.scopes.push(scope);
+        Ok(labels)
+    }
+
+    /// Appends a whole program, `main` first, each function scheduled by the strategy
+    /// `selection` picks for it, weighing spills by the cost of the spill backend. Every function
+    /// gets slots of its own, after those of `main`, so calls never clobber the caller's slots,
+    /// which rules out recursion through functions using slots. `main` starts with the
+    /// backend's prologue covering all slots. Fails before appending anything on calls of
+    /// builtins the targeted version does not have, see `Program::check_evm_version`, on
+    /// spill backends it does not support, see `SpillBackend::check`, on calls of unknown
+    /// functions and on recursion through functions using slots. With `reorder` set the
+    /// statements of a copy of the program are reordered first.
+    pub fn program(
+        &mut self,
+        program: &Program,
+        selection: &StrategySelection,
+    ) -> Result<(), AssemblyError> {
+        program.check_evm_version(self.version)?;
+        self.spill.check(self.version)?;
+        let unknown =
+            |callee: &&str| !is_builtin(callee) && !program.functions.contains_key(*callee);
+        if let Some(callee) = std::iter::once(&program.main)
+            .chain(program.functions.values())
+            .flat_map(Function::callees)
+            .find(unknown)
+        {
+            return Err(AssemblyError::UnknownFunction(callee.to_owned()));
+        }
+        let reordered;
+        let program = match self.reorder {
+            true => {
+                let mut copy = program.clone();
+                copy.reorder_statements();
+                reordered = copy;
+                &reordered
+            }
+            false => program,
+        };
+        let schedules = selection.schedule_program_with(program, &self.spill.cost())?;
+        let slots = |schedules: &[Schedule]| {
+            schedules
+                .iter()
+                .map(|schedule| schedule.slots)
+                .max()
+                .unwrap_or(0)
+        };
+        let total = slots(&schedules.main)
+            + schedules
+                .functions
+                .values()
+                .map(|schedules| slots(schedules))
+                .sum::<usize>();
+        let recursive = |(name, schedules): (&String, &Vec<Schedule>)| {
+            slots(schedules) > 0 && is_recursive(program, name)
+        };
+        if let Some((name, _)) = schedules.functions.iter().find(|&f| recursive(f)) {
+            return Err(AssemblyError::Recursion(name.clone()));
+        }
+        let spill = self.spill;
+        if let (SpillBackend::Memory(layout), true) = (spill, total > 0) {
+            let first_slot = layout.address(0);
+            let past_slots = |claimed: &U256| *claimed > U256::from(first_slot);
+            if let Some(claimed) = memory_guards(program).find(past_slots) {
+                return Err(AssemblyError::MemoryGuard {
+                    claimed,
+                    first_slot,
+                });
+            }
+        }
+        self.memory_guard = spill.memory_guard(total);
+        self.ops(&spill.prologue(total))?;
+        self.function(None, &program.main, &schedules.main)?;
+
+        let mut first_slot = slots(&schedules.main);
+        for (name, function) in program.functions.iter() {
+            let schedules = &schedules.functions[name];
+            self.spill = spill.skip(first_slot);
+            self.function(Some(name), function, schedules)?;
+            first_slot += slots(schedules);
+        }
+        self.spill = spill;
+        Ok(())
+    }
+}
+
+/// Whether a call of `name` is one of a builtin rather than of a user function.
+fn is_builtin(name: &str) -> bool {
+    builtin(name).is_some() || parse_literal_builtin(name).is_some() || name == "memoryguard"
+}
+
+/// Whether the function `name` of the program calls itself, directly or through others.
+fn is_recursive(program: &Program, name: &str) -> bool {
+    let mut seen = BTreeSet::new();
+    let mut callers = vec![name];
+    while let Some(caller) = callers.pop() {
+        for callee in program.functions[caller].callees() {
+            if callee == name {
+                return true;
+            }
+            if program.functions.contains_key(callee) && seen.insert(callee) {
+                callers.push(callee);
+            }
+        }
+    }
+    false
+}
+
+/// Literal arguments of the `memoryguard` calls in the program.
+fn memory_guards(program: &Program) -> impl Iterator<Item = U256> + '_ {
+    std::iter::once(&program.main)
+        .chain(program.functions.values())
+        .flat_map(|function| function.nodes.iter())
+        .flat_map(|node| node.block.statements.iter())
+        .filter_map(|stmt| match stmt {
+            Statement::CallAssign { calls, takes, .. } if calls == "memoryguard" => {
+                match takes.first() {
+                    Some(Value::Literal(lit)) => Some(const_fold::to_word(lit)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+}
+
+/// Whether the block ends in a builtin execution never continues past.
+fn ends_in_halt(block: &Block) -> bool {
+    block
+        .statements
+        .last()
+        .and_then(|stmt| stmt.callee())
+        .and_then(builtin)
+        .is_some_and(|builtin| builtin.halts)
+}
+
+/// Offsets of the instructions in `code`, skipping push immediates.
+fn opcode_offsets(code: &[u8]) -> Vec<usize> {
+    let mut offsets = vec![];
+    let mut offset = 0;
+    while offset < code.len() {
+        offsets.push(offset);
+        if let PUSH0..=0x7f = code[offset] {
+            offset += (code[offset] - PUSH0) as usize;
+        }
+        offset += 1;
+    }
+    offsets
+}
+
+fn push(code: &mut Vec<u8>, value: usize, size: usize) {
+    code.push(PUSH0 + size as u8);
+    code.extend(&value.to_be_bytes()[8 - size..]);
+}
+
+fn reach(n: usize) -> u8 {
+    assert!(
+        (1..=MAX_REACH).contains(&n),
+        "Stack depth {} out of reach",
+        n
+    );
+    n as u8
+}
+
+/// Mnemonic of an opcode, `None` for undefined ones.
+pub fn mnemonic(opcode: u8) -> Option<String> {
+    let name = match opcode {
+        JUMP => "JUMP".to_owned(),
+        JUMPI => "JUMPI".to_owned(),
+        0x58 => "PC".to_owned(),
+        JUMPDEST => "JUMPDEST".to_owned(),
+        PUSH0..=0x7f => format!("PUSH{}", opcode - PUSH0),
+        DUP1..=0x8f => format!("DUP{}", opcode - DUP1 + 1),
+        SWAP1..=0x9f => format!("SWAP{}", opcode - SWAP1 + 1),
+        _ => EVM_BUILTINS
+            .iter()
+            .find(|builtin| builtin.opcode == opcode)?
+            .name
+            .to_uppercase(),
+    };
+    Some(name)
+}
+
+/// Listing of the code, one instruction per line with its offset and immediate.
+pub fn disassemble(code: &[u8]) -> String {
+    let mut listing = String::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = code[offset];
+        let name = mnemonic(opcode).unwrap_or_else(|| format!("INVALID({:#04x})", opcode));
+        write!(listing, "{:04x}: {}", offset, name).unwrap();
+        offset += 1;
+        if (PUSH1..=0x7f).contains(&opcode) {
+            let end = code.len().min(offset + (opcode - PUSH0) as usize);
+            write!(listing, " 0x").unwrap();
+            code[offset..end]
+                .iter()
+                .for_each(|byte| write!(listing, "{:02x}", byte).unwrap());
+            offset = end;
+        }
+        listing.push('\n');
+    }
+    listing
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::const_fold::to_literal;
+    use crate::memory_layout::MemoryLayout;
+    use crate::stack_scheduler::test::{call, lit, node, r};
+    use crate::strategy::Strategy;
+    use ir::Span;
+    use ruint::aliases::U256;
+
+    #[derive(Debug, Default)]
+    pub(crate) struct Execution {
+        pub(crate) stack: Vec<U256>,
+        pub(crate) storage: BTreeMap<U256, U256>,
+        pub(crate) transient: BTreeMap<U256, U256>,
+        /// Data passed to `RETURN`.
+        pub(crate) output: Vec<u8>,
+    }
+
+    /// Range of memory accessed, growing memory to cover it.
+    fn memory_range(memory: &mut Vec<u8>, offset: U256, size: usize) -> Range<usize> {
+        let offset = offset.to::<usize>();
+        if memory.len() < offset + size {
+            memory.resize((offset + size).div_ceil(32) * 32, 0);
+        }
+        offset..offset + size
+    }
+
+    /// Runs code using only arithmetic, memory, storage, code and control flow opcodes until it
+    /// stops, returns or runs off its end.
+    pub(crate) fn execute(code: &[u8]) -> Execution {
+        let mut stack: Vec<U256> = vec![];
+        let mut memory: Vec<u8> = vec![];
+        let mut storage: BTreeMap<U256, U256> = BTreeMap::new();
+        let mut transient: BTreeMap<U256, U256> = BTreeMap::new();
+        let mut output = vec![];
+        let mut pc = 0;
+        let mut steps = 0;
+        while pc < code.len() {
+            steps += 1;
+            assert!(steps < 100_000, "Code does not terminate");
+            let opcode = code[pc];
+            pc += 1;
+            let mut pop = || stack.pop().expect("Stack underflow");
+            let pushed = match opcode {
+                0x00 => break,
+                0x01..=0x03 | 0x10 | 0x11 | 0x14 => {
+                    let (a, b) = (pop(), pop());
+                    Some(match opcode {
+                        0x01 => a.wrapping_add(b),
+                        0x02 => a.wrapping_mul(b),
+                        0x03 => a.wrapping_sub(b),
+                        0x10 => U256::from(a < b),
+                        0x11 => U256::from(a > b),
+                        _ => U256::from(a == b),
+                    })
+                }
+                0x15 => Some(U256::from(pop().is_zero())),
+                POP => {
+                    pop();
+                    None
+                }
+                0x39 => {
+                    let (to, from, size) = (pop(), pop().to::<usize>(), pop().to::<usize>());
+                    let range = memory_range(&mut memory, to, size);
+                    memory[range].copy_from_slice(&code[from..from + size]);
+                    None
+                }
+                0x51 => {
+                    let range = memory_range(&mut memory, pop(), 32);
+                    Some(U256::from_be_slice(&memory[range]))
+                }
+                0x52 => {
+                    let (address, value) = (pop(), pop());
+                    let range = memory_range(&mut memory, address, 32);
+                    memory[range].copy_from_slice(&value.to_be_bytes::<32>());
+                    None
+                }
+                0x5c => Some(transient.get(&pop()).copied().unwrap_or_default()),
+                0x5d => {
+                    let (key, value) = (pop(), pop());
+                    transient.insert(key, value);
+                    None
+                }
+                0x5e => {
+                    let (to, from, size) = (pop(), pop(), pop().to::<usize>());
+                    let from = memory_range(&mut memory, from, size);
+                    let to = memory_range(&mut memory, to, size);
+                    memory.copy_within(from, to.start);
+                    None
+                }
+                0x54 => Some(storage.get(&pop()).copied().unwrap_or_default()),
+                0x55 => {
+                    let (key, value) = (pop(), pop());
+                    storage.insert(key, value);
+                    None
+                }
+                JUMP | JUMPI => {
+                    let to = pop().to::<usize>();
+                    if opcode == JUMP || !pop().is_zero() {
+                        assert_eq!(code[to], JUMPDEST, "Jump to {:#x} without JUMPDEST", to);
+                        pc = to;
+                    }
+                    None
+                }
+                JUMPDEST => None,
+                PUSH0..=0x7f => {
+                    let size = (opcode - PUSH0) as usize;
+                    let value = U256::from_be_slice(&code[pc..pc + size]);
+                    pc += size;
+                    Some(value)
+                }
+                DUP1..=0x8f => Some(stack[stack.len() - 1 - (opcode - DUP1) as usize]),
+                SWAP1..=0x9f => {
+                    let top = stack.len() - 1;
+                    stack.swap(top, top - 1 - (opcode - SWAP1) as usize);
+                    None
+                }
+                0xf3 => {
+                    let (offset, size) = (pop(), pop().to::<usize>());
+                    let range = memory_range(&mut memory, offset, size);
+                    output = memory[range].to_vec();
+                    break;
+                }
+                _ => panic!("Unsupported opcode {:#04x} at {:#x}", opcode, pc - 1),
+            };
+            stack.extend(pushed);
+        }
+        Execution {
+            stack,
+            storage,
+            transient,
+            output,
+        }
+    }
+
+    fn push(value: u64) -> Op<'static> {
+        Op::Push(to_literal(U256::from(value)))
+    }
+
+    #[test]
+    fn test_assemble_ops() {
+        let mut assembler = Assembler::default();
+        assembler
+            .ops(&[
+                push(0),
+                push(0xff),
+                push(0x100),
+                Op::Dup(16),
+                Op::Swap(1),
+                Op::CallFn("add"),
+                Op::MemVarStore(1),
+                Op::Pop,
+            ])
+            .unwrap();
+        assert_eq!(
+            assembler.assemble().unwrap().code,
+            vec![0x5f, 0x60, 0xff, 0x61, 0x01, 0x00, 0x8f, 0x90, 0x01, 0x60, 0xa0, 0x52, 0x50]
+        );
+    }
+
+    #[test]
+    fn test_evm_versions() {
+        let ops = [
+            push(0),
+            Op::MemVarStore(0),
+            push(5),
+            Op::MemVarStore(1),
+            Op::MemCopy { from: 0, to: 2 },
+            Op::MemCopy { from: 1, to: 3 },
+            Op::MemVarLoad(3),
+        ];
+        let assemble = |version| {
+            let mut assembler = Assembler {
+                version,
+                ..Default::default()
+            };
+            assembler.ops(&ops).unwrap();
+            assembler.assemble().unwrap().code
+        };
+        let cancun = assemble(EvmVersion::Cancun);
+        let paris = assemble(EvmVersion::Paris);
+        assert_eq!(&cancun[..1], &[PUSH0]);
+        assert_eq!(&paris[..2], &[PUSH1, 0]);
+        assert!(cancun.contains(&0x5e) && !paris.contains(&0x5e));
+        assert!(cancun.len() < paris.len());
+        for code in [cancun, paris] {
+            assert_eq!(execute(&code).stack, vec![U256::from(5)]);
+        }
+    }
+
+    #[test]
+    fn test_unavailable_builtin() {
+        let mut assembler = Assembler {
+            version: EvmVersion::Shanghai,
+            ..Default::default()
+        };
+        assert_eq!(
+            assembler.ops(&[push(0), Op::CallFn("tload")]),
+            Err(Unavailable {
+                builtin: "tload".to_owned(),
+                since: EvmVersion::Cancun,
+                version: EvmVersion::Shanghai,
+            })
+        );
+        assert!(assembler.instructions.is_empty());
+
+        let mut program = call_program();
+        let f = &mut program.functions.get_mut("f").unwrap().nodes[0].block;
+        if let Statement::CallAssign { calls, .. } = &mut f.statements[0] {
+            *calls = "chainid".to_owned();
+        }
+        let mut assembler = Assembler {
+            version: EvmVersion::Petersburg,
+            ..Default::default()
+        };
+        assert!(matches!(
+            assembler.program(&program, &StrategySelection::default()),
+            Err(AssemblyError::Unavailable(Unavailable {
+                since: EvmVersion::Istanbul,
+                ..
+            }))
+        ));
+        assert!(assembler.instructions.is_empty());
+
+        // Transient slots are rejected before Cancun even if no value gets spilled.
+        let mut assembler = Assembler {
+            spill: SpillBackend::Transient { base: 0 },
+            version: EvmVersion::Shanghai,
+            ..Default::default()
+        };
+        assert_eq!(
+            assembler.program(&call_program(), &StrategySelection::default()),
+            Err(AssemblyError::Unavailable(Unavailable {
+                builtin: "tstore".to_owned(),
+                since: EvmVersion::Cancun,
+                version: EvmVersion::Shanghai,
+            }))
+        );
+        assert!(assembler.instructions.is_empty());
+        assembler.version = EvmVersion::Cancun;
+        assembler
+            .program(&call_program(), &StrategySelection::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_assemble_user_call() {
+        let mut assembler = Assembler::default();
+        assembler
+            .ops(&[push(1), Op::CallFn("f"), Op::CallFn("stop")])
+            .unwrap();
+        let f = assembler.function_label("f");
+        assembler.place(f);
+        assembler.opcode(JUMP);
+        assert_eq!(
+            assembler.assemble().unwrap().listing(),
+            [
+                "0000: PUSH1 0x01",
+                "0002: PUSH1 0x07",
+                "0004: PUSH1 0x09",
+                "0006: JUMP",
+                "0007: JUMPDEST",
+                "0008: STOP",
+                "0009: JUMPDEST",
+                "000a: JUMP",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    /// Stores `f(41)` with `f(x) -> y { y := add(x, 1) }`.
+    fn call_program() -> Program {
+        let function = |start: &[&str], statements, end: &[&str], exit| Function {
+            nodes: vec![node(start, statements, end, exit)],
+        };
+        Program {
+            main: function(
+                &[],
+                vec![
+                    call(&["y"], "f", vec![lit(41)]),
+                    call(&[], "sstore", vec![lit(0), r("y")]),
+                ],
+                &[],
+                Terminator::Halt,
+            ),
+            functions: BTreeMap::from([(
+                "f".to_owned(),
+                function(
+                    &["x", "ret"],
+                    vec![call(&["y"], "add", vec![r("x"), lit(1)])],
+                    &["y", "ret"],
+                    Terminator::Leave,
+                ),
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_unknown_function() {
+        let mut program = call_program();
+        if let Statement::CallAssign { calls, .. } = &mut program.main.nodes[0].block.statements[0]
+        {
+            *calls = "g".to_owned();
+        }
+        let mut assembler = Assembler::default();
+        assert_eq!(
+            assembler.program(&program, &StrategySelection::default()),
+            Err(AssemblyError::UnknownFunction("g".to_owned()))
+        );
+        assert!(assembler.instructions.is_empty());
+    }
+
+    #[test]
+    fn test_recursion() {
+        // f(x) -> y { y := g(x) }  g(x) -> y { y := f(add(x, 1)) }
+        let function = |statements| Function {
+            nodes: vec![node(
+                &["x", "ret"],
+                statements,
+                &["y", "ret"],
+                Terminator::Leave,
+            )],
+        };
+        let mut program = call_program();
+        program.functions = BTreeMap::from([
+            (
+                "f".to_owned(),
+                function(vec![call(&["y"], "g", vec![r("x")])]),
+            ),
+            (
+                "g".to_owned(),
+                function(vec![
+                    call(&["z"], "add", vec![r("x"), lit(1)]),
+                    call(&["y"], "f", vec![r("z")]),
+                ]),
+            ),
+        ]);
+        let selection = StrategySelection::default();
+        Assembler::default().program(&program, &selection).unwrap();
+        let selection = selection.with("g", Strategy::Memory);
+        assert_eq!(
+            Assembler::default().program(&program, &selection),
+            Err(AssemblyError::Recursion("g".to_owned()))
+        );
+
+        let f = program.functions.get_mut("f").unwrap();
+        f.nodes[0].block.statements[0] = call(&["y"], "f", vec![r("x")]);
+        let selection = StrategySelection::default().with("f", Strategy::Memory);
+        assert_eq!(
+            Assembler::default().program(&program, &selection),
+            Err(AssemblyError::Recursion("f".to_owned()))
+        );
+    }
+
+    /// Locates `statement` at the 10 bytes from `start` of `source`.
+    fn locate(statement: &mut Statement, start: usize, source: usize) {
+        let (Statement::CallAssign { span, .. } | Statement::ValueAssign { span, .. }) = statement;
+        *span = Some(Span {
+            start,
+            length: 10,
+            source: Some(source),
+        });
+    }
+
+    #[test]
+    fn test_source_map() {
+        let mut program = call_program();
+        let main = &mut program.main.nodes[0].block.statements;
+        locate(&mut main[0], 0, 1);
+        locate(&mut main[1], 20, 1);
+        let f = program.functions.get_mut("f").unwrap();
+        locate(&mut f.nodes[0].block.statements[0], 40, 1);
+        let mut assembler = Assembler::default();
+        assembler
+            .program(&program, &StrategySelection::default())
+            .unwrap();
+        let bytecode = assembler.assemble().unwrap();
+        assert_eq!(execute(&bytecode.code).storage[&U256::ZERO], U256::from(42));
+
+        let instructions = bytecode.listing().lines().count();
+        assert_eq!(bytecode.source_map.len(), instructions);
+        let jumps: Vec<(Jump, Option<usize>)> = bytecode
+            .source_map
+            .iter()
+            .filter(|location| location.jump != Jump::Regular)
+            .map(|location| (location.jump, location.span.map(|span| span.start)))
+            .collect();
+        assert_eq!(jumps, vec![(Jump::Into, Some(0)), (Jump::Out, Some(40))]);
+        let map = bytecode.compressed_source_map();
+        assert_eq!(map.split(';').count(), instructions);
+        assert!(map.starts_with("0:10:1:-:0;"));
+        assert!(map.contains(";20;") && map.contains(":::i;") && map.contains(":o"));
+    }
+
+    #[test]
+    fn test_unlocated_program() {
+        let mut assembler = Assembler::default();
+        assembler
+            .program(&call_program(), &StrategySelection::default())
+            .unwrap();
+        let bytecode = assembler.assemble().unwrap();
+        assert!(bytecode
+            .source_map
+            .iter()
+            .all(|location| location.span.is_none()));
+        assert!(bytecode.compressed_source_map().starts_with(":::-:0;"));
+    }
+
+    #[test]
+    fn test_reordered_program() {
+        // x := add(1, 2)  y := mul(3, 4)  sstore(x, y), the `mul` moving before the `add`.
+        let mut program = Program {
+            main: Function {
+                nodes: vec![node(
+                    &[],
+                    vec![
+                        call(&["x"], "add", vec![lit(1), lit(2)]),
+                        call(&["y"], "mul", vec![lit(3), lit(4)]),
+                        call(&[], "sstore", vec![r("x"), r("y")]),
+                    ],
+                    &[],
+                    Terminator::Halt,
+                )],
+            },
+            functions: BTreeMap::new(),
+        };
+        let statements = &mut program.main.nodes[0].block.statements;
+        for (i, statement) in statements.iter_mut().enumerate() {
+            locate(statement, 20 * i, 0);
+        }
+        let mut assembler = Assembler {
+            reorder: true,
+            ..Default::default()
+        };
+        assembler
+            .program(&program, &StrategySelection::default())
+            .unwrap();
+        let bytecode = assembler.assemble().unwrap();
+        assert_eq!(
+            execute(&bytecode.code).storage[&U256::from(3)],
+            U256::from(12)
+        );
+        let located: Vec<(u8, Option<usize>)> = opcode_offsets(&bytecode.code)
+            .into_iter()
+            .zip(&bytecode.source_map)
+            .filter(|(offset, _)| matches!(bytecode.code[*offset], 0x01 | 0x02))
+            .map(|(offset, location)| (bytecode.code[offset], location.span.map(|span| span.start)))
+            .collect();
+        assert_eq!(located, vec![(0x02, Some(20)), (0x01, Some(0))]);
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let mut assembler = Assembler::default();
+        assembler
+            .program(&call_program(), &StrategySelection::default())
+            .unwrap();
+        let bytecode = assembler.assemble().unwrap();
+        let debug_info = &bytecode.debug_info;
+        assert_eq!(
+            debug_info.instructions.len(),
+            bytecode.listing().lines().count()
+        );
+        let scopes: Vec<(&str, &[String])> = debug_info
+            .scopes
+            .iter()
+            .map(|scope| (scope.name.as_str(), scope.parameters.as_slice()))
+            .collect();
+        assert_eq!(
+            scopes,
+            vec![("main", &[][..]), ("f", &["x".to_owned()][..])]
+        );
+        let f = &debug_info.scopes[1];
+        assert_eq!(f.end, bytecode.size());
+        assert_eq!(debug_info.scopes[0].end, f.start);
+
+        // Entering `f`, `x` is right below the return address, which is not a variable.
+        let entry = debug_info
+            .instructions
+            .iter()
+            .find(|info| info.offset > f.start && info.variables.is_some())
+            .unwrap();
+        let x = Variable {
+            name: "x".to_owned(),
+            location: Location::Stack { depth: 1 },
+        };
+        assert_eq!(entry.variables, Some(vec![x]));
+        let json = debug_info.to_json();
+        assert!(json.contains("\"scopes\"") && json.contains("\"stack\""));
+    }
+
+    #[test]
+    fn test_disassemble_truncated() {
+        assert_eq!(
+            disassemble(&[0x44, 0x0c, 0x62, 0x01]),
+            "0000: PREVRANDAO\n0001: INVALID(0x0c)\n0002: PUSH3 0x01\n"
+        );
+    }
+
+    #[test]
+    fn test_relaxation() {
+        // The first jump's target is past 255 bytes of pushes, so it needs a `PUSH2`, which
+        // moves the second label.
+        let mut assembler = Assembler::default();
+        let (far, near) = (assembler.new_label(), assembler.new_label());
+        assembler.jump(far);
+        assembler.jump(near);
+        assembler.place(near);
+        (0..127).for_each(|_| assembler.ops(&[push(1)]).unwrap());
+        assembler.place(far);
+        let bytecode = assembler.assemble().unwrap();
+        assert_eq!(bytecode.labels, vec![Some(262), Some(7)]);
+        assert_eq!(bytecode.size(), 263);
+        assert_eq!(
+            &bytecode.code[..7],
+            &[0x61, 0x01, 0x06, 0x56, 0x60, 0x07, 0x56]
+        );
+    }
+
+    #[test]
+    fn test_memory_guard() {
+        // mstore(0x40, memoryguard(guard))  sstore(0, mload(0x40))
+        let program = |guard| Program {
+            main: Function {
+                nodes: vec![node(
+                    &[],
+                    vec![
+                        call(&["m"], "memoryguard", vec![lit(guard)]),
+                        call(&[], "mstore", vec![lit(0x40), r("m")]),
+                        call(&["p"], "mload", vec![lit(0x40)]),
+                        call(&[], "sstore", vec![lit(0), r("p")]),
+                        call(&[], "stop", vec![]),
+                    ],
+                    &[],
+                    Terminator::Halt,
+                )],
+            },
+            functions: BTreeMap::new(),
+        };
+        let layout = MemoryLayout::default();
+        for strategy in Strategy::ALL {
+            let selection = StrategySelection {
+                default: strategy,
+                ..Default::default()
+            };
+            let mut assembler = Assembler::default();
+            assembler.program(&program(0x80), &selection).unwrap();
+            let bytecode = assembler.assemble().unwrap();
+            let pointer = execute(&bytecode.code).storage[&U256::ZERO];
+            let slots = (pointer.to::<usize>() - layout.address(0)) / layout.slot_size;
+            assert_eq!(pointer, U256::from(layout.end(slots)), "{:?}", strategy);
+            if strategy == Strategy::Memory {
+                assert!(slots > 0);
+                assert_eq!(
+                    Assembler::default().program(&program(0xa0), &selection),
+                    Err(AssemblyError::MemoryGuard {
+                        claimed: U256::from(0xa0),
+                        first_slot: 0x80,
+                    })
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_assemble_function() {
+        // n := 10  s := 0  while n { s := add(s, n)  n := sub(n, 1) }  sstore(0, s)
+        let function = Function {
+            nodes: vec![
+                node(
+                    &[],
+                    vec![
+                        Statement::ValueAssign {
+                            to: "n".to_owned().into(),
+                            value: lit(10),
+                            span: None,
+                        },
+                        Statement::ValueAssign {
+                            to: "s".to_owned().into(),
+                            value: lit(0),
+                            span: None,
+                        },
+                    ],
+                    &["n", "s"],
+                    Terminator::Jump(1),
+                ),
+                node(
+                    &["n", "s"],
+                    vec![call(&["c"], "iszero", vec![r("n")])],
+                    &["n", "s", "c"],
+                    Terminator::Branch {
+                        non_zero: 3,
+                        zero: 2,
+                    },
+                ),
+                node(
+                    &["n", "s"],
+                    vec![
+                        call(&["s"], "add", vec![r("s"), r("n")]),
+                        call(&["n"], "sub", vec![r("n"), lit(1)]),
+                    ],
+                    &["n", "s"],
+                    Terminator::Jump(1),
+                ),
+                node(
+                    &["n", "s"],
+                    vec![
+                        call(&[], "sstore", vec![lit(0), r("s")]),
+                        call(&[], "stop", vec![]),
+                    ],
+                    &[],
+                    Terminator::Halt,
+                ),
+            ],
+        };
+        let backends = [
+            SpillBackend::default(),
+            SpillBackend::Transient { base: 1 << 32 },
+        ];
+        for (strategy, spill) in Strategy::ALL
+            .into_iter()
+            .flat_map(|strategy| backends.map(|spill| (strategy, spill)))
+        {
+            let schedules = strategy
+                .schedule_function_with(&function, &spill.cost())
+                .unwrap();
+            let mut assembler = Assembler::new(spill);
+            let labels = assembler.function(None, &function, &schedules).unwrap();
+            let bytecode = assembler.assemble().unwrap();
+            assert_eq!(bytecode.labels[labels[0]], Some(0));
+            let execution = execute(&bytecode.code);
+            assert!(execution.stack.is_empty(), "{:?}", strategy);
+            assert_eq!(
+                execution.storage[&U256::ZERO],
+                U256::from(55),
+                "{:?} with {:?}",
+                strategy,
+                spill
+            );
+            if strategy == Strategy::Memory {
+                assert_eq!(execution.transient.is_empty(), spill == backends[0]);
+            }
+        }
+    }
+}
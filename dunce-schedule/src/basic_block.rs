@@ -1,32 +1,39 @@
 use std::collections::BTreeMap;
 
+use crate::cfg::{BlockId, Function, Node, Program, Terminator};
+use crate::dialect::{builtin, literal_builtin_call, parse_literal_builtin};
 use crate::ssa_block::{Block as SSABlock, Name, Statement, Value};
-use ir::{FunctionDefinition, Literal};
-
-static mut COUNTER: u32 = 0;
-
-fn get_counter() -> u32 {
-    let counter: u32;
-    unsafe {
-        counter = COUNTER;
-        COUNTER += 1;
-    }
-    counter
-}
-
-fn get_cond_label() -> String {
-    format!("__cond__{}__", get_counter())
-}
-
-fn get_ret_label() -> String {
-    format!("__ret_addr__{}__", get_counter())
+use ir::{FunctionDefinition, Literal, Span};
+
+/// Name of the return address in the stacks of a function, see `BasicBlocksBuilder::temporary`.
+const RETURN_ADDRESS: &str = "ret#";
+
+/// Yul code that cannot be lowered to a `cfg::Program`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoweringError {
+    /// Reference of a variable not in scope.
+    UndefinedVariable(String),
+    /// More than one function of the name.
+    DuplicateFunction(String),
+    /// Call with another number of arguments or return values than the function has.
+    Arity(String),
+    /// Assignment of a literal or variable to other than one variable.
+    ValueCount(Vec<String>),
+    /// `break` or `continue` outside of a loop body.
+    OutsideLoop,
+    /// `leave` outside of a function body.
+    OutsideFunction,
 }
 
 #[derive(Debug, Clone)]
 pub enum Expr {
     Refr(String),
     Literal(Literal),
-    Call { fn_name: String, args: Vec<Expr> },
+    Call {
+        fn_name: String,
+        args: Vec<Expr>,
+        span: Option<Span>,
+    },
 }
 
 impl Expr {
@@ -46,6 +53,16 @@ impl Expr {
         Expr::Call {
             fn_name: name.to_owned(),
             args,
+            span: None,
+        }
+    }
+
+    /// First variable referenced anywhere in the expression for which `undefined` holds.
+    fn find_ref(&self, undefined: &impl Fn(&String) -> bool) -> Option<&String> {
+        match self {
+            Expr::Refr(name) => undefined(name).then_some(name),
+            Expr::Literal(_) => None,
+            Expr::Call { args, .. } => args.iter().find_map(|arg| arg.find_ref(undefined)),
         }
     }
 }
@@ -55,12 +72,29 @@ impl From<ir::Expr> for Expr {
         match value {
             ir::Expr::VarRef(vr) => Expr::Refr(vr),
             ir::Expr::Literal(literal) => Expr::Literal(literal),
-            ir::Expr::Call { fn_name, args: ir_args } => {
+            ir::Expr::Call {
+                fn_name,
+                args: ir_args,
+                span,
+            } => {
                 let mut args = Vec::new();
                 for arg in ir_args {
                     args.push(arg.into());
                 }
-                Expr::Call { fn_name, args }
+                Expr::Call {
+                    fn_name,
+                    args,
+                    span,
+                }
+            },
+            ir::Expr::Builtin {
+                fn_name,
+                input,
+                span,
+            } => Expr::Call {
+                fn_name: literal_builtin_call(&fn_name, &input),
+                args: Vec::new(),
+                span,
             },
         }
     }
@@ -79,7 +113,9 @@ impl FlatStatementBuilder {
         Name::Intermed(id)
     }
 
-    fn flatten_to_values(&mut self, args: Vec<Expr>) -> Vec<Value> {
+    /// Values of the arguments, their calls hoisted into statements located at the call's span,
+    /// or the `outer` one if it has none.
+    fn flatten_to_values(&mut self, args: Vec<Expr>, outer: Option<Span>) -> Vec<Value> {
         args.into_iter()
             .rev()
             .map(|arg| match arg {
@@ -88,13 +124,16 @@ impl FlatStatementBuilder {
                 Expr::Call {
                     fn_name,
                     args: expr_args,
+                    span,
                 } => {
-                    let takes = self.flatten_to_values(expr_args);
+                    let span = span.or(outer);
+                    let takes = self.flatten_to_values(expr_args, span);
                     let new_name = self.get_next_name();
                     self.statements.push(Statement::CallAssign {
                         assigns: vec![new_name.clone()],
                         calls: fn_name.clone(),
                         takes,
+                        span,
                     });
                     new_name.into()
                 }
@@ -108,13 +147,17 @@ impl FlatStatementBuilder {
 pub struct Assignment {
     to_idents: Vec<String>,
     expr: Expr,
+    /// Span of the statement the assignment was lowered from.
+    span: Option<Span>,
 }
 
+#[allow(dead_code)]
 impl Assignment {
     fn new(to: Vec<&str>, expr: Expr) -> Self {
         Self {
             to_idents: to.into_iter().map(|s| s.to_owned()).collect(),
             expr,
+            span: None,
         }
     }
 }
@@ -124,157 +167,186 @@ pub struct BasicBlock {
     start_stack: Vec<String>,
     assignments: Vec<Assignment>,
     end_stack: Vec<String>,
+    exit: Terminator,
+}
+
+/// Where `break` and `continue` in a loop body go, with the variables in scope there.
+#[derive(Debug, Clone)]
+struct LoopTargets {
+    exit: BlockId,
+    post: BlockId,
+    stack: Vec<String>,
 }
 
+/// Splits Yul code into basic blocks. Between blocks the stack holds the variables in scope in
+/// the order they were declared, in functions after the arguments and return address of the
+/// calling convention of `cfg::Program`.
 struct BasicBlocksBuilder {
-    start_stack: Vec<String>,
+    /// Variables in scope.
     current_stack: Vec<String>,
-    assignments: Vec<Assignment>,
+    /// Block statements are appended to.
+    current: BlockId,
     functions: BTreeMap<String, Vec<BasicBlock>>,
+    /// Numbers of arguments and return values of the functions.
+    signatures: BTreeMap<String, (usize, usize)>,
+    /// Blocks of the code being split, the first is the entry.
     basic_blocks: Vec<BasicBlock>,
-    loop_revert_state: Option<Vec<String>>,
-    loop_continue_state: Option<Vec<String>>,
-    fn_return: Option<Vec<String>>
+    loop_targets: Option<LoopTargets>,
+    fn_return: Option<Vec<String>>,
+    next_temporary: usize,
 }
 
 impl BasicBlocksBuilder {
-    fn new(start_stack: &Vec<String>) -> Self {
+    fn new(start_stack: &[String]) -> Self {
         Self {
-            start_stack: start_stack.clone(),
-            current_stack: start_stack.clone(),
-            assignments: Vec::new(),
+            current_stack: start_stack.to_vec(),
+            current: 0,
             functions: BTreeMap::new(),
-            basic_blocks: Vec::new(),
-            loop_revert_state: None,
-            loop_continue_state: None,
-            fn_return: None
+            signatures: BTreeMap::new(),
+            basic_blocks: vec![BasicBlock::new(start_stack.to_vec())],
+            loop_targets: None,
+            fn_return: None,
+            next_temporary: 0,
         }
     }
 
-    fn derive_builder(&self) -> Self {
-        Self {
-            start_stack: self.current_stack.clone(),
-            current_stack: self.current_stack.clone(),
-            assignments: Vec::new(),
-            functions: BTreeMap::new(),
-            basic_blocks: Vec::new(),
-            loop_revert_state: self.loop_revert_state.clone(),
-            loop_continue_state: self.loop_continue_state.clone(),
-            fn_return: self.fn_return.clone()
-        }
+    /// Name of a new variable of the lowering, the `#` keeping it apart from Yul identifiers.
+    fn temporary(&mut self, kind: &str) -> String {
+        self.next_temporary += 1;
+        format!("{}#{}", kind, self.next_temporary - 1)
+    }
+
+    fn new_block(&mut self, start_stack: Vec<String>) -> BlockId {
+        self.basic_blocks.push(BasicBlock::new(start_stack));
+        self.basic_blocks.len() - 1
+    }
+
+    /// Ends the current block with `end_stack` on the stack, continuing at `exit`.
+    fn end_block(&mut self, end_stack: Vec<String>, exit: Terminator) {
+        let block = &mut self.basic_blocks[self.current];
+        block.end_stack = end_stack;
+        block.exit = exit;
+    }
+
+    /// Appends the following statements to the block `id`, with its start stack in scope.
+    fn continue_at(&mut self, id: BlockId) {
+        self.current = id;
+        self.current_stack = self.basic_blocks[id].start_stack.clone();
+    }
+
+    /// Ends the current block like `end_block`, appending the dead code after it to a block
+    /// of its own, which is never reached.
+    fn end_unreachable(&mut self, end_stack: Vec<String>, exit: Terminator) {
+        self.end_block(end_stack, exit);
+        self.current = self.new_block(self.current_stack.clone());
+    }
+
+    /// Splits the statements of a block, the variables declared in it going out of scope at
+    /// its end.
+    fn split_block(&mut self, block: ir::Block) -> Result<(), LoweringError> {
+        let scope = self.current_stack.len();
+        self.split_statements(block)?;
+        self.current_stack.truncate(scope);
+        Ok(())
     }
 
-    fn split_block(&mut self, block: ir::Block) {
+    fn split_statements(&mut self, block: ir::Block) -> Result<(), LoweringError> {
         for statement in block.0 {
             match statement {
-                ir::Statement::Block(block) => self.split_block(block),
-                ir::Statement::FnDef(f) => self.split_fn_def(f),
-                ir::Statement::Assignment { to, expr } => self.split_assignment(to, expr),
-                ir::Statement::If { cond: _, body } => self.split_if(body),
-                ir::Statement::Switch { cond, cases, default } => todo!(),
-                ir::Statement::ForLoop { setup, cond, on_iter, body } => self.split_for(setup, cond, on_iter, body),
+                ir::Statement::Block(block) => self.split_block(block)?,
+                ir::Statement::FnDef(f) => self.split_fn_def(f)?,
+                ir::Statement::Assignment { to, expr, span } => {
+                    self.split_assignment(to, expr, span)?
+                }
+                ir::Statement::If { cond, body, span } => self.split_if(cond, body, span)?,
+                ir::Statement::Switch {
+                    cond,
+                    cases,
+                    default,
+                    span,
+                } => self.split_switch(cond, cases, default, span)?,
+                ir::Statement::ForLoop {
+                    setup,
+                    cond,
+                    on_iter,
+                    body,
+                    span,
+                } => self.split_for(setup, cond, on_iter, body, span)?,
                 ir::Statement::Leave => {
-                    let bb = BasicBlock {
-                        start_stack: self.start_stack.clone(),
-                        assignments: self.assignments.clone(),
-                        end_stack: self.fn_return.clone().unwrap(),
-                    };
-                    self.basic_blocks.push(bb);
-                    self.assignments = Vec::new();
-                    self.start_stack = self.current_stack.clone();
-                },
-                ir::Statement::Break => {
-                    let mut bb = BasicBlock {
-                        start_stack: self.start_stack.clone(),
-                        assignments: self.assignments.clone(),
-                        end_stack: self.current_stack.clone(),
-                    };
-                    if let Some(loop_revert_state) = self.loop_revert_state.clone() {
-                        bb.end_stack = loop_revert_state
-                    }
-                    self.basic_blocks.push(bb);
-                    self.assignments = Vec::new();
-                    self.start_stack = self.current_stack.clone();
-                },
-                ir::Statement::Continue => {
-                    let mut bb = BasicBlock {
-                        start_stack: self.start_stack.clone(),
-                        assignments: self.assignments.clone(),
-                        end_stack: self.current_stack.clone(),
+                    let end_stack = self
+                        .fn_return
+                        .clone()
+                        .ok_or(LoweringError::OutsideFunction)?;
+                    self.end_unreachable(end_stack, Terminator::Leave);
+                }
+                ir::Statement::Break | ir::Statement::Continue => {
+                    let targets = self
+                        .loop_targets
+                        .clone()
+                        .ok_or(LoweringError::OutsideLoop)?;
+                    let to = match statement {
+                        ir::Statement::Break => targets.exit,
+                        _ => targets.post,
                     };
-                    if let Some(loop_continue_state) = self.loop_continue_state.clone() {
-                        bb.end_stack = loop_continue_state
-                    }
-                    self.basic_blocks.push(bb);
-                    self.assignments = Vec::new();
-                    self.start_stack = self.current_stack.clone();
-                },
+                    self.end_unreachable(targets.stack, Terminator::Jump(to));
+                }
             }
         }
-        if self.start_stack != self.current_stack || self.assignments.len() > 0 {
-            let bb = BasicBlock {
-                start_stack: self.start_stack.clone(),
-                assignments: self.assignments.clone(),
-                end_stack: self.current_stack.clone(),
-            };
-            self.basic_blocks.push(bb);
-        }
-        
+        Ok(())
     }
 
-    fn split_for(&mut self, setup: ir::Block, cond: ir::Expr, on_iter: ir::Block, body: ir::Block) {
-        let end_stack = self.current_stack.clone();
-        let bb = BasicBlock {
-            start_stack: self.start_stack.clone(),
-            assignments: self.assignments.clone(),
-            end_stack,
-        };
-        self.basic_blocks.push(bb);
-        self.start_stack = self.current_stack.clone();
-        self.assignments = Vec::new();
-        
-        let mut setup_builder = self.derive_builder();
-        setup_builder.split_block(setup);
-        
-        let mut body_builder = setup_builder.derive_builder();
-        body_builder.loop_revert_state = Some(setup_builder.start_stack.clone());
-        body_builder.loop_continue_state = Some(setup_builder.current_stack.clone());
-        body_builder.split_block(body);
-        let mut last_bb = body_builder.basic_blocks.pop().unwrap();
-        last_bb.end_stack = setup_builder.current_stack.clone();
-        body_builder.basic_blocks.push(last_bb);
-
-        let mut on_iter_builder = setup_builder.derive_builder();
-        on_iter_builder.split_block(on_iter);
-        let mut last_bb = on_iter_builder.basic_blocks.pop().unwrap();
-        last_bb.end_stack = on_iter_builder.start_stack.clone();
-        on_iter_builder.basic_blocks.push(last_bb);
-
-        let mut cond_builder = setup_builder.derive_builder();
-        let cond_var: String = get_cond_label();
-        cond_builder.split_assignment(vec![cond_var.clone()], cond);
-        let mut end_stack = cond_builder.start_stack.clone();
-        end_stack.push(cond_var.clone());
-        let bb = BasicBlock {
-            start_stack: cond_builder.start_stack.clone(),
-            assignments: cond_builder.assignments.clone(),
-            end_stack,
-        };
-        cond_builder.basic_blocks.push(bb);
-        let bb = BasicBlock {
-            start_stack: cond_builder.start_stack.clone(),
-            assignments: Vec::new(),
-            end_stack: setup_builder.start_stack.clone(),
-        };
-        cond_builder.basic_blocks.push(bb);
-
-        self.consume_builder(setup_builder);
-        self.consume_builder(body_builder);
-        self.consume_builder(on_iter_builder);
-        self.consume_builder(cond_builder);
+    /// `for { setup } cond { post } { body }` becomes a header evaluating `cond`, branching to
+    /// the body or the exit, with the body continuing at the post block, which jumps back to
+    /// the header. The variables of `setup` stay in scope up to the exit.
+    fn split_for(
+        &mut self,
+        setup: ir::Block,
+        cond: ir::Expr,
+        on_iter: ir::Block,
+        body: ir::Block,
+        span: Option<Span>,
+    ) -> Result<(), LoweringError> {
+        let scope = self.current_stack.len();
+        self.split_statements(setup)?;
+        let stack = self.current_stack.clone();
+        let header = self.new_block(stack.clone());
+        let body_id = self.new_block(stack.clone());
+        let post = self.new_block(stack.clone());
+        let exit = self.new_block(stack.clone());
+        self.end_block(stack.clone(), Terminator::Jump(header));
+
+        self.continue_at(header);
+        self.split_cond(cond, span, body_id, exit)?;
+
+        self.continue_at(body_id);
+        let outer = self.loop_targets.replace(LoopTargets {
+            exit,
+            post,
+            stack: stack.clone(),
+        });
+        self.split_block(body)?;
+        self.loop_targets = outer;
+        self.end_block(stack.clone(), Terminator::Jump(post));
+
+        self.continue_at(post);
+        self.split_block(on_iter)?;
+        self.end_block(stack, Terminator::Jump(header));
+
+        self.continue_at(exit);
+        self.current_stack.truncate(scope);
+        Ok(())
     }
 
-    fn split_assignment(&mut self, to: Vec<String>, expr: ir::Expr) {
+    fn split_assignment(
+        &mut self,
+        to: Vec<String>,
+        expr: ir::Expr,
+        span: Option<Span>,
+    ) -> Result<(), LoweringError> {
+        let expr: Expr = expr.into();
+        if let Some(name) = expr.find_ref(&|name| !self.current_stack.contains(name)) {
+            return Err(LoweringError::UndefinedVariable(name.clone()));
+        }
         for v in to.clone() {
             if !self.current_stack.contains(&v) {
                 self.current_stack.push(v);
@@ -282,64 +354,225 @@ impl BasicBlocksBuilder {
         }
         let assignment = Assignment {
             to_idents: to,
-            expr: expr.into(),
+            expr,
+            span,
         };
-        self.assignments.push(assignment);      
+        self.basic_blocks[self.current].assignments.push(assignment);
+        Ok(())
     }
 
-    fn split_fn_def(&mut self, f: ir::FunctionDefinition) {
-        let ret_addr: String = get_ret_label();
-        let FunctionDefinition { name, args, rets, body } = f;
-        let mut start_stack = args;
-        start_stack.extend(rets.clone());
-        start_stack.push(ret_addr.clone());
+    /// Ends the current block branching on `cond`, to `non_zero` or `zero`, which both start
+    /// with the variables in scope. The condition is located at the `span` of its statement.
+    fn split_cond(
+        &mut self,
+        cond: ir::Expr,
+        span: Option<Span>,
+        non_zero: BlockId,
+        zero: BlockId,
+    ) -> Result<(), LoweringError> {
+        let cond_var = self.temporary("cond");
+        self.split_assignment(vec![cond_var], cond, span)?;
+        let end_stack = self.current_stack.clone();
+        self.end_block(end_stack, Terminator::Branch { non_zero, zero });
+        Ok(())
+    }
 
-        let mut assignments: Vec<Assignment> = Vec::new();
-        for ret in &rets {
-            assignments.push(Assignment {
-                to_idents: vec![ret.to_owned()],
-                expr: Expr::Literal([0u8;32]),
-            });
-        }
+    /// Stacks follow the calling convention of `cfg::Program`, the return values starting out
+    /// as zero.
+    fn split_fn_def(&mut self, f: ir::FunctionDefinition) -> Result<(), LoweringError> {
+        let FunctionDefinition {
+            name,
+            args,
+            rets,
+            body,
+        } = f;
+        let ret_addr = RETURN_ADDRESS.to_owned();
+        let mut start_stack: Vec<String> = args.iter().rev().cloned().collect();
+        start_stack.push(ret_addr.clone());
 
-        let mut end_stack = rets;
-        end_stack.push(ret_addr.clone());
+        let mut end_stack = rets.clone();
+        end_stack.push(ret_addr);
 
         let mut builder = BasicBlocksBuilder::new(&start_stack);
         builder.fn_return = Some(end_stack.clone());
-        builder.assignments = assignments;
-        builder.split_block(body);
-        let mut basic_blocks = builder.basic_blocks;
-        let mut last_bb = basic_blocks.pop().unwrap();
-        last_bb.end_stack = end_stack;
-        basic_blocks.push(last_bb);
+        for ret in rets.iter() {
+            builder.split_assignment(vec![ret.clone()], ir::Expr::Literal([0u8; 32]), None)?;
+        }
+        builder.split_block(body)?;
+        builder.end_block(end_stack, Terminator::Leave);
+        self.add_function(name, builder.basic_blocks, (args.len(), rets.len()))?;
+        for (name, basic_blocks) in builder.functions {
+            let signature = builder.signatures[&name];
+            self.add_function(name, basic_blocks, signature)?;
+        }
+        Ok(())
+    }
+
+    fn add_function(
+        &mut self,
+        name: String,
+        basic_blocks: Vec<BasicBlock>,
+        signature: (usize, usize),
+    ) -> Result<(), LoweringError> {
+        if self.functions.contains_key(&name) {
+            return Err(LoweringError::DuplicateFunction(name));
+        }
+        self.signatures.insert(name.clone(), signature);
         self.functions.insert(name, basic_blocks);
-        self.functions.extend(builder.functions);
+        Ok(())
     }
 
-    fn split_if(&mut self, body: ir::Block) {
-        let mut end_stack = self.current_stack.clone();
-        end_stack.push(get_cond_label());
-        let bb = BasicBlock {
-            start_stack: self.start_stack.clone(),
-            assignments: self.assignments.clone(),
-            end_stack,
-        };
-        self.basic_blocks.push(bb);
-        self.start_stack = self.current_stack.clone();
-        self.assignments = Vec::new();
-        let mut builder = self.derive_builder();
-        builder.split_block(body);
-        self.consume_builder(builder);
+    /// `if cond { body }` branches to the body or past it, the body jumping there at its end.
+    fn split_if(
+        &mut self,
+        cond: ir::Expr,
+        body: ir::Block,
+        span: Option<Span>,
+    ) -> Result<(), LoweringError> {
+        let stack = self.current_stack.clone();
+        let then = self.new_block(stack.clone());
+        let join = self.new_block(stack.clone());
+        self.split_cond(cond, span, then, join)?;
+        self.continue_at(then);
+        self.split_block(body)?;
+        self.end_block(stack, Terminator::Jump(join));
+        self.continue_at(join);
+        Ok(())
     }
 
-    fn consume_builder(&mut self, builder: BasicBlocksBuilder) {
-        self.basic_blocks.extend(builder.basic_blocks);
-        self.functions.extend(builder.functions);
+    /// The value switched on is kept in a variable compared with each case in turn, the last
+    /// comparison falling through to the default, or past the switch without one. Both are
+    /// located at the `span` of the switch.
+    fn split_switch(
+        &mut self,
+        cond: ir::Expr,
+        cases: Vec<(Literal, ir::Block)>,
+        default: Option<ir::Block>,
+        span: Option<Span>,
+    ) -> Result<(), LoweringError> {
+        let stack = self.current_stack.clone();
+        let value = self.temporary("switch");
+        self.split_assignment(vec![value.clone()], cond, span)?;
+        let join = self.new_block(stack.clone());
+        for (literal, body) in cases {
+            let case = self.new_block(self.current_stack.clone());
+            let next = self.new_block(self.current_stack.clone());
+            let matches = ir::Expr::Call {
+                fn_name: "eq".to_owned(),
+                args: vec![ir::Expr::VarRef(value.clone()), ir::Expr::Literal(literal)],
+                span: None,
+            };
+            self.split_cond(matches, span, case, next)?;
+            self.continue_at(case);
+            self.split_block(body)?;
+            self.end_block(stack.clone(), Terminator::Jump(join));
+            self.continue_at(next);
+        }
+        if let Some(body) = default {
+            self.split_block(body)?;
+        }
+        self.end_block(stack, Terminator::Jump(join));
+        self.continue_at(join);
+        Ok(())
+    }
+
+    /// Numbers of arguments and return values of `name`, `None` for unknown functions.
+    fn arity(&self, name: &str) -> Option<(usize, usize)> {
+        match (builtin(name), parse_literal_builtin(name)) {
+            (Some(builtin), _) => Some((builtin.takes, builtin.returns)),
+            (None, Some(("setimmutable", _))) => Some((2, 0)),
+            (None, Some(_)) => Some((0, 1)),
+            (None, None) if name == "memoryguard" => Some((1, 1)),
+            (None, None) => self.signatures.get(name).copied(),
+        }
+    }
+
+    /// Fails on calls with another number of arguments or return values than the function has.
+    fn check_arities(&self, program: &Program) -> Result<(), LoweringError> {
+        let mismatch = std::iter::once(&program.main)
+            .chain(program.functions.values())
+            .flat_map(|function| function.nodes.iter())
+            .flat_map(|node| node.block.statements.iter())
+            .find_map(|stmt| match stmt {
+                Statement::CallAssign {
+                    assigns,
+                    calls,
+                    takes,
+                    ..
+                } => self
+                    .arity(calls)
+                    .is_some_and(|arity| arity != (takes.len(), assigns.len()))
+                    .then(|| calls.clone()),
+                Statement::ValueAssign { .. } => None,
+            });
+        match mismatch {
+            Some(calls) => Err(LoweringError::Arity(calls)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Lowers Yul code to `main` of a program, the functions defined anywhere in it becoming the
+/// program's functions. Calls of functions neither defined nor builtin are left for the
+/// assembler to reject.
+impl TryFrom<ir::Block> for Program {
+    type Error = LoweringError;
+
+    fn try_from(block: ir::Block) -> Result<Self, Self::Error> {
+        let mut builder = BasicBlocksBuilder::new(&[]);
+        builder.split_block(block)?;
+        builder.end_block(vec![], Terminator::Halt);
+        let assignments = std::iter::once(&builder.basic_blocks)
+            .chain(builder.functions.values())
+            .flatten()
+            .flat_map(|bb| bb.assignments.iter());
+        for assignment in assignments {
+            if !matches!(assignment.expr, Expr::Call { .. }) && assignment.to_idents.len() != 1 {
+                return Err(LoweringError::ValueCount(assignment.to_idents.clone()));
+            }
+        }
+        let program = Program {
+            main: BasicBlock::function(std::mem::take(&mut builder.basic_blocks)),
+            functions: std::mem::take(&mut builder.functions)
+                .into_iter()
+                .map(|(name, basic_blocks)| (name, BasicBlock::function(basic_blocks)))
+                .collect(),
+        };
+        builder.check_arities(&program)?;
+        Ok(program)
     }
 }
 
 impl BasicBlock {
+    fn new(start_stack: Vec<String>) -> Self {
+        Self {
+            start_stack,
+            assignments: Vec::new(),
+            end_stack: Vec::new(),
+            exit: Terminator::Halt,
+        }
+    }
+
+    /// Function of the blocks, the first being the entry, without those never reached.
+    fn function(basic_blocks: Vec<BasicBlock>) -> Function {
+        let mut function = Function {
+            nodes: basic_blocks
+                .into_iter()
+                .map(|bb| {
+                    let exit = bb.exit.clone();
+                    Node {
+                        block: bb.flatten_to(),
+                        exit,
+                    }
+                })
+                .collect(),
+        };
+        function.retain_nodes(&function.reachable());
+        function
+    }
+
+    /// Statements of the assignments, calls located at their span, or that of the statement
+    /// they are in if they have none.
     fn flatten_to(self) -> SSABlock {
         let mut flattener = FlatStatementBuilder::default();
 
@@ -350,6 +583,7 @@ impl BasicBlock {
                     [ident] => Some(Statement::ValueAssign {
                         to: ident.into(),
                         value: Value::Literal(lit),
+                        span: assign.span,
                     }),
                     _ => panic!("Assigning literal to more than one variable"),
                 },
@@ -358,18 +592,27 @@ impl BasicBlock {
                     [to_ident] => Some(Statement::ValueAssign {
                         to: to_ident.into(),
                         value: Value::RefName(value_ident.into()),
+                        span: assign.span,
                     }),
                     _ => panic!("Assigning literal to more than one variable"),
                 },
-                Expr::Call { fn_name, args } => Some(Statement::CallAssign {
-                    assigns: assign
-                        .to_idents
-                        .into_iter()
-                        .map(|ident| ident.into())
-                        .collect(),
-                    calls: fn_name,
-                    takes: flattener.flatten_to_values(args),
-                }),
+                Expr::Call {
+                    fn_name,
+                    args,
+                    span,
+                } => {
+                    let span = span.or(assign.span);
+                    Some(Statement::CallAssign {
+                        assigns: assign
+                            .to_idents
+                            .into_iter()
+                            .map(|ident| ident.into())
+                            .collect(),
+                        calls: fn_name,
+                        takes: flattener.flatten_to_values(args, span),
+                        span,
+                    })
+                }
             };
             if let Some(stmt) = new_stmt {
                 flattener.statements.push(stmt);
@@ -393,15 +636,17 @@ mod test {
     fn test_bb_assign() {
         let s1 = ir::Statement::Assignment {
             to: vec!["a".into(), "b".into(), "c".into()], 
-            expr: ir::Expr::Literal([0u8; 32])
+            expr: ir::Expr::Literal([0u8; 32]),
+            span: None,
         };
         let s2 = ir::Statement::Assignment {
             to: vec!["a".into()],
-            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+            span: None,
         };
-        let block = ir::Block { 0: vec![s1, s2] };
-        let mut builder = BasicBlocksBuilder::new(&vec![]);
-        builder.split_block(block);
+        let block = ir::Block(vec![s1, s2]);
+        let mut builder = BasicBlocksBuilder::new(&[]);
+        builder.split_block(block).unwrap();
         dbg!(builder.basic_blocks);
         dbg!(builder.functions);
     }
@@ -410,26 +655,29 @@ mod test {
     fn test_bb_assign_fndef_assign() {
         let s1 = ir::Statement::Assignment {
             to: vec!["a".into()],
-            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+            span: None,
         };
         let f = ir::Statement::FnDef(
             ir::FunctionDefinition {
                 name: "bla".into(),
                 args: vec!["x".into(), "y".into()],
                 rets: vec!["z".into()],
-                body: ir::Block{ 0: vec![ir::Statement::Assignment {
+                body: ir::Block(vec![ir::Statement::Assignment {
                     to: vec!["a".into()],
-                    expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![] }
-                }]},
+                    expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+                    span: None,
+                }]),
             }
         );
         let s2 = ir::Statement::Assignment {
             to: vec!["b".into()],
-            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+            span: None,
         };
-        let block = ir::Block { 0: vec![s1, f, s2] };
-        let mut builder = BasicBlocksBuilder::new(&vec![]);
-        builder.split_block(block);
+        let block = ir::Block(vec![s1, f, s2]);
+        let mut builder = BasicBlocksBuilder::new(&[]);
+        builder.split_block(block).unwrap();
         dbg!(builder.basic_blocks);
         dbg!(builder.functions);
     }
@@ -438,33 +686,39 @@ mod test {
     fn test_bb_if() {
         let s1 = ir::Statement::Assignment {
             to: vec!["a".into()],
-            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+            span: None,
         };
         
         let a1 = ir::Statement::Assignment {
             to: vec!["x".into()],
-            expr: ir::Expr::Call { fn_name: "x_raise".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "x_raise".into(), args: vec![], span: None },
+            span: None,
         };
         let if_stmt = ir::Statement::If {
-            cond: ir::Expr::Call { fn_name: "if_var".into(), args: vec![] },
-            body: ir::Block { 0: vec![ir::Statement::Assignment {
+            cond: ir::Expr::Call { fn_name: "if_var".into(), args: vec![], span: None },
+            body: ir::Block(vec![ir::Statement::Assignment {
                 to: vec!["if".into()], 
-                expr: ir::Expr::Call { fn_name: "nothing".into(), args: vec![] }
-            }] }
+                expr: ir::Expr::Call { fn_name: "nothing".into(), args: vec![], span: None },
+                span: None,
+            }]),
+            span: None,
         };
         let a2 = ir::Statement::Assignment {
             to: vec!["y".into()],
-            expr: ir::Expr::Call { fn_name: "y_raise".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "y_raise".into(), args: vec![], span: None },
+            span: None,
         };
 
         let s2 = ir::Statement::Assignment {
             to: vec!["b".into()],
-            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+            span: None,
         };
 
-        let block = ir::Block{ 0: vec![s1, a1, if_stmt, a2, s2]};
-        let mut builder = BasicBlocksBuilder::new(&vec![]);
-        builder.split_block(block);
+        let block = ir::Block(vec![s1, a1, if_stmt, a2, s2]);
+        let mut builder = BasicBlocksBuilder::new(&[]);
+        builder.split_block(block).unwrap();
         dbg!(builder.basic_blocks);
         dbg!(builder.functions);
     }
@@ -473,48 +727,52 @@ mod test {
     fn test_bb_for_loop_continue() {
         let s1 = ir::Statement::Assignment {
             to: vec!["a".into()],
-            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+            span: None,
         };
         let s2 = ir::Statement::Assignment {
             to: vec!["b".into()],
-            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+            span: None,
         };
 
-        let setup = ir::Block {
-            0: vec![ir::Statement::Assignment {
-                to: vec!["i".into()],
-                expr: ir::Expr::Literal([0u8; 32])
-            }]
-        };
+        let setup = ir::Block(vec![ir::Statement::Assignment {
+            to: vec!["i".into()],
+            expr: ir::Expr::Literal([0u8; 32]),
+            span: None,
+        }]);
         let cond = ir::Expr::Literal([1u8; 32]);
-        let on_iter = ir::Block {
-            0: vec![ir::Statement::Assignment {
-                to: vec!["i".into()],
-                expr: ir::Expr::Call { fn_name: "add".into(), args: vec![] }
-            }]
-        };
+        let on_iter = ir::Block(vec![ir::Statement::Assignment {
+            to: vec!["i".into()],
+            expr: ir::Expr::Call { fn_name: "add".into(), args: vec![], span: None },
+            span: None,
+        }]);
         let a1 = ir::Statement::Assignment {
             to: vec!["x".into()],
-            expr: ir::Expr::Call { fn_name: "x_raise".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "x_raise".into(), args: vec![], span: None },
+            span: None,
         };
         let if_stmt = ir::Statement::If {
-            cond: ir::Expr::Call { fn_name: "continue".into(), args: vec![] },
-            body: ir::Block { 0: vec![ir::Statement::Continue] }
+            cond: ir::Expr::Call { fn_name: "continue".into(), args: vec![], span: None },
+            body: ir::Block(vec![ir::Statement::Continue]),
+            span: None,
         };
         let a2 = ir::Statement::Assignment {
             to: vec!["y".into()],
-            expr: ir::Expr::Call { fn_name: "y_raise".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "y_raise".into(), args: vec![], span: None },
+            span: None,
         };
-        let body = ir::Block{ 0: vec![a1, if_stmt, a2]};
+        let body = ir::Block(vec![a1, if_stmt, a2]);
         let for_loop = ir::Statement::ForLoop {
             setup,
             cond,
             on_iter,
-            body
+            body,
+            span: None,
         };
-        let block = ir::Block { 0: vec![s1, s2, for_loop] };
-        let mut builder = BasicBlocksBuilder::new(&vec![]);
-        builder.split_block(block);
+        let block = ir::Block(vec![s1, s2, for_loop]);
+        let mut builder = BasicBlocksBuilder::new(&[]);
+        builder.split_block(block).unwrap();
         dbg!(builder.basic_blocks);
         dbg!(builder.functions);
     }
@@ -523,48 +781,52 @@ mod test {
     fn test_bb_for_loop_break() {
         let s1 = ir::Statement::Assignment {
             to: vec!["a".into()],
-            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+            span: None,
         };
         let s2 = ir::Statement::Assignment {
             to: vec!["b".into()],
-            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+            span: None,
         };
 
-        let setup = ir::Block {
-            0: vec![ir::Statement::Assignment {
-                to: vec!["i".into()],
-                expr: ir::Expr::Literal([0u8; 32])
-            }]
-        };
+        let setup = ir::Block(vec![ir::Statement::Assignment {
+            to: vec!["i".into()],
+            expr: ir::Expr::Literal([0u8; 32]),
+            span: None,
+        }]);
         let cond = ir::Expr::Literal([1u8; 32]);
-        let on_iter = ir::Block {
-            0: vec![ir::Statement::Assignment {
-                to: vec!["i".into()],
-                expr: ir::Expr::Call { fn_name: "add".into(), args: vec![] }
-            }]
-        };
+        let on_iter = ir::Block(vec![ir::Statement::Assignment {
+            to: vec!["i".into()],
+            expr: ir::Expr::Call { fn_name: "add".into(), args: vec![], span: None },
+            span: None,
+        }]);
         let a1 = ir::Statement::Assignment {
             to: vec!["x".into()],
-            expr: ir::Expr::Call { fn_name: "x_raise".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "x_raise".into(), args: vec![], span: None },
+            span: None,
         };
         let if_stmt = ir::Statement::If {
-            cond: ir::Expr::Call { fn_name: "break".into(), args: vec![] },
-            body: ir::Block { 0: vec![ir::Statement::Break] }
+            cond: ir::Expr::Call { fn_name: "break".into(), args: vec![], span: None },
+            body: ir::Block(vec![ir::Statement::Break]),
+            span: None,
         };
         let a2 = ir::Statement::Assignment {
             to: vec!["y".into()],
-            expr: ir::Expr::Call { fn_name: "y_raise".into(), args: vec![] }
+            expr: ir::Expr::Call { fn_name: "y_raise".into(), args: vec![], span: None },
+            span: None,
         };
-        let body = ir::Block{ 0: vec![a1, if_stmt, a2]};
+        let body = ir::Block(vec![a1, if_stmt, a2]);
         let for_loop = ir::Statement::ForLoop {
             setup,
             cond,
             on_iter,
-            body
+            body,
+            span: None,
         };
-        let block = ir::Block { 0: vec![s1, s2, for_loop] };
-        let mut builder = BasicBlocksBuilder::new(&vec![]);
-        builder.split_block(block);
+        let block = ir::Block(vec![s1, s2, for_loop]);
+        let mut builder = BasicBlocksBuilder::new(&[]);
+        builder.split_block(block).unwrap();
         dbg!(builder.basic_blocks);
         dbg!(builder.functions);
     }
@@ -576,30 +838,33 @@ mod test {
                 name: "bla".into(),
                 args: vec!["x".into(), "y".into()],
                 rets: vec!["z".into()],
-                body: ir::Block{ 
-                    0: vec![
+                body: ir::Block(vec![
                         ir::Statement::Assignment {
                             to: vec!["a".into()],
-                            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], }
+                            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+                            span: None,
                         },
                         ir::Statement::Assignment {
                             to: vec!["b".into()],
-                            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], }
+                            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+                            span: None,
                         },
                         ir::Statement::If {
-                            cond: ir::Expr::Call { fn_name: "leave".into(), args: vec![] },
-                            body: ir::Block { 0: vec![ir::Statement::Leave] }
+                            cond: ir::Expr::Call { fn_name: "leave".into(), args: vec![], span: None },
+                            body: ir::Block(vec![ir::Statement::Leave]),
+                            span: None,
                         },
                         ir::Statement::Assignment {
                             to: vec!["c".into()],
-                            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], }
+                            expr: ir::Expr::Call { fn_name: "bla".into(), args: vec![], span: None },
+                            span: None,
                         },
-                ]},
+                ]),
             }
         );
-        let block = ir::Block { 0: vec![f] };
-        let mut builder = BasicBlocksBuilder::new(&vec![]);
-        builder.split_block(block);
+        let block = ir::Block(vec![f]);
+        let mut builder = BasicBlocksBuilder::new(&[]);
+        builder.split_block(block).unwrap();
         dbg!(builder.basic_blocks);
         dbg!(builder.functions);
     }
@@ -621,8 +886,126 @@ mod test {
                 "skrr".to_owned(),
                 "sender_slot".to_owned(),
             ],
+            exit: Terminator::Halt,
         };
 
         dbg!(bb.flatten_to().schedule_memory());
     }
+
+    #[test]
+    fn test_fn_def_calling_convention() {
+        let f = ir::Statement::FnDef(
+            ir::FunctionDefinition {
+                name: "f".into(),
+                args: vec!["x".into(), "y".into()],
+                rets: vec!["z".into()],
+                body: ir::Block(vec![ir::Statement::Assignment {
+                    to: vec!["z".into()],
+                    expr: ir::Expr::Call { fn_name: "add".into(), args: vec![ir::Expr::VarRef("x".into()), ir::Expr::VarRef("y".into())], span: None },
+                    span: None,
+                }]),
+            }
+        );
+        let mut builder = BasicBlocksBuilder::new(&[]);
+        builder.split_block(ir::Block(vec![f])).unwrap();
+        let blocks = &builder.functions["f"];
+        let entry = &blocks[0].start_stack;
+        assert_eq!(entry[..2], ["y".to_owned(), "x".to_owned()]);
+        assert_eq!(entry.len(), 3);
+        let exit = &blocks.last().unwrap().end_stack;
+        assert_eq!(exit[0], "z");
+        assert_eq!(exit[1], entry[2]);
+    }
+
+    #[test]
+    fn test_builtin_expr() {
+        let expr: Expr = ir::Expr::Builtin { fn_name: "datasize".into(), input: "runtime".into(), span: None }.into();
+        match expr {
+            Expr::Call { fn_name, args, .. } => {
+                assert_eq!(fn_name, literal_builtin_call("datasize", "runtime"));
+                assert!(args.is_empty());
+            }
+            other => panic!("Builtin converted to {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lowering_errors() {
+        let lower =
+            |source: &str| Program::try_from(source.parse::<ir::Block>().unwrap()).map(|_| ());
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let cases = [
+            (
+                "{ { let x := 1 } sstore(0, x) }",
+                LoweringError::UndefinedVariable("x".into()),
+            ),
+            (
+                "{ let y := 1 function f() -> r { r := y } }",
+                LoweringError::UndefinedVariable("y".into()),
+            ),
+            (
+                "{ function f() { } { function f() { } } }",
+                LoweringError::DuplicateFunction("f".into()),
+            ),
+            ("{ let x := add(1) }", LoweringError::Arity("add".into())),
+            (
+                "{ function f(a) -> b { } let x, y := f(1) }",
+                LoweringError::Arity("f".into()),
+            ),
+            (
+                "{ let a, b := 1 }",
+                LoweringError::ValueCount(names(&["a", "b"])),
+            ),
+            ("{ if 1 { break } }", LoweringError::OutsideLoop),
+            ("{ leave }", LoweringError::OutsideFunction),
+        ];
+        for (source, err) in cases {
+            assert_eq!(lower(source), Err(err), "{}", source);
+        }
+        lower("{ function f(a) -> b { b := a } let x := f(1) sstore(x, undefined_function()) }")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_lower_loop() {
+        let source = "{
+            let s := 0
+            for { let i := 0 } lt(i, 5) { i := add(i, 1) } {
+                if eq(i, 1) { continue }
+                s := add(s, i)
+            }
+            sstore(0, s)
+        }";
+        let program = Program::try_from(source.parse::<ir::Block>().unwrap()).unwrap();
+        let exits: Vec<Terminator> = program
+            .main
+            .nodes
+            .iter()
+            .map(|node| node.exit.clone())
+            .collect();
+        // Entry, loop header, body, post, exit, if body and what follows the if.
+        assert_eq!(
+            exits,
+            vec![
+                Terminator::Jump(1),
+                Terminator::Branch {
+                    non_zero: 2,
+                    zero: 4
+                },
+                Terminator::Branch {
+                    non_zero: 5,
+                    zero: 6
+                },
+                Terminator::Jump(1),
+                Terminator::Halt,
+                Terminator::Jump(3),
+                Terminator::Jump(3),
+            ]
+        );
+        let header = &program.main.nodes[1].block;
+        assert_eq!(header.start_stack, ["s", "i"]);
+        assert_eq!(header.end_stack, ["s", "i", "cond#0"]);
+        let body = &program.main.nodes[6].block;
+        assert_eq!(body.end_stack, ["s", "i"]);
+    }
 }
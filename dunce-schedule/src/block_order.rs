@@ -0,0 +1,174 @@
+use crate::cfg::{BlockId, Function, Terminator};
+
+/// Whether the node only ever ends in a `revert` or `invalid`, directly or through all its
+/// successors. The entry is never cold.
+pub fn cold_blocks(function: &Function) -> Vec<bool> {
+    let mut cold: Vec<bool> = function
+        .nodes
+        .iter()
+        .map(|node| {
+            node.exit == Terminator::Halt
+                && matches!(
+                    node.block.statements.last().and_then(|stmt| stmt.callee()),
+                    Some("revert" | "invalid")
+                )
+        })
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (id, node) in function.nodes.iter().enumerate().skip(1) {
+            let succs = node.exit.successors();
+            if !cold[id] && !succs.is_empty() && succs.iter().all(|succ| cold[*succ]) {
+                cold[id] = true;
+                changed = true;
+            }
+        }
+    }
+    if let Some(entry) = cold.first_mut() {
+        *entry = false;
+    }
+    cold
+}
+
+/// Order to emit the nodes in, the entry first. Chains of nodes are laid out so each jumps
+/// to its successor by falling through where possible: the successor staying in the deepest
+/// loop comes first, and among equals the `zero` side of a branch, which needs no inverted
+/// condition. A chain ending continues with the deepest pending successor, so loop bodies stay
+/// contiguous. Cold nodes come after all others, and unreachable ones last.
+pub fn block_order(function: &Function) -> Vec<BlockId> {
+    let n = function.nodes.len();
+    let depths = function.loop_depths();
+    let cold = cold_blocks(function);
+    let reachable = function.reachable();
+    let mut placed = vec![false; n];
+    let mut order = vec![];
+
+    for place_cold in [false, true] {
+        let mut pending: Vec<BlockId> = match place_cold {
+            false => (0..n.min(1)).collect(),
+            true => (0..n).rev().filter(|id| cold[*id]).collect(),
+        };
+        let eligible =
+            |id: BlockId, placed: &[bool]| !placed[id] && cold[id] == place_cold && reachable[id];
+        while let Some((index, _)) = pending
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| eligible(**id, &placed))
+            .max_by_key(|(index, id)| (depths[**id], *index))
+        {
+            let mut current = pending.remove(index);
+            loop {
+                placed[current] = true;
+                order.push(current);
+                let mut succs: Vec<BlockId> = match function.nodes[current].exit {
+                    Terminator::Branch { non_zero, zero } => vec![zero, non_zero],
+                    ref exit => exit.successors(),
+                };
+                succs.retain(|succ| eligible(*succ, &placed));
+                succs.sort_by_key(|succ| std::cmp::Reverse(depths[*succ]));
+                let Some(next) = succs.first().copied() else {
+                    break;
+                };
+                pending.extend(succs.into_iter().skip(1).rev());
+                current = next;
+            }
+        }
+    }
+    order.extend((0..n).filter(|id| !placed[*id]));
+    order
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembly::{Assembler, JUMP, JUMPI};
+    use crate::cfg::Node;
+    use crate::ssa_block::Statement;
+    use crate::stack_scheduler::test::{call, lit, node as node_with};
+    use crate::strategy::{Scheduler, Strategy};
+
+    fn node(statements: Vec<Statement>, exit: Terminator) -> Node {
+        let end: &[&str] = match exit {
+            Terminator::Branch { .. } => &["c"],
+            _ => &[],
+        };
+        node_with(&[], statements, end, exit)
+    }
+
+    fn branch(non_zero: BlockId, zero: BlockId) -> Node {
+        node(
+            vec![call(&["c"], "gas", vec![])],
+            Terminator::Branch { non_zero, zero },
+        )
+    }
+
+    fn halt(calls: &str) -> Node {
+        let takes = match calls {
+            "revert" => 2,
+            _ => 0,
+        };
+        node(
+            vec![call(&[], calls, (0..takes).map(|_| lit(0)).collect())],
+            Terminator::Halt,
+        )
+    }
+
+    #[test]
+    fn test_cold_blocks() {
+        // 0 -> 1 | 2, 1 -> 3 (revert), 2 -> 4 (stop).
+        let function = Function {
+            nodes: vec![
+                branch(1, 2),
+                node(vec![], Terminator::Jump(3)),
+                node(vec![], Terminator::Jump(4)),
+                halt("revert"),
+                halt("stop"),
+            ],
+        };
+        assert_eq!(
+            cold_blocks(&function),
+            vec![false, true, false, true, false]
+        );
+        assert_eq!(block_order(&function), vec![0, 2, 4, 1, 3]);
+    }
+
+    #[test]
+    fn test_loop_contiguous() {
+        // 0 -> 1 (header), 1 -> 5 (exit) | 2, 2 -> 3 | 4, 3 -> 1, 4 -> 1, 5 stops, 6 unreachable.
+        let function = Function {
+            nodes: vec![
+                node(vec![], Terminator::Jump(1)),
+                branch(5, 2),
+                branch(3, 4),
+                node(vec![], Terminator::Jump(1)),
+                node(vec![], Terminator::Jump(1)),
+                halt("stop"),
+                halt("stop"),
+            ],
+        };
+        assert_eq!(block_order(&function), vec![0, 1, 2, 4, 3, 5, 6]);
+    }
+
+    #[test]
+    fn test_fall_through() {
+        // 0 -> 1 | 2 with the revert on the zero side, 1 -> 3, 2 reverts, 3 stops.
+        let function = Function {
+            nodes: vec![
+                branch(1, 2),
+                node(vec![], Terminator::Jump(3)),
+                halt("revert"),
+                halt("stop"),
+            ],
+        };
+        assert_eq!(block_order(&function), vec![0, 1, 3, 2]);
+
+        let schedules = Strategy::Stack.schedule_function(&function).unwrap();
+        let mut assembler = Assembler::default();
+        assembler.function(None, &function, &schedules).unwrap();
+        let code = assembler.assemble().unwrap().code;
+        // The branch inverts its condition to fall through to 1, which falls through to 3.
+        assert_eq!(code.iter().filter(|op| **op == JUMPI).count(), 1);
+        assert_eq!(code.iter().filter(|op| **op == JUMP).count(), 0);
+    }
+}
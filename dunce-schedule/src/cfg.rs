@@ -0,0 +1,255 @@
+use crate::dialect::{EvmVersion, Unavailable};
+use crate::ssa_block::Block;
+use std::collections::BTreeMap;
+
+pub type BlockId = usize;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Terminator {
+    /// Continues execution at the given block.
+    Jump(BlockId),
+    /// Consumes the top of the `end_stack` as the condition.
+    Branch { non_zero: BlockId, zero: BlockId },
+    /// Returns to the address on top of the `end_stack` (`leave` or end of function body).
+    Leave,
+    /// Execution does not continue past the block (`stop`, `return`, `revert`, ...).
+    Halt,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(to) => vec![*to],
+            Terminator::Branch { non_zero, zero } => vec![*non_zero, *zero],
+            Terminator::Leave | Terminator::Halt => vec![],
+        }
+    }
+
+    fn remap(&mut self, new_ids: &[Option<BlockId>]) {
+        let remap = |id: &mut BlockId| {
+            *id = new_ids[*id].expect("Successor of reachable block unreachable")
+        };
+        match self {
+            Terminator::Jump(to) => remap(to),
+            Terminator::Branch { non_zero, zero } => {
+                remap(non_zero);
+                remap(zero);
+            }
+            Terminator::Leave | Terminator::Halt => (),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub block: Block,
+    pub exit: Terminator,
+}
+
+/// Control flow graph of a function body, the first node is the entry.
+#[derive(Clone, Debug, Default)]
+pub struct Function {
+    pub nodes: Vec<Node>,
+}
+
+impl Function {
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut seen = vec![false; self.nodes.len()];
+        let mut todo: Vec<BlockId> = vec![];
+        if !self.nodes.is_empty() {
+            todo.push(0);
+        }
+        while let Some(id) = todo.pop() {
+            if seen[id] {
+                continue;
+            }
+            seen[id] = true;
+            todo.extend(self.nodes[id].exit.successors());
+        }
+        seen
+    }
+
+    /// Keeps only the nodes for which `keep` is set, renumbering the remaining ones.
+    pub fn retain_nodes(&mut self, keep: &[bool]) {
+        let mut next_id = 0;
+        let new_ids: Vec<Option<BlockId>> = keep
+            .iter()
+            .map(|kept| {
+                kept.then(|| {
+                    next_id += 1;
+                    next_id - 1
+                })
+            })
+            .collect();
+        let nodes = std::mem::take(&mut self.nodes);
+        self.nodes = nodes
+            .into_iter()
+            .zip(keep)
+            .filter(|(_, kept)| **kept)
+            .map(|(mut node, _)| {
+                node.exit.remap(&new_ids);
+                node
+            })
+            .collect();
+    }
+
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![vec![]; self.nodes.len()];
+        for (id, node) in self.nodes.iter().enumerate() {
+            node.exit
+                .successors()
+                .into_iter()
+                .for_each(|succ| preds[succ].push(id));
+        }
+        preds
+    }
+
+    /// Number of loops each node is part of. A loop is headed by the target of a back edge found
+    /// by a depth first search from the entry and contains every node reaching the back edge
+    /// without passing through the header.
+    pub fn loop_depths(&self) -> Vec<usize> {
+        let preds = self.predecessors();
+        let mut back_edges: Vec<(BlockId, BlockId)> = vec![];
+        let mut state = vec![0u8; self.nodes.len()];
+        let mut todo: Vec<(BlockId, usize)> = vec![];
+        if !self.nodes.is_empty() {
+            todo.push((0, 0));
+            state[0] = 1;
+        }
+        while let Some((id, next)) = todo.pop() {
+            let succs = self.nodes[id].exit.successors();
+            let Some(succ) = succs.get(next).copied() else {
+                state[id] = 2;
+                continue;
+            };
+            todo.push((id, next + 1));
+            match state[succ] {
+                0 => {
+                    state[succ] = 1;
+                    todo.push((succ, 0));
+                }
+                1 => back_edges.push((id, succ)),
+                _ => (),
+            }
+        }
+
+        let mut bodies: BTreeMap<BlockId, Vec<bool>> = BTreeMap::new();
+        for (tail, header) in back_edges {
+            let body = bodies
+                .entry(header)
+                .or_insert_with(|| vec![false; self.nodes.len()]);
+            body[header] = true;
+            let mut todo = vec![tail];
+            while let Some(id) = todo.pop() {
+                if !body[id] {
+                    body[id] = true;
+                    todo.extend(preds[id].iter().copied());
+                }
+            }
+        }
+        (0..self.nodes.len())
+            .map(|id| bodies.values().filter(|body| body[id]).count())
+            .collect()
+    }
+
+    /// Names of all functions called from within this function.
+    pub fn callees(&self) -> impl Iterator<Item = &str> {
+        self.nodes
+            .iter()
+            .flat_map(|node| node.block.statements.iter())
+            .filter_map(|stmt| stmt.callee())
+    }
+}
+
+/// `main` and the user functions of a program.
+///
+/// A user function is called like any other call, with its first argument on top of the stack,
+/// and the caller pushes the return address above the arguments. The entry node's `start_stack`
+/// is thus the arguments, last first, followed by the return address. The function returns
+/// through `Terminator::Leave` with the return values, last on top, followed by the return
+/// address as its `end_stack`.
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    pub main: Function,
+    pub functions: BTreeMap<String, Function>,
+}
+
+impl Program {
+    /// Fails on the first call of a builtin `version` does not have.
+    pub fn check_evm_version(&self, version: EvmVersion) -> Result<(), Unavailable> {
+        std::iter::once(&self.main)
+            .chain(self.functions.values())
+            .flat_map(Function::callees)
+            .try_for_each(|callee| version.check(callee))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stack_scheduler::test::{call, node};
+
+    #[test]
+    fn test_loop_depths() {
+        let node = |exit| node(&[], vec![], &[], exit);
+        // 0 -> 1 (outer header) -> 2 (inner header) -> 3 -> 2, 2 -> 4 -> 1, 1 -> 5.
+        let function = Function {
+            nodes: vec![
+                node(Terminator::Jump(1)),
+                node(Terminator::Branch {
+                    non_zero: 2,
+                    zero: 5,
+                }),
+                node(Terminator::Branch {
+                    non_zero: 3,
+                    zero: 4,
+                }),
+                node(Terminator::Jump(2)),
+                node(Terminator::Jump(1)),
+                node(Terminator::Halt),
+            ],
+        };
+        assert_eq!(function.loop_depths(), vec![0, 1, 2, 2, 1, 0]);
+        assert_eq!(function.predecessors()[2], vec![1, 3]);
+    }
+
+    #[test]
+    fn test_check_evm_version() {
+        let call = |calls: &str| call(&[], calls, vec![]);
+        let function = |calls: &str| Function {
+            nodes: vec![node(
+                &[],
+                vec![call("f"), call(calls)],
+                &[],
+                Terminator::Halt,
+            )],
+        };
+        let program = Program {
+            main: function("chainid"),
+            functions: BTreeMap::from([("f".to_owned(), function("stop"))]),
+        };
+        assert_eq!(program.check_evm_version(EvmVersion::Istanbul), Ok(()));
+        assert_eq!(
+            program.check_evm_version(EvmVersion::Petersburg),
+            Err(Unavailable {
+                builtin: "chainid".to_owned(),
+                since: EvmVersion::Istanbul,
+                version: EvmVersion::Petersburg,
+            })
+        );
+
+        let reverting = Program {
+            main: function("revert"),
+            ..Default::default()
+        };
+        assert_eq!(reverting.check_evm_version(EvmVersion::Byzantium), Ok(()));
+        assert_eq!(
+            reverting.check_evm_version(EvmVersion::SpuriousDragon),
+            Err(Unavailable {
+                builtin: "revert".to_owned(),
+                since: EvmVersion::Byzantium,
+                version: EvmVersion::SpuriousDragon,
+            })
+        );
+    }
+}
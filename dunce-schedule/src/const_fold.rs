@@ -0,0 +1,381 @@
+use crate::cfg::{Function, Program, Terminator};
+use crate::ssa_block::{Block, Name, Statement, Value};
+use ir::Literal;
+use ruint::aliases::U256;
+use std::collections::HashMap;
+
+pub trait ConstantFolding {
+    fn fold_constants(&mut self);
+}
+
+fn is_negative(x: U256) -> bool {
+    x.bit(255)
+}
+
+fn abs(x: U256) -> U256 {
+    if is_negative(x) {
+        x.wrapping_neg()
+    } else {
+        x
+    }
+}
+
+fn bool_word(b: bool) -> U256 {
+    if b {
+        U256::from(1)
+    } else {
+        U256::ZERO
+    }
+}
+
+/// Converts a shift amount or byte index to `usize` if it is below `limit`.
+fn small(x: U256, limit: usize) -> Option<usize> {
+    (x < U256::from(limit)).then(|| x.to::<usize>())
+}
+
+fn signed_lt(a: U256, b: U256) -> bool {
+    match (is_negative(a), is_negative(b)) {
+        (true, false) => true,
+        (false, true) => false,
+        _ => a < b,
+    }
+}
+
+/// Evaluates a pure builtin with the exact wraparound semantics of the EVM. Arguments are in
+/// call order, `None` if `fn_name` is not a foldable builtin or the arity doesn't match.
+pub fn evaluate(fn_name: &str, args: &[U256]) -> Option<U256> {
+    let value = match (fn_name, args) {
+        ("add", &[a, b]) => a.wrapping_add(b),
+        ("sub", &[a, b]) => a.wrapping_sub(b),
+        ("mul", &[a, b]) => a.wrapping_mul(b),
+        ("div", &[a, b]) => a.checked_div(b).unwrap_or_default(),
+        ("mod", &[a, b]) => a.checked_rem(b).unwrap_or_default(),
+        ("sdiv", &[a, b]) => {
+            let quotient = abs(a).checked_div(abs(b)).unwrap_or_default();
+            if is_negative(a) != is_negative(b) {
+                quotient.wrapping_neg()
+            } else {
+                quotient
+            }
+        }
+        ("smod", &[a, b]) => {
+            let remainder = abs(a).checked_rem(abs(b)).unwrap_or_default();
+            if is_negative(a) {
+                remainder.wrapping_neg()
+            } else {
+                remainder
+            }
+        }
+        ("addmod", &[a, b, n]) => a.add_mod(b, n),
+        ("mulmod", &[a, b, n]) => a.mul_mod(b, n),
+        ("exp", &[a, b]) => a.wrapping_pow(b),
+        ("signextend", &[b, x]) => match small(b, 31) {
+            Some(b) => {
+                let sign_bit = b * 8 + 7;
+                let mask = (U256::from(1) << (sign_bit + 1)).wrapping_sub(U256::from(1));
+                if x.bit(sign_bit) {
+                    x | !mask
+                } else {
+                    x & mask
+                }
+            }
+            None => x,
+        },
+        ("lt", &[a, b]) => bool_word(a < b),
+        ("gt", &[a, b]) => bool_word(a > b),
+        ("slt", &[a, b]) => bool_word(signed_lt(a, b)),
+        ("sgt", &[a, b]) => bool_word(signed_lt(b, a)),
+        ("eq", &[a, b]) => bool_word(a == b),
+        ("iszero", &[a]) => bool_word(a.is_zero()),
+        ("and", &[a, b]) => a & b,
+        ("or", &[a, b]) => a | b,
+        ("xor", &[a, b]) => a ^ b,
+        ("not", &[a]) => !a,
+        ("byte", &[i, x]) => match small(i, 32) {
+            Some(i) => (x >> (248 - i * 8)) & U256::from(0xff),
+            None => U256::ZERO,
+        },
+        ("shl", &[shift, x]) => small(shift, 256).map_or(U256::ZERO, |shift| x << shift),
+        ("shr", &[shift, x]) => small(shift, 256).map_or(U256::ZERO, |shift| x >> shift),
+        ("sar", &[shift, x]) => match small(shift, 256) {
+            Some(shift) => x.arithmetic_shr(shift),
+            None if is_negative(x) => U256::MAX,
+            None => U256::ZERO,
+        },
+        _ => return None,
+    };
+    Some(value)
+}
+
+pub fn to_word(lit: &Literal) -> U256 {
+    U256::from_be_bytes(*lit)
+}
+
+pub fn to_literal(word: U256) -> Literal {
+    word.to_be_bytes()
+}
+
+fn propagate(value: &mut Value, known: &HashMap<Name, Literal>) {
+    if let Value::RefName(name) = value {
+        if let Some(lit) = known.get(name) {
+            *value = Value::Literal(*lit);
+        }
+    }
+}
+
+fn fold_call(stmt: &Statement) -> Option<Statement> {
+    let Statement::CallAssign {
+        assigns,
+        calls,
+        takes,
+        span,
+    } = stmt
+    else {
+        return None;
+    };
+    let [to] = assigns.as_slice() else {
+        return None;
+    };
+    let args = takes
+        .iter()
+        .map(|value| match value {
+            Value::Literal(lit) => Some(to_word(lit)),
+            Value::RefName(_) => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let result = evaluate(calls, &args)?;
+    Some(Statement::ValueAssign {
+        to: to.clone(),
+        value: Value::Literal(to_literal(result)),
+        span: *span,
+    })
+}
+
+/// Folds the statements of `block` given the constants `known` at its start, returning the
+/// constants known at its end.
+fn fold_block(block: &mut Block, mut known: HashMap<Name, Literal>) -> HashMap<Name, Literal> {
+    for stmt in block.statements.iter_mut() {
+        match stmt {
+            Statement::CallAssign { takes, .. } => {
+                takes.iter_mut().for_each(|value| propagate(value, &known))
+            }
+            Statement::ValueAssign { value, .. } => propagate(value, &known),
+        }
+        if let Some(folded) = fold_call(stmt) {
+            *stmt = folded;
+        }
+        stmt.defs().into_iter().for_each(|name| {
+            known.remove(name);
+        });
+        if let Statement::ValueAssign {
+            to,
+            value: Value::Literal(lit),
+            ..
+        } = stmt
+        {
+            known.insert(to.clone(), *lit);
+        }
+    }
+    known
+}
+
+/// Value of the branch condition on top of the `end_stack`, if constant.
+fn branch_condition(block: &Block, known: &HashMap<Name, Literal>) -> Option<bool> {
+    let cond = block.end_stack.last()?;
+    known.get(&cond.into()).map(|lit| *lit != [0u8; 32])
+}
+
+fn taken_successors(node_exit: &Terminator, cond: Option<bool>) -> Vec<usize> {
+    match (node_exit, cond) {
+        (Terminator::Branch { non_zero, .. }, Some(true)) => vec![*non_zero],
+        (Terminator::Branch { zero, .. }, Some(false)) => vec![*zero],
+        (exit, _) => exit.successors(),
+    }
+}
+
+impl ConstantFolding for Block {
+    fn fold_constants(&mut self) {
+        fold_block(self, HashMap::new());
+    }
+}
+
+impl Function {
+    /// Constants known on entry of every block, found by optimistically iterating to a fixpoint
+    /// over the control flow graph. `None` for blocks never reached.
+    fn entry_constants(&self) -> Vec<Option<HashMap<Name, Literal>>> {
+        let mut entries: Vec<Option<HashMap<Name, Literal>>> = vec![None; self.nodes.len()];
+        if let Some(entry) = entries.first_mut() {
+            *entry = Some(HashMap::new());
+        }
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (id, node) in self.nodes.iter().enumerate() {
+                let Some(entry) = entries[id].clone() else {
+                    continue;
+                };
+                let exit = fold_block(&mut node.block.clone(), entry);
+                let cond = match node.exit {
+                    Terminator::Branch { .. } => branch_condition(&node.block, &exit),
+                    _ => None,
+                };
+                for succ in taken_successors(&node.exit, cond) {
+                    let outgoing: HashMap<Name, Literal> = self.nodes[succ]
+                        .block
+                        .start_stack
+                        .iter()
+                        .filter_map(|name| {
+                            let name: Name = name.into();
+                            exit.get(&name).map(|lit| (name, *lit))
+                        })
+                        .collect();
+                    match &mut entries[succ] {
+                        Some(incoming) => {
+                            let before = incoming.len();
+                            incoming.retain(|name, lit| outgoing.get(name) == Some(lit));
+                            changed |= incoming.len() != before;
+                        }
+                        None => {
+                            entries[succ] = Some(outgoing);
+                            changed = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        entries
+    }
+}
+
+impl ConstantFolding for Function {
+    /// Folds and propagates constants across blocks, turning branches on constant conditions
+    /// into jumps. Leaves now dead statements and blocks to dead-code elimination.
+    fn fold_constants(&mut self) {
+        let entries = self.entry_constants();
+        for (node, entry) in self.nodes.iter_mut().zip(entries) {
+            let exit = fold_block(&mut node.block, entry.unwrap_or_default());
+            if let Terminator::Branch { .. } = node.exit {
+                if let Some(cond) = branch_condition(&node.block, &exit) {
+                    node.exit = Terminator::Jump(taken_successors(&node.exit, Some(cond))[0]);
+                    node.block.end_stack.pop();
+                }
+            }
+        }
+    }
+}
+
+impl ConstantFolding for Program {
+    fn fold_constants(&mut self) {
+        self.main.fold_constants();
+        self.functions
+            .values_mut()
+            .for_each(|function| function.fold_constants());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dce::DeadCodeElimination;
+    use crate::stack_scheduler::test::{call, lit, names, node, r};
+
+    fn word(x: i64) -> U256 {
+        if x < 0 {
+            U256::from(x.unsigned_abs()).wrapping_neg()
+        } else {
+            U256::from(x)
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let eval = |name: &str, args: &[i64]| {
+            evaluate(name, &args.iter().map(|x| word(*x)).collect::<Vec<_>>()).unwrap()
+        };
+        let min = U256::from(1) << 255;
+
+        assert_eq!(eval("sub", &[0, 1]), U256::MAX);
+        assert_eq!(eval("div", &[7, 0]), U256::ZERO);
+        assert_eq!(eval("sdiv", &[-7, 2]), word(-3));
+        assert_eq!(evaluate("sdiv", &[min, U256::MAX]), Some(min));
+        assert_eq!(eval("smod", &[-7, 2]), word(-1));
+        assert_eq!(eval("exp", &[2, 256]), U256::ZERO);
+        assert_eq!(eval("shl", &[256, 1]), U256::ZERO);
+        assert_eq!(eval("shr", &[4, 0x100]), U256::from(0x10));
+        assert_eq!(eval("sar", &[4, -32]), word(-2));
+        assert_eq!(eval("sar", &[300, -32]), U256::MAX);
+        assert_eq!(eval("byte", &[31, 0x1234]), U256::from(0x34));
+        assert_eq!(eval("byte", &[32, 0x1234]), U256::ZERO);
+        assert_eq!(eval("signextend", &[0, 0xff]), U256::MAX);
+        assert_eq!(eval("signextend", &[0, 0x17f]), U256::from(0x7f));
+        assert_eq!(eval("slt", &[-1, 0]), U256::from(1));
+        assert_eq!(
+            eval("addmod", &[-1, 2, 7]),
+            (U256::MAX % U256::from(7) + U256::from(2)) % U256::from(7)
+        );
+        assert_eq!(eval("mulmod", &[-1, -1, 0]), U256::ZERO);
+        assert_eq!(evaluate("sload", &[U256::ZERO]), None);
+    }
+
+    #[test]
+    fn test_fold_block() {
+        let mut bb = Block {
+            start_stack: names(&["a"]),
+            statements: vec![
+                call(&["x"], "add", vec![lit(1), lit(2)]),
+                call(&["y"], "mul", vec![r("x"), r("a")]),
+                call(&["z"], "shl", vec![r("x"), lit(1)]),
+            ],
+            end_stack: names(&["y", "z"]),
+        };
+        bb.fold_constants();
+        bb.eliminate_dead_code();
+
+        assert!(matches!(
+            &bb.statements[0],
+            Statement::CallAssign { takes, .. } if matches!(takes[0], Value::Literal(l) if l == to_literal(word(3)))
+        ));
+        assert!(matches!(
+            &bb.statements[1],
+            Statement::ValueAssign { value: Value::Literal(l), .. } if *l == to_literal(word(8))
+        ));
+    }
+
+    #[test]
+    fn test_fold_branch() {
+        let mut function = Function {
+            nodes: vec![
+                node(
+                    &[],
+                    vec![Statement::ValueAssign {
+                        to: "n".to_owned().into(),
+                        value: lit(3),
+                        span: None,
+                    }],
+                    &["n"],
+                    Terminator::Jump(1),
+                ),
+                node(
+                    &["n"],
+                    vec![call(&["c"], "gt", vec![r("n"), lit(5)])],
+                    &["n", "c"],
+                    Terminator::Branch {
+                        non_zero: 2,
+                        zero: 3,
+                    },
+                ),
+                node(&["n"], vec![], &[], Terminator::Halt),
+                node(&["n"], vec![], &[], Terminator::Halt),
+            ],
+        };
+        function.fold_constants();
+        assert_eq!(function.nodes[1].exit, Terminator::Jump(3));
+        assert_eq!(function.nodes[1].block.end_stack, vec!["n".to_owned()]);
+
+        function.eliminate_dead_code();
+        assert_eq!(function.nodes.len(), 3);
+        assert!(function.nodes[1].block.statements.is_empty());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::cfg::{Function, Program};
+use crate::dialect::{self, Locations};
+use crate::ssa_block::{Block, Name, Statement, Value};
+use ir::Literal;
+use std::collections::HashMap;
+
+pub trait CommonSubexpressionElimination {
+    fn eliminate_common_subexpressions(&mut self);
+}
+
+/// Key identifying equivalent calls: the function, its operand value numbers and the versions
+/// of the locations it reads from.
+type ExprKey = (String, Vec<usize>, Vec<usize>);
+
+#[derive(Debug, Default)]
+struct ValueNumbering {
+    next_number: usize,
+    of_name: HashMap<Name, usize>,
+    of_literal: HashMap<Literal, usize>,
+    leaders: HashMap<usize, Name>,
+    exprs: HashMap<ExprKey, usize>,
+    epochs: [usize; Locations::COUNT],
+}
+
+impl ValueNumbering {
+    fn fresh(&mut self) -> usize {
+        self.next_number += 1;
+        self.next_number - 1
+    }
+
+    fn number(&mut self, value: &Value) -> usize {
+        match value {
+            Value::RefName(name) => match self.of_name.get(name) {
+                Some(number) => *number,
+                None => {
+                    let number = self.fresh();
+                    self.define(name, number);
+                    number
+                }
+            },
+            Value::Literal(lit) => match self.of_literal.get(lit) {
+                Some(number) => *number,
+                None => {
+                    let number = self.fresh();
+                    self.of_literal.insert(*lit, number);
+                    number
+                }
+            },
+        }
+    }
+
+    /// Name currently holding `number`, if any still does.
+    fn leader(&self, number: usize) -> Option<&Name> {
+        self.leaders
+            .get(&number)
+            .filter(|name| self.of_name.get(*name) == Some(&number))
+    }
+
+    fn define(&mut self, name: &Name, number: usize) {
+        self.of_name.insert(name.clone(), number);
+        if self.leader(number).is_none() {
+            self.leaders.insert(number, name.clone());
+        }
+    }
+
+    /// Replaces references to copies with the name first holding the value.
+    fn canonicalize(&self, value: &mut Value) {
+        if let Value::RefName(name) = value {
+            let leader = self
+                .of_name
+                .get(name)
+                .and_then(|number| self.leader(*number));
+            if let Some(leader) = leader {
+                *name = leader.clone();
+            }
+        }
+    }
+
+    fn clobber(&mut self, writes: Locations) {
+        writes.indices().for_each(|i| self.epochs[i] += 1);
+    }
+
+    fn key(&mut self, calls: &str, takes: &[Value], reads: Locations) -> ExprKey {
+        let mut operands: Vec<usize> = takes.iter().map(|value| self.number(value)).collect();
+        if dialect::is_commutative(calls) {
+            operands.sort();
+        }
+        let versions = reads.indices().map(|i| self.epochs[i]).collect();
+        (calls.to_owned(), operands, versions)
+    }
+
+    fn visit(&mut self, stmt: &mut Statement) {
+        match stmt {
+            Statement::ValueAssign { to, value, .. } => {
+                self.canonicalize(value);
+                let number = self.number(value);
+                self.define(to, number);
+            }
+            Statement::CallAssign {
+                assigns,
+                calls,
+                takes,
+                span,
+            } => {
+                takes.iter_mut().for_each(|value| self.canonicalize(value));
+                let reusable = dialect::builtin(calls).filter(|builtin| {
+                    builtin.returns == 1
+                        && builtin.is_removable()
+                        && !builtin.reads.intersects(Locations::EXECUTION)
+                });
+                match (reusable, assigns.as_slice()) {
+                    (Some(builtin), [to]) => {
+                        let key = self.key(calls, takes, builtin.reads);
+                        let existing = self.exprs.get(&key).copied();
+                        let replacement = existing.and_then(|number| self.leader(number)).cloned();
+                        let to = to.clone();
+                        match (existing, replacement) {
+                            (Some(number), Some(leader)) => {
+                                self.define(&to, number);
+                                *stmt = Statement::ValueAssign {
+                                    to,
+                                    value: Value::RefName(leader),
+                                    span: *span,
+                                };
+                            }
+                            _ => {
+                                let number = self.fresh();
+                                self.exprs.insert(key, number);
+                                self.define(&to, number);
+                            }
+                        }
+                    }
+                    _ => {
+                        let writes = dialect::builtin(calls)
+                            .map_or(Locations::ALL, |builtin| builtin.writes);
+                        self.clobber(writes);
+                        for name in assigns.iter() {
+                            let number = self.fresh();
+                            self.define(name, number);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl CommonSubexpressionElimination for Block {
+    /// Replaces calls equivalent to an earlier one in the block with a reference to the
+    /// earlier result. Calls reading memory, storage or other state are only considered
+    /// equivalent if no statement in between may have written to what they read.
+    fn eliminate_common_subexpressions(&mut self) {
+        let mut numbering = ValueNumbering::default();
+        self.statements
+            .iter_mut()
+            .for_each(|stmt| numbering.visit(stmt));
+    }
+}
+
+impl CommonSubexpressionElimination for Function {
+    fn eliminate_common_subexpressions(&mut self) {
+        self.nodes
+            .iter_mut()
+            .for_each(|node| node.block.eliminate_common_subexpressions());
+    }
+}
+
+impl CommonSubexpressionElimination for Program {
+    fn eliminate_common_subexpressions(&mut self) {
+        self.main.eliminate_common_subexpressions();
+        self.functions
+            .values_mut()
+            .for_each(|function| function.eliminate_common_subexpressions());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dce::DeadCodeElimination;
+    use crate::stack_scheduler::test::{block, call, calls, lit, r};
+
+    #[test]
+    fn test_cse_selector() {
+        // Flattened `x := shr(224, calldataload(0))` and `y := shr(224, calldataload(0))`.
+        let im = |id| Value::RefName(Name::Intermed(id));
+        let assign = |to: Name, calls: &str, takes| Statement::CallAssign {
+            assigns: vec![to],
+            calls: calls.to_owned(),
+            takes,
+            span: None,
+        };
+        let mut bb = block(
+            vec![
+                assign(Name::Intermed(0), "calldataload", vec![lit(0)]),
+                call(&["x"], "shr", vec![lit(224), im(0)]),
+                assign(Name::Intermed(1), "calldataload", vec![lit(0)]),
+                call(&["y"], "shr", vec![lit(224), im(1)]),
+            ],
+            &["x", "y"],
+        );
+        bb.eliminate_common_subexpressions();
+        bb.eliminate_dead_code();
+        assert_eq!(calls(&bb), vec!["calldataload", "shr"]);
+        assert!(matches!(
+            bb.statements.last(),
+            Some(Statement::ValueAssign { to, value: Value::RefName(Name::Ident(from)), .. })
+                if *to == Name::Ident("y".to_owned()) && from == "x"
+        ));
+    }
+
+    #[test]
+    fn test_cse_commutative() {
+        let mut bb = block(
+            vec![
+                call(&["x"], "add", vec![r("a"), r("b")]),
+                call(&["y"], "add", vec![r("b"), r("a")]),
+                call(&["z"], "sub", vec![r("b"), r("a")]),
+                call(&["w"], "sub", vec![r("a"), r("b")]),
+                call(&["v"], "mul", vec![r("y"), r("z")]),
+            ],
+            &["v", "w"],
+        );
+        bb.eliminate_common_subexpressions();
+        bb.eliminate_dead_code();
+        assert_eq!(calls(&bb), vec!["add", "sub", "sub", "mul"]);
+        assert!(matches!(
+            &bb.statements[3],
+            Statement::CallAssign { takes, .. } if matches!(&takes[0], Value::RefName(Name::Ident(n)) if n == "x")
+        ));
+    }
+
+    #[test]
+    fn test_cse_effect_barriers() {
+        let mut bb = block(
+            vec![
+                call(&["x"], "sload", vec![lit(0)]),
+                call(&["m"], "mload", vec![lit(0)]),
+                call(&[], "mstore", vec![lit(0), r("a")]),
+                call(&["y"], "sload", vec![lit(0)]),
+                call(&["n"], "mload", vec![lit(0)]),
+                call(&[], "sstore", vec![lit(0), r("b")]),
+                call(&["z"], "sload", vec![lit(0)]),
+                call(&["g1"], "gas", vec![]),
+                call(&["g2"], "gas", vec![]),
+            ],
+            &["x", "y", "z", "m", "n", "g1", "g2"],
+        );
+        bb.eliminate_common_subexpressions();
+        assert_eq!(
+            calls(&bb),
+            vec!["sload", "mload", "mstore", "mload", "sstore", "sload", "gas", "gas"]
+        );
+    }
+
+    #[test]
+    fn test_cse_reassignment() {
+        let mut bb = block(
+            vec![
+                call(&["x"], "add", vec![r("a"), lit(1)]),
+                call(&["a"], "add", vec![r("a"), lit(1)]),
+                call(&["y"], "add", vec![r("a"), lit(1)]),
+            ],
+            &["x", "y"],
+        );
+        bb.eliminate_common_subexpressions();
+        assert_eq!(calls(&bb), vec!["add", "add"]);
+        assert!(matches!(
+            &bb.statements[1],
+            Statement::ValueAssign { value: Value::RefName(Name::Ident(from)), .. } if from == "x"
+        ));
+    }
+}
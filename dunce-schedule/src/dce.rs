@@ -0,0 +1,153 @@
+use crate::cfg::{Function, Program};
+use crate::dialect;
+use crate::ssa_block::{Block, Name, Statement};
+use std::collections::{BTreeSet, HashSet};
+
+pub trait DeadCodeElimination {
+    fn eliminate_dead_code(&mut self);
+}
+
+fn is_removable(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::CallAssign { calls, .. } => dialect::is_removable(calls),
+        Statement::ValueAssign { .. } => true,
+    }
+}
+
+impl DeadCodeElimination for Block {
+    /// Removes side effect free statements whose results are never used. Walks the block
+    /// backwards so that statements only feeding dead statements are removed as well.
+    fn eliminate_dead_code(&mut self) {
+        let mut live: HashSet<Name> = self.end_stack.iter().map(|name| name.into()).collect();
+        let mut kept: Vec<Statement> = vec![];
+
+        for stmt in std::mem::take(&mut self.statements).into_iter().rev() {
+            let is_live = stmt.defs().iter().any(|name| live.contains(*name));
+            if !is_live && is_removable(&stmt) {
+                continue;
+            }
+            stmt.defs().into_iter().for_each(|name| {
+                live.remove(name);
+            });
+            live.extend(stmt.uses().into_iter().cloned());
+            kept.push(stmt);
+        }
+
+        kept.reverse();
+        self.statements = kept;
+    }
+}
+
+impl DeadCodeElimination for Function {
+    fn eliminate_dead_code(&mut self) {
+        let reachable = self.reachable();
+        self.retain_nodes(&reachable);
+        self.nodes
+            .iter_mut()
+            .for_each(|node| node.block.eliminate_dead_code());
+    }
+}
+
+impl DeadCodeElimination for Program {
+    /// Additionally drops function definitions not transitively called from `main`.
+    fn eliminate_dead_code(&mut self) {
+        self.main.eliminate_dead_code();
+        self.functions
+            .values_mut()
+            .for_each(|function| function.eliminate_dead_code());
+
+        let mut used: BTreeSet<String> = BTreeSet::new();
+        let mut todo: Vec<&str> = self.main.callees().collect();
+        while let Some(callee) = todo.pop() {
+            if let Some(function) = self.functions.get(callee) {
+                if used.insert(callee.to_owned()) {
+                    todo.extend(function.callees());
+                }
+            }
+        }
+        self.functions.retain(|name, _| used.contains(name));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cfg::{Node, Terminator};
+    use crate::stack_scheduler::test::{block, call, r};
+
+    #[test]
+    fn test_dce_transitive() {
+        let mut bb = block(
+            vec![
+                call(&["x"], "add", vec![r("a"), r("b")]),
+                call(&["y"], "mul", vec![r("x"), r("x")]),
+                call(&["z"], "sub", vec![r("a"), r("b")]),
+                call(&[], "sstore", vec![r("a"), r("y")]),
+            ],
+            &["b"],
+        );
+        bb.eliminate_dead_code();
+        assert_eq!(bb.statements.len(), 3);
+
+        let mut bb = block(
+            vec![
+                call(&["x"], "add", vec![r("a"), r("b")]),
+                call(&["y"], "mul", vec![r("x"), r("x")]),
+                call(&["z"], "sload", vec![r("y")]),
+            ],
+            &["a"],
+        );
+        bb.eliminate_dead_code();
+        assert!(bb.statements.is_empty());
+    }
+
+    #[test]
+    fn test_dce_keeps_effects_and_reassignments() {
+        let mut bb = block(
+            vec![
+                call(&["x"], "add", vec![r("a"), r("b")]),
+                call(&["x"], "mul", vec![r("x"), r("a")]),
+                call(&["r"], "call", vec![r("a"); 7]),
+                call(&["u"], "user_fn", vec![r("a")]),
+            ],
+            &["x"],
+        );
+        bb.eliminate_dead_code();
+        assert_eq!(bb.statements.len(), 4);
+    }
+
+    #[test]
+    fn test_dce_program() {
+        let node = |statements, exit| Node {
+            block: block(statements, &[]),
+            exit,
+        };
+        let main = Function {
+            nodes: vec![
+                node(vec![call(&[], "f", vec![])], Terminator::Jump(2)),
+                node(vec![call(&[], "g", vec![])], Terminator::Halt),
+                node(vec![], Terminator::Halt),
+            ],
+        };
+        let f = Function {
+            nodes: vec![node(vec![call(&[], "h", vec![])], Terminator::Leave)],
+        };
+        let leaf = Function {
+            nodes: vec![node(vec![], Terminator::Leave)],
+        };
+        let mut program = Program {
+            main,
+            functions: [
+                ("f".to_owned(), f),
+                ("g".to_owned(), leaf.clone()),
+                ("h".to_owned(), leaf),
+            ]
+            .into(),
+        };
+        program.eliminate_dead_code();
+
+        assert_eq!(program.main.nodes.len(), 2);
+        assert_eq!(program.main.nodes[0].exit, Terminator::Jump(1));
+        assert_eq!(program.functions.keys().collect::<Vec<_>>(), vec!["f", "h"]);
+    }
+}
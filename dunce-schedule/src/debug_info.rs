@@ -0,0 +1,218 @@
+use crate::scheduler::Op;
+use crate::ssa_block::{Block, Name, Statement, Value};
+use crate::verify::Symbol;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::iter::Peekable;
+
+/// Where a variable's value is, as seen by a debugger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Location {
+    /// Stack item `depth` items below the top, 0 being the top.
+    Stack { depth: usize },
+    /// Word of memory at `offset`.
+    Memory { offset: usize },
+    /// Transient storage at `key`.
+    Transient { key: usize },
+}
+
+/// Where a scheduled value is, before lowering slots to a spill backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Place {
+    Stack(usize),
+    Slot(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Variable {
+    pub name: String,
+    pub location: Location,
+}
+
+/// Variables visible from an instruction of the code.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InstructionInfo {
+    pub offset: usize,
+    /// Variables and where they are when the instruction is about to run, given on the first
+    /// instruction of every scheduled op only, as those in between may have extra items on the
+    /// stack.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Vec<Variable>>,
+}
+
+/// Code range of a function, `main` for the code outside of functions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Scope {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    /// Arguments on the stack when entering, the first on top.
+    pub parameters: Vec<String>,
+}
+
+/// Debug information of bytecode, to be emitted as JSON next to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DebugInfo {
+    pub instructions: Vec<InstructionInfo>,
+    pub scopes: Vec<Scope>,
+}
+
+impl DebugInfo {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Debug info is serializable")
+    }
+}
+
+/// Places of the Yul identifiers of `block` before each of `ops`, following the ops
+/// symbolically like `verify`. A name is only placed where its current value is, so a stack
+/// item left over from before a reassignment is not. Intermediate values are left out.
+pub fn variable_places(block: &Block, ops: &[Op]) -> Vec<Vec<(String, Place)>> {
+    let mut names: HashMap<Name, Symbol> = block
+        .start_stack
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.into(), Symbol::Start(i)))
+        .collect();
+    let mut stack: Vec<Symbol> = (0..block.start_stack.len()).map(Symbol::Start).collect();
+    let mut memory: HashMap<usize, Symbol> = HashMap::new();
+    let mut statements = block.statements.iter().peekable();
+    let mut calls = 0;
+    // Value assignments take effect right after the call before them.
+    let assign_values =
+        |names: &mut HashMap<Name, Symbol>,
+         statements: &mut Peekable<std::slice::Iter<Statement>>| {
+            while let Some(Statement::ValueAssign { to, value, .. }) = statements.peek() {
+                let symbol = match value {
+                    Value::RefName(name) => names[name],
+                    Value::Literal(lit) => Symbol::Literal(*lit),
+                };
+                names.insert(to.clone(), symbol);
+                statements.next();
+            }
+        };
+    assign_values(&mut names, &mut statements);
+
+    let mut places = vec![];
+    for op in ops {
+        let mut visible: Vec<(String, Place)> = names
+            .iter()
+            .filter_map(|(name, symbol)| match name {
+                Name::Ident(ident) => Some((ident, symbol)),
+                Name::Intermed(_) => None,
+            })
+            .flat_map(|(ident, symbol)| {
+                let on_stack = stack
+                    .iter()
+                    .rev()
+                    .enumerate()
+                    .filter(move |(_, item)| *item == symbol)
+                    .map(|(depth, _)| Place::Stack(depth));
+                let in_memory = memory
+                    .iter()
+                    .filter(move |(_, item)| *item == symbol)
+                    .map(|(slot, _)| Place::Slot(*slot));
+                on_stack
+                    .chain(in_memory)
+                    .map(move |place| (ident.clone(), place))
+            })
+            .collect();
+        visible.sort();
+        places.push(visible);
+
+        let top = stack.len().saturating_sub(1);
+        match op {
+            Op::Swap(n) => stack.swap(top, top - n),
+            Op::Dup(n) => stack.push(stack[stack.len() - n]),
+            Op::Pop => {
+                stack.pop();
+            }
+            Op::Push(lit) => stack.push(Symbol::Literal(*lit)),
+            Op::MemSwap(a, b) => {
+                let (va, vb) = (memory[a], memory[b]);
+                memory.insert(*a, vb);
+                memory.insert(*b, va);
+            }
+            Op::MemVarLoad(slot) => stack.push(memory[slot]),
+            Op::MemVarStore(slot) => {
+                memory.insert(*slot, stack.pop().expect("Stack underflow"));
+            }
+            Op::MemCopy { from, to } => {
+                memory.insert(*to, memory[from]);
+            }
+            Op::CallFn(name) => {
+                let Some(Statement::CallAssign { assigns, takes, .. }) = statements.next() else {
+                    panic!("Call of {} not matching the block's statements", name);
+                };
+                stack.truncate(stack.len() - takes.len());
+                for (j, name) in assigns.iter().enumerate() {
+                    stack.push(Symbol::Result(calls, j));
+                    names.insert(name.clone(), Symbol::Result(calls, j));
+                }
+                calls += 1;
+                assign_values(&mut names, &mut statements);
+            }
+        }
+    }
+    places
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stack_scheduler::test::{block, call, r};
+
+    #[test]
+    fn test_variable_places() {
+        // x := add(a, b), a := x, end stack [a].
+        let block = block(
+            vec![
+                call(&["x"], "add", vec![r("a"), r("b")]),
+                Statement::ValueAssign {
+                    to: "a".to_owned().into(),
+                    value: r("x"),
+                    span: None,
+                },
+            ],
+            &["a"],
+        );
+        let ops = [
+            Op::MemVarStore(0),
+            Op::Dup(1),
+            Op::MemVarLoad(0),
+            Op::Swap(1),
+            Op::CallFn("add"),
+            Op::Swap(1),
+            Op::Pop,
+        ];
+        let places = variable_places(&block, &ops);
+        let at = |places: &[(&str, Place)]| -> Vec<(String, Place)> {
+            places
+                .iter()
+                .map(|(name, place)| (name.to_string(), *place))
+                .collect()
+        };
+        assert_eq!(
+            places[0],
+            at(&[("a", Place::Stack(1)), ("b", Place::Stack(0))])
+        );
+        assert_eq!(
+            places[3],
+            at(&[
+                ("a", Place::Stack(1)),
+                ("a", Place::Stack(2)),
+                ("b", Place::Stack(0)),
+                ("b", Place::Slot(0)),
+            ])
+        );
+        // The old value of `a` below the result is no longer `a`.
+        assert_eq!(
+            places[5],
+            at(&[
+                ("a", Place::Stack(0)),
+                ("b", Place::Slot(0)),
+                ("x", Place::Stack(0)),
+            ])
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use std::ops::BitOr;
+
+/// Set of state locations a builtin may read from or write to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub struct Locations(u8);
+
+impl Locations {
+    pub const NONE: Self = Self(0);
+    pub const MEMORY: Self = Self(1 << 0);
+    pub const STORAGE: Self = Self(1 << 1);
+    pub const TRANSIENT: Self = Self(1 << 2);
+    pub const RETURN_DATA: Self = Self(1 << 3);
+    /// Balances, code and other accounts, anything an external call may change.
+    pub const WORLD: Self = Self(1 << 4);
+    /// Values that change with every executed instruction (`gas`, `msize`).
+    pub const EXECUTION: Self = Self(1 << 5);
+    pub const ALL: Self = Self(0b11_1111);
+    pub const COUNT: usize = 6;
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn intersects(&self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Indices of the contained locations, each below `Locations::COUNT`.
+    pub fn indices(&self) -> impl Iterator<Item = usize> {
+        let bits = self.0;
+        (0..Self::COUNT).filter(move |i| bits & (1 << i) != 0)
+    }
+}
+
+impl BitOr for Locations {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub opcode: u8,
+    pub takes: usize,
+    pub returns: usize,
+    pub reads: Locations,
+    pub writes: Locations,
+    /// Whether execution never continues past the builtin.
+    pub halts: bool,
+}
+
+impl Builtin {
+    /// Whether the builtin can be removed if its outputs are unused.
+    pub fn is_removable(&self) -> bool {
+        self.writes.is_empty() && !self.halts
+    }
+
+    /// Whether the builtin's result only depends on its arguments.
+    pub fn is_pure(&self) -> bool {
+        self.reads.is_empty() && self.is_removable()
+    }
+}
+
+/// Hardfork to generate code for. Later versions add builtins and opcodes allowing cheaper
+/// code, like `PUSH0`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub enum EvmVersion {
+    Homestead,
+    TangerineWhistle,
+    SpuriousDragon,
+    Byzantium,
+    Constantinople,
+    Petersburg,
+    Istanbul,
+    Berlin,
+    London,
+    Paris,
+    Shanghai,
+    #[default]
+    Cancun,
+    Prague,
+}
+
+/// Call of a builtin the targeted EVM version does not have yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unavailable {
+    pub builtin: String,
+    pub since: EvmVersion,
+    pub version: EvmVersion,
+}
+
+impl EvmVersion {
+    pub const ALL: [EvmVersion; 13] = [
+        EvmVersion::Homestead,
+        EvmVersion::TangerineWhistle,
+        EvmVersion::SpuriousDragon,
+        EvmVersion::Byzantium,
+        EvmVersion::Constantinople,
+        EvmVersion::Petersburg,
+        EvmVersion::Istanbul,
+        EvmVersion::Berlin,
+        EvmVersion::London,
+        EvmVersion::Paris,
+        EvmVersion::Shanghai,
+        EvmVersion::Cancun,
+        EvmVersion::Prague,
+    ];
+
+    /// Name of the version as solc's `evmVersion` setting spells it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EvmVersion::Homestead => "homestead",
+            EvmVersion::TangerineWhistle => "tangerineWhistle",
+            EvmVersion::SpuriousDragon => "spuriousDragon",
+            EvmVersion::Byzantium => "byzantium",
+            EvmVersion::Constantinople => "constantinople",
+            EvmVersion::Petersburg => "petersburg",
+            EvmVersion::Istanbul => "istanbul",
+            EvmVersion::Berlin => "berlin",
+            EvmVersion::London => "london",
+            EvmVersion::Paris => "paris",
+            EvmVersion::Shanghai => "shanghai",
+            EvmVersion::Cancun => "cancun",
+            EvmVersion::Prague => "prague",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|version| version.name() == name)
+    }
+
+    /// Version introducing the builtin `name`, `Homestead` for those always available and for
+    /// user defined functions.
+    pub fn introducing(name: &str) -> Self {
+        match name {
+            "returndatasize" | "returndatacopy" | "staticcall" | "revert" => EvmVersion::Byzantium,
+            "shl" | "shr" | "sar" | "extcodehash" | "create2" => EvmVersion::Constantinople,
+            "chainid" | "selfbalance" => EvmVersion::Istanbul,
+            "basefee" => EvmVersion::London,
+            "prevrandao" => EvmVersion::Paris,
+            "tload" | "tstore" | "mcopy" | "blobhash" | "blobbasefee" => EvmVersion::Cancun,
+            _ => EvmVersion::Homestead,
+        }
+    }
+
+    pub fn has_builtin(&self, name: &str) -> bool {
+        *self >= Self::introducing(name)
+    }
+
+    /// Fails for calls of builtins introduced after this version.
+    pub fn check(&self, name: &str) -> Result<(), Unavailable> {
+        match self.has_builtin(name) {
+            true => Ok(()),
+            false => Err(Unavailable {
+                builtin: name.to_owned(),
+                since: Self::introducing(name),
+                version: *self,
+            }),
+        }
+    }
+
+    pub fn has_push0(&self) -> bool {
+        *self >= EvmVersion::Shanghai
+    }
+}
+
+const N: Locations = Locations::NONE;
+const MEM: Locations = Locations::MEMORY;
+const STO: Locations = Locations::STORAGE;
+const TRA: Locations = Locations::TRANSIENT;
+const RET: Locations = Locations::RETURN_DATA;
+const WLD: Locations = Locations::WORLD;
+const EXE: Locations = Locations::EXECUTION;
+const ALL: Locations = Locations::ALL;
+
+const fn b(
+    name: &'static str,
+    opcode: u8,
+    takes: usize,
+    returns: usize,
+    reads: Locations,
+    writes: Locations,
+) -> Builtin {
+    Builtin {
+        name,
+        opcode,
+        takes,
+        returns,
+        reads,
+        writes,
+        halts: false,
+    }
+}
+
+const fn halt(name: &'static str, opcode: u8, takes: usize, reads: Locations) -> Builtin {
+    Builtin {
+        name,
+        opcode,
+        takes,
+        returns: 0,
+        reads,
+        writes: N,
+        halts: true,
+    }
+}
+
+pub const EVM_BUILTINS: &[Builtin] = &[
+    halt("stop", 0x00, 0, N),
+    b("add", 0x01, 2, 1, N, N),
+    b("mul", 0x02, 2, 1, N, N),
+    b("sub", 0x03, 2, 1, N, N),
+    b("div", 0x04, 2, 1, N, N),
+    b("sdiv", 0x05, 2, 1, N, N),
+    b("mod", 0x06, 2, 1, N, N),
+    b("smod", 0x07, 2, 1, N, N),
+    b("addmod", 0x08, 3, 1, N, N),
+    b("mulmod", 0x09, 3, 1, N, N),
+    b("exp", 0x0a, 2, 1, N, N),
+    b("signextend", 0x0b, 2, 1, N, N),
+    b("lt", 0x10, 2, 1, N, N),
+    b("gt", 0x11, 2, 1, N, N),
+    b("slt", 0x12, 2, 1, N, N),
+    b("sgt", 0x13, 2, 1, N, N),
+    b("eq", 0x14, 2, 1, N, N),
+    b("iszero", 0x15, 1, 1, N, N),
+    b("and", 0x16, 2, 1, N, N),
+    b("or", 0x17, 2, 1, N, N),
+    b("xor", 0x18, 2, 1, N, N),
+    b("not", 0x19, 1, 1, N, N),
+    b("byte", 0x1a, 2, 1, N, N),
+    b("shl", 0x1b, 2, 1, N, N),
+    b("shr", 0x1c, 2, 1, N, N),
+    b("sar", 0x1d, 2, 1, N, N),
+    b("keccak256", 0x20, 2, 1, MEM, N),
+    b("address", 0x30, 0, 1, N, N),
+    b("balance", 0x31, 1, 1, WLD, N),
+    b("origin", 0x32, 0, 1, N, N),
+    b("caller", 0x33, 0, 1, N, N),
+    b("callvalue", 0x34, 0, 1, N, N),
+    b("calldataload", 0x35, 1, 1, N, N),
+    b("calldatasize", 0x36, 0, 1, N, N),
+    b("calldatacopy", 0x37, 3, 0, N, MEM),
+    b("codesize", 0x38, 0, 1, N, N),
+    b("codecopy", 0x39, 3, 0, N, MEM),
+    b("gasprice", 0x3a, 0, 1, N, N),
+    b("extcodesize", 0x3b, 1, 1, WLD, N),
+    b("extcodecopy", 0x3c, 4, 0, WLD, MEM),
+    b("returndatasize", 0x3d, 0, 1, RET, N),
+    b("returndatacopy", 0x3e, 3, 0, RET, MEM),
+    b("extcodehash", 0x3f, 1, 1, WLD, N),
+    b("blockhash", 0x40, 1, 1, N, N),
+    b("coinbase", 0x41, 0, 1, N, N),
+    b("timestamp", 0x42, 0, 1, N, N),
+    b("number", 0x43, 0, 1, N, N),
+    b("prevrandao", 0x44, 0, 1, N, N),
+    b("difficulty", 0x44, 0, 1, N, N),
+    b("gaslimit", 0x45, 0, 1, N, N),
+    b("chainid", 0x46, 0, 1, N, N),
+    b("selfbalance", 0x47, 0, 1, WLD, N),
+    b("basefee", 0x48, 0, 1, N, N),
+    b("blobhash", 0x49, 1, 1, N, N),
+    b("blobbasefee", 0x4a, 0, 1, N, N),
+    b("pop", 0x50, 1, 0, N, N),
+    b("mload", 0x51, 1, 1, MEM, N),
+    b("mstore", 0x52, 2, 0, N, MEM),
+    b("mstore8", 0x53, 2, 0, N, MEM),
+    b("sload", 0x54, 1, 1, STO, N),
+    b("sstore", 0x55, 2, 0, N, STO),
+    b("msize", 0x59, 0, 1, EXE, N),
+    b("gas", 0x5a, 0, 1, EXE, N),
+    b("tload", 0x5c, 1, 1, TRA, N),
+    b("tstore", 0x5d, 2, 0, N, TRA),
+    b("mcopy", 0x5e, 3, 0, MEM, MEM),
+    b("log0", 0xa0, 2, 0, MEM, WLD),
+    b("log1", 0xa1, 3, 0, MEM, WLD),
+    b("log2", 0xa2, 4, 0, MEM, WLD),
+    b("log3", 0xa3, 5, 0, MEM, WLD),
+    b("log4", 0xa4, 6, 0, MEM, WLD),
+    b("create", 0xf0, 3, 1, ALL, ALL),
+    b("call", 0xf1, 7, 1, ALL, ALL),
+    b("callcode", 0xf2, 7, 1, ALL, ALL),
+    halt("return", 0xf3, 2, MEM),
+    b("delegatecall", 0xf4, 6, 1, ALL, ALL),
+    b("create2", 0xf5, 4, 1, ALL, ALL),
+    b("staticcall", 0xfa, 6, 1, ALL, MEM.union(RET)),
+    halt("revert", 0xfd, 2, MEM),
+    halt("invalid", 0xfe, 0, N),
+    halt("selfdestruct", 0xff, 1, N),
+];
+
+/// Looks up an EVM builtin by its Yul name, `None` for user defined functions.
+pub fn builtin(name: &str) -> Option<&'static Builtin> {
+    EVM_BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+/// Builtins taking a literal string, like `dataoffset("runtime")`. Calls of them are named
+/// after the builtin and its quoted literal and take the remaining arguments, so
+/// `setimmutable(offset, "x", value)` calls `setimmutable("x")` with `offset` and `value`.
+pub const LITERAL_BUILTINS: &[&str] = &[
+    "dataoffset",
+    "datasize",
+    "loadimmutable",
+    "linkersymbol",
+    "setimmutable",
+];
+
+/// Name of the call of the literal builtin `fn_name` with the literal `input`.
+pub fn literal_builtin_call(fn_name: &str, input: &str) -> String {
+    format!("{}(\"{}\")", fn_name, input)
+}
+
+/// Splits the name of a call of a literal builtin into the builtin and its literal.
+pub fn parse_literal_builtin(calls: &str) -> Option<(&str, &str)> {
+    let (fn_name, rest) = calls.split_once("(\"")?;
+    let input = rest.strip_suffix("\")")?;
+    LITERAL_BUILTINS
+        .contains(&fn_name)
+        .then_some((fn_name, input))
+}
+
+/// Whether the arguments of the builtin `name` may be swapped without changing its result.
+pub fn is_commutative(name: &str) -> bool {
+    matches!(name, "add" | "mul" | "and" | "or" | "xor" | "eq")
+}
+
+/// Whether a call to `name` may be removed if its outputs are unused. User defined functions
+/// are conservatively assumed to have side effects.
+pub fn is_removable(name: &str) -> bool {
+    builtin(name).is_some_and(Builtin::is_removable)
+        || parse_literal_builtin(name).is_some_and(|(fn_name, _)| fn_name != "setimmutable")
+}
@@ -0,0 +1,257 @@
+use crate::scheduler::Op;
+use crate::spill::SpillCost;
+use crate::ssa_block::{Block, Name};
+use crate::stack_scheduler::{schedule_onto, Stack};
+use std::collections::HashMap;
+
+/// Gas of `PUSH slot MLOAD`, paid for every use of a spilled value.
+pub const LOAD_GAS: usize = 6;
+/// Gas of `PUSH slot MSTORE`, paid for every definition of a spilled value.
+pub const STORE_GAS: usize = 6;
+/// Gas of the `DUP` a use of a value kept on the stack costs instead.
+pub const DUP_GAS: usize = 3;
+
+pub trait HybridScheduler {
+    /// Schedules the block on the operand stack, spilling values to `MemVar*` slots where
+    /// the stack would get too deep, choosing what to spill by the slots' `cost`. Returns the
+    /// number of slots used and the ops.
+    fn schedule_hybrid_with(&self, cost: &SpillCost) -> (usize, Vec<Op<'_>>);
+
+    /// Schedules with slots in memory.
+    fn schedule_hybrid(&self) -> (usize, Vec<Op<'_>>) {
+        self.schedule_hybrid_with(&SpillCost::MEMORY)
+    }
+}
+
+/// How long a name occupies a stack slot and how much spilling it would cost.
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    first: usize,
+    last: usize,
+    defs: usize,
+    uses: usize,
+}
+
+impl Usage {
+    fn spill_gas(&self, cost: &SpillCost) -> usize {
+        self.uses * cost.load.saturating_sub(DUP_GAS) + self.defs * cost.store
+    }
+
+    /// Statements spanned per unit of extra gas, higher for long-lived values used rarely.
+    fn score(&self, cost: &SpillCost) -> usize {
+        1000 * (self.last - self.first) / self.spill_gas(cost).max(1)
+    }
+}
+
+/// Positions count the start of the block as 0, the `i`th statement as `i + 1` and the end of
+/// the block as one past the last statement.
+fn usages(block: &Block) -> HashMap<Name, Usage> {
+    let mut usages: HashMap<Name, Usage> = HashMap::new();
+    let mut touch = |name: &Name, pos: usize, def: bool| {
+        let usage = usages.entry(name.clone()).or_insert(Usage {
+            first: pos,
+            ..Default::default()
+        });
+        usage.last = pos;
+        match def {
+            true => usage.defs += 1,
+            false => usage.uses += 1,
+        }
+    };
+    for name in block.start_stack.iter() {
+        touch(&name.into(), 0, true);
+    }
+    for (i, stmt) in block.statements.iter().enumerate() {
+        stmt.uses()
+            .into_iter()
+            .for_each(|name| touch(name, i + 1, false));
+        stmt.defs()
+            .into_iter()
+            .for_each(|name| touch(name, i + 1, true));
+    }
+    for name in block.end_stack.iter() {
+        touch(&name.into(), block.statements.len() + 1, false);
+    }
+    usages
+}
+
+/// Gas of the stack and memory traffic in `ops`, ignoring the calls themselves.
+pub fn traffic_gas(ops: &[Op]) -> usize {
+    SpillCost::MEMORY.traffic_gas(ops)
+}
+
+fn try_schedule<'a>(
+    block: &'a Block,
+    spills: &HashMap<Name, usize>,
+) -> Result<Vec<Op<'a>>, Stack<'a>> {
+    let start: Vec<Name> = block.start_stack.iter().map(|name| name.into()).collect();
+    let mut stack = Stack::new(&start);
+    stack.spills = spills.clone();
+    match schedule_onto(block, &mut stack) {
+        Ok(()) => Ok(stack.ops),
+        Err(_) => Err(stack),
+    }
+}
+
+/// Picks the name to spill next among the names on the stack where scheduling failed. A
+/// spill that makes the whole block schedulable wins, the cheapest one if there are several.
+/// Otherwise the long-lived, rarely used names go first.
+fn spill_candidate(
+    block: &Block,
+    cost: &SpillCost,
+    usages: &HashMap<Name, Usage>,
+    spills: &HashMap<Name, usize>,
+    on_stack: &[Option<Name>],
+) -> Option<Name> {
+    let mut candidates: Vec<&Name> = on_stack
+        .iter()
+        .flatten()
+        .filter(|name| !spills.contains_key(*name))
+        .filter(|name| usages.get(*name).is_some_and(|usage| usage.uses > 0))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+
+    let resolving = candidates.iter().filter_map(|name| {
+        let mut spills = spills.clone();
+        spills.insert((*name).clone(), spills.len());
+        try_schedule(block, &spills)
+            .ok()
+            .map(|ops| (cost.traffic_gas(&ops), *name))
+    });
+    if let Some((_, name)) = resolving.min() {
+        return Some(name.clone());
+    }
+    candidates
+        .into_iter()
+        .max_by_key(|name| (usages[*name].score(cost), std::cmp::Reverse(*name)))
+        .or_else(|| {
+            usages
+                .iter()
+                .filter(|(name, usage)| !spills.contains_key(*name) && usage.uses > 0)
+                .max_by_key(|(name, usage)| (usage.score(cost), std::cmp::Reverse(*name)))
+                .map(|(name, _)| name)
+        })
+        .cloned()
+}
+
+impl HybridScheduler for Block {
+    /// Starts from a stack-only schedule. Whenever a value sits out of reach, one more name
+    /// live at that point is spilled and the block is scheduled again. Once every used name is
+    /// spilled the stack only holds operands in flight, so compilation always succeeds.
+    fn schedule_hybrid_with(&self, cost: &SpillCost) -> (usize, Vec<Op<'_>>) {
+        let usages = usages(self);
+        let mut spills: HashMap<Name, usize> = HashMap::new();
+        loop {
+            let stack = match try_schedule(self, &spills) {
+                Ok(ops) => return (spills.len(), ops),
+                Err(stack) => stack,
+            };
+            let name =
+                spill_candidate(self, cost, &usages, &spills, &stack.slots).unwrap_or_else(|| {
+                    panic!("Stack too deep with every value spilled: {:?}", stack.slots)
+                });
+            spills.insert(name, spills.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stack_scheduler::test::{
+        assert_schedules_equivalently, call, interpret, r, random_blocks, run,
+    };
+    use crate::stack_scheduler::StackScheduler;
+
+    fn check(block: &Block) -> (usize, Vec<Op<'_>>) {
+        let start: Vec<u64> = (0..block.start_stack.len() as u64)
+            .map(|i| 1000 + i)
+            .collect();
+        let (slots, ops) = block.schedule_hybrid();
+        assert_eq!(
+            run(block, &ops, &start),
+            interpret(block, &start),
+            "{:?} scheduled as {:?}",
+            block,
+            ops
+        );
+        (slots, ops)
+    }
+
+    #[test]
+    fn test_schedule_hybrid_random() {
+        for (seed, block) in random_blocks(500) {
+            let (slots, ops) = block.schedule_hybrid();
+            assert_schedules_equivalently(seed, &block, &ops);
+            assert_eq!(slots, 0);
+            assert_eq!(Ok(ops), block.schedule_stack());
+        }
+    }
+
+    #[test]
+    fn test_schedule_hybrid_too_deep() {
+        let names: Vec<String> = (0..18).map(|i| format!("v{}", i)).collect();
+        let block = Block {
+            start_stack: names.clone(),
+            statements: vec![],
+            end_stack: names.iter().rev().cloned().collect(),
+        };
+        let (slots, ops) = check(&block);
+        assert!(slots > 0);
+        assert!(ops.iter().any(|op| matches!(op, Op::MemVarLoad(_))));
+    }
+
+    #[test]
+    fn test_schedule_hybrid_single_spill() {
+        // `a` is read by every sum while 15 values and `cold` sit above it. Spilling `a`
+        // alone is enough, spilling the values above it one by one is not.
+        let mut statements = vec![call(&["cold"], "gas", vec![])];
+        let hot: Vec<String> = (0..15).map(|i| format!("h{}", i)).collect();
+        for h in hot.iter() {
+            statements.push(call(&[h], "mload", vec![r("a")]));
+        }
+        for (i, h) in hot.iter().enumerate() {
+            statements.push(call(&[&format!("s{}", i)], "add", vec![r(h), r("a")]));
+        }
+        let mut end_stack: Vec<String> = (0..15).map(|i| format!("s{}", i)).collect();
+        end_stack.push("cold".to_owned());
+        let block = Block {
+            start_stack: vec!["a".to_owned()],
+            statements,
+            end_stack,
+        };
+        assert!(block.schedule_stack().is_err());
+
+        let (slots, ops) = check(&block);
+        assert_eq!(slots, 1);
+        assert_eq!(
+            ops.iter()
+                .filter(|op| matches!(op, Op::MemVarLoad(_)))
+                .count(),
+            30
+        );
+    }
+
+    #[test]
+    fn test_schedule_hybrid_wide() {
+        let vs: Vec<String> = (0..24).map(|i| format!("v{}", i)).collect();
+        let statements = (0..24)
+            .map(|i| {
+                call(
+                    &[&format!("x{}", i)],
+                    "add",
+                    vec![r(&vs[i]), r(&vs[23 - i])],
+                )
+            })
+            .collect();
+        let mut end_stack: Vec<String> = (0..24).map(|i| format!("x{}", i)).collect();
+        end_stack.extend(vs.iter().step_by(3).cloned());
+        let block = Block {
+            start_stack: vs.clone(),
+            statements,
+            end_stack,
+        };
+        check(&block);
+    }
+}
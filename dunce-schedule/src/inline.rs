@@ -0,0 +1,831 @@
+use crate::dialect;
+use ir::{Block, Expr, FunctionDefinition, Literal, Span, Statement};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Estimated bytes of a call: pushing the return and entry label, the jump and both jumpdests.
+const CALL_SIZE: usize = 8;
+/// Estimated gas of a call and return, excluding argument shuffling.
+const CALL_GAS: usize = 24;
+/// Gas paid at deployment per byte of code.
+const DEPOSIT_GAS_PER_BYTE: usize = 200;
+/// Bytes of the loop turning `leave` into a jump to the continuation.
+const LEAVE_LOOP_SIZE: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct CostModel {
+    /// Expected executions of each call site over the contract's lifetime, like solc's `runs`.
+    pub runs: usize,
+    /// Functions with larger bodies are only inlined if called exactly once.
+    pub max_size: usize,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            runs: 200,
+            max_size: 64,
+        }
+    }
+}
+
+pub trait Inlining {
+    fn inline_functions(&mut self, model: &CostModel);
+}
+
+fn literal_size(lit: &Literal) -> usize {
+    1 + lit.iter().skip_while(|byte| **byte == 0).count()
+}
+
+fn expr_size(expr: &Expr) -> usize {
+    match expr {
+        Expr::VarRef(_) => 1,
+        Expr::Literal(lit) => literal_size(lit),
+        Expr::Call { fn_name, args, .. } => {
+            let call = match dialect::builtin(fn_name) {
+                Some(_) => 1,
+                None => CALL_SIZE,
+            };
+            call + args.iter().map(expr_size).sum::<usize>()
+        }
+        Expr::Builtin { .. } => 3,
+    }
+}
+
+/// Rough estimate of the bytecode size of a block.
+fn block_size(block: &Block) -> usize {
+    block
+        .0
+        .iter()
+        .map(|stmt| match stmt {
+            Statement::Block(block) => block_size(block),
+            Statement::FnDef(_) => 0,
+            Statement::Assignment { to, expr, .. } => expr_size(expr) + to.len(),
+            Statement::If { cond, body, .. } => expr_size(cond) + 5 + block_size(body),
+            Statement::Switch {
+                cond,
+                cases,
+                default,
+                ..
+            } => {
+                let cases: usize = cases
+                    .iter()
+                    .map(|(lit, body)| literal_size(lit) + 6 + block_size(body))
+                    .sum();
+                expr_size(cond) + cases + default.as_ref().map_or(0, block_size)
+            }
+            Statement::ForLoop {
+                setup,
+                cond,
+                on_iter,
+                body,
+                ..
+            } => block_size(setup) + expr_size(cond) + block_size(on_iter) + block_size(body) + 10,
+            Statement::Leave | Statement::Break | Statement::Continue => 4,
+        })
+        .sum()
+}
+
+fn called_functions(expr: &Expr, calls: &mut Vec<String>) {
+    if let Expr::Call { fn_name, args, .. } = expr {
+        args.iter().for_each(|arg| called_functions(arg, calls));
+        calls.push(fn_name.clone());
+    }
+}
+
+/// Visits all calls in `block` without descending into function definitions. `inlinable` is
+/// false for calls in for loop conditions, which are evaluated on every iteration.
+fn visit_calls(block: &Block, visit: &mut impl FnMut(&str, bool)) {
+    let visit_expr = |expr: &Expr, inlinable: bool, visit: &mut dyn FnMut(&str, bool)| {
+        let mut calls = vec![];
+        called_functions(expr, &mut calls);
+        calls.iter().for_each(|call| visit(call, inlinable));
+    };
+    for stmt in block.0.iter() {
+        match stmt {
+            Statement::Block(block) => visit_calls(block, visit),
+            Statement::FnDef(_) => (),
+            Statement::Assignment { expr, .. } => visit_expr(expr, true, visit),
+            Statement::If { cond, body, .. } => {
+                visit_expr(cond, true, visit);
+                visit_calls(body, visit);
+            }
+            Statement::Switch {
+                cond,
+                cases,
+                default,
+                ..
+            } => {
+                visit_expr(cond, true, visit);
+                cases.iter().for_each(|(_, body)| visit_calls(body, visit));
+                default.iter().for_each(|body| visit_calls(body, visit));
+            }
+            Statement::ForLoop {
+                setup,
+                cond,
+                on_iter,
+                body,
+                ..
+            } => {
+                visit_calls(setup, visit);
+                visit_expr(cond, false, visit);
+                visit_calls(on_iter, visit);
+                visit_calls(body, visit);
+            }
+            Statement::Leave | Statement::Break | Statement::Continue => (),
+        }
+    }
+}
+
+fn collect_functions(block: &Block, functions: &mut BTreeMap<String, FunctionDefinition>) {
+    for stmt in block.0.iter() {
+        match stmt {
+            Statement::Block(block) => collect_functions(block, functions),
+            Statement::FnDef(f) => {
+                functions.insert(f.name.clone(), f.clone());
+                collect_functions(&f.body, functions);
+            }
+            Statement::If { body, .. } => collect_functions(body, functions),
+            Statement::Switch { cases, default, .. } => {
+                cases
+                    .iter()
+                    .for_each(|(_, body)| collect_functions(body, functions));
+                default
+                    .iter()
+                    .for_each(|body| collect_functions(body, functions));
+            }
+            Statement::ForLoop {
+                setup,
+                on_iter,
+                body,
+                ..
+            } => {
+                collect_functions(setup, functions);
+                collect_functions(on_iter, functions);
+                collect_functions(body, functions);
+            }
+            Statement::Assignment { .. }
+            | Statement::Leave
+            | Statement::Break
+            | Statement::Continue => (),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Leaves {
+    Never,
+    Directly,
+    /// `leave` inside a for loop of the body, where it can't be turned into a `break`.
+    InLoop,
+}
+
+/// How `leave` is used in `block`, `None` if the block defines functions.
+fn leaves(block: &Block, in_loop: bool) -> Option<Leaves> {
+    let mut found = Leaves::Never;
+    for stmt in block.0.iter() {
+        let inner = match stmt {
+            Statement::Leave if in_loop => Leaves::InLoop,
+            Statement::Leave => Leaves::Directly,
+            Statement::FnDef(_) => return None,
+            Statement::Block(block) | Statement::If { body: block, .. } => leaves(block, in_loop)?,
+            Statement::Switch { cases, default, .. } => {
+                let mut inner = Leaves::Never;
+                for body in cases.iter().map(|(_, body)| body).chain(default.iter()) {
+                    inner = inner.max(leaves(body, in_loop)?);
+                }
+                inner
+            }
+            Statement::ForLoop {
+                setup,
+                on_iter,
+                body,
+                ..
+            } => leaves(setup, in_loop)?
+                .max(leaves(on_iter, true)?)
+                .max(leaves(body, true)?),
+            Statement::Assignment { .. } | Statement::Break | Statement::Continue => Leaves::Never,
+        };
+        found = found.max(inner);
+    }
+    Some(found)
+}
+
+fn rename_expr(expr: &mut Expr, rename: &impl Fn(&str) -> String) {
+    match expr {
+        Expr::VarRef(name) => *name = rename(name),
+        Expr::Call { args, .. } => args.iter_mut().for_each(|arg| rename_expr(arg, rename)),
+        Expr::Literal(_) | Expr::Builtin { .. } => (),
+    }
+}
+
+/// Renames all variables of a function body, which can't refer to anything outside of it. Also
+/// replaces `leave` with `break` if `leave_to_break` is set.
+fn rename_block(block: &mut Block, rename: &impl Fn(&str) -> String, leave_to_break: bool) {
+    for stmt in block.0.iter_mut() {
+        match stmt {
+            Statement::Block(block) => rename_block(block, rename, leave_to_break),
+            Statement::FnDef(_) => panic!("Renaming function with nested definitions"),
+            Statement::Assignment { to, expr, .. } => {
+                to.iter_mut().for_each(|name| *name = rename(name));
+                rename_expr(expr, rename);
+            }
+            Statement::If { cond, body, .. } => {
+                rename_expr(cond, rename);
+                rename_block(body, rename, leave_to_break);
+            }
+            Statement::Switch {
+                cond,
+                cases,
+                default,
+                ..
+            } => {
+                rename_expr(cond, rename);
+                cases
+                    .iter_mut()
+                    .for_each(|(_, body)| rename_block(body, rename, leave_to_break));
+                default
+                    .iter_mut()
+                    .for_each(|body| rename_block(body, rename, leave_to_break));
+            }
+            Statement::ForLoop {
+                setup,
+                cond,
+                on_iter,
+                body,
+                ..
+            } => {
+                rename_block(setup, rename, leave_to_break);
+                rename_expr(cond, rename);
+                rename_block(on_iter, rename, leave_to_break);
+                rename_block(body, rename, leave_to_break);
+            }
+            Statement::Leave if leave_to_break => *stmt = Statement::Break,
+            Statement::Leave | Statement::Break | Statement::Continue => (),
+        }
+    }
+}
+
+struct Inliner {
+    next_id: usize,
+    /// Function definitions with their callees already inlined.
+    functions: BTreeMap<String, FunctionDefinition>,
+    inlined: BTreeSet<String>,
+}
+
+impl Inliner {
+    fn get_next_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn contains_inlined(&self, expr: &Expr) -> bool {
+        let mut calls = vec![];
+        called_functions(expr, &mut calls);
+        calls.iter().any(|call| self.inlined.contains(call))
+    }
+
+    /// Hoists the call arguments of `expr` into temporaries in evaluation order (right to left),
+    /// each assigned at the span of its call.
+    fn split(&mut self, expr: Expr, out: &mut Vec<Statement>) -> Expr {
+        match expr {
+            Expr::Call {
+                fn_name,
+                args,
+                span,
+            } => {
+                let mut args: Vec<Expr> = args
+                    .into_iter()
+                    .rev()
+                    .map(|arg| match arg {
+                        Expr::Call { span, .. } => {
+                            let call = self.split(arg, out);
+                            let tmp = format!("__inl_tmp__{}__", self.get_next_id());
+                            self.inline_statement(
+                                Statement::Assignment {
+                                    to: vec![tmp.clone()],
+                                    expr: call,
+                                    span,
+                                },
+                                out,
+                            );
+                            Expr::VarRef(tmp)
+                        }
+                        arg => arg,
+                    })
+                    .collect();
+                args.reverse();
+                Expr::Call {
+                    fn_name,
+                    args,
+                    span,
+                }
+            }
+            expr => expr,
+        }
+    }
+
+    fn split_cond(&mut self, cond: Expr, span: Option<Span>, out: &mut Vec<Statement>) -> Expr {
+        if !self.contains_inlined(&cond) {
+            return cond;
+        }
+        let span = cond.span().or(span);
+        let cond = self.split(cond, out);
+        let tmp = format!("__inl_tmp__{}__", self.get_next_id());
+        self.inline_statement(
+            Statement::Assignment {
+                to: vec![tmp.clone()],
+                expr: cond,
+                span,
+            },
+            out,
+        );
+        Expr::VarRef(tmp)
+    }
+
+    /// Replaces `to := f(args)` with the renamed body of `f`, wrapped in a single iteration loop
+    /// if the body uses `leave`. The body keeps its spans, the statements passing arguments and
+    /// return values get the `span` of the call.
+    fn expand(
+        &mut self,
+        to: Vec<String>,
+        fn_name: &str,
+        args: Vec<Expr>,
+        span: Option<Span>,
+        out: &mut Vec<Statement>,
+    ) {
+        let f = self.functions[fn_name].clone();
+        let id = self.get_next_id();
+        let rename = |name: &str| format!("__inl__{}__{}__", id, name);
+        let has_leave = leaves(&f.body, false) == Some(Leaves::Directly);
+        let mut body = f.body;
+        rename_block(&mut body, &rename, has_leave);
+
+        let mut stmts = vec![];
+        for (param, arg) in f.args.iter().zip(args).rev() {
+            self.inline_statement(
+                Statement::Assignment {
+                    to: vec![rename(param)],
+                    expr: arg,
+                    span,
+                },
+                &mut stmts,
+            );
+        }
+        for ret in f.rets.iter() {
+            stmts.push(Statement::Assignment {
+                to: vec![rename(ret)],
+                expr: Expr::Literal([0u8; 32]),
+                span,
+            });
+        }
+        if has_leave {
+            let mut one = [0u8; 32];
+            one[31] = 1;
+            body.0.push(Statement::Break);
+            stmts.push(Statement::ForLoop {
+                setup: Block(vec![]),
+                cond: Expr::Literal(one),
+                on_iter: Block(vec![]),
+                body,
+                span,
+            });
+        } else {
+            stmts.extend(body.0);
+        }
+        for (to, ret) in to.into_iter().zip(f.rets.iter()) {
+            stmts.push(Statement::Assignment {
+                to: vec![to],
+                expr: Expr::VarRef(rename(ret)),
+                span,
+            });
+        }
+        out.push(Statement::Block(Block(stmts)));
+    }
+
+    fn inline_statement(&mut self, stmt: Statement, out: &mut Vec<Statement>) {
+        match stmt {
+            Statement::Block(mut block) => {
+                self.inline_block(&mut block);
+                out.push(Statement::Block(block));
+            }
+            Statement::Assignment {
+                to,
+                expr:
+                    Expr::Call {
+                        fn_name,
+                        args,
+                        span: call,
+                    },
+                span,
+            } if self.inlined.contains(&fn_name) => {
+                self.expand(to, &fn_name, args, call.or(span), out)
+            }
+            Statement::Assignment { to, expr, span } => {
+                let expr = match self.contains_inlined(&expr) {
+                    true => self.split(expr, out),
+                    false => expr,
+                };
+                out.push(Statement::Assignment { to, expr, span });
+            }
+            Statement::If {
+                cond,
+                mut body,
+                span,
+            } => {
+                let cond = self.split_cond(cond, span, out);
+                self.inline_block(&mut body);
+                out.push(Statement::If { cond, body, span });
+            }
+            Statement::Switch {
+                cond,
+                mut cases,
+                mut default,
+                span,
+            } => {
+                let cond = self.split_cond(cond, span, out);
+                cases
+                    .iter_mut()
+                    .for_each(|(_, body)| self.inline_block(body));
+                default.iter_mut().for_each(|body| self.inline_block(body));
+                out.push(Statement::Switch {
+                    cond,
+                    cases,
+                    default,
+                    span,
+                });
+            }
+            Statement::ForLoop {
+                mut setup,
+                cond,
+                mut on_iter,
+                mut body,
+                span,
+            } => {
+                self.inline_block(&mut setup);
+                self.inline_block(&mut on_iter);
+                self.inline_block(&mut body);
+                out.push(Statement::ForLoop {
+                    setup,
+                    cond,
+                    on_iter,
+                    body,
+                    span,
+                });
+            }
+            stmt @ (Statement::FnDef(_)
+            | Statement::Leave
+            | Statement::Break
+            | Statement::Continue) => out.push(stmt),
+        }
+    }
+
+    fn inline_block(&mut self, block: &mut Block) {
+        let mut out = vec![];
+        for stmt in std::mem::take(&mut block.0) {
+            self.inline_statement(stmt, &mut out);
+        }
+        block.0 = out;
+    }
+
+    /// Replaces function bodies with their processed versions and drops functions inlined at
+    /// every call site.
+    fn finish_block(&self, block: &mut Block, removed: &BTreeSet<String>) {
+        block.0.retain(|stmt| match stmt {
+            Statement::FnDef(f) => !removed.contains(&f.name),
+            _ => true,
+        });
+        for stmt in block.0.iter_mut() {
+            match stmt {
+                Statement::FnDef(f) => {
+                    f.body = self.functions[&f.name].body.clone();
+                    self.finish_block(&mut f.body, removed);
+                }
+                Statement::Block(block) | Statement::If { body: block, .. } => {
+                    self.finish_block(block, removed)
+                }
+                Statement::Switch { cases, default, .. } => {
+                    cases
+                        .iter_mut()
+                        .for_each(|(_, body)| self.finish_block(body, removed));
+                    default
+                        .iter_mut()
+                        .for_each(|body| self.finish_block(body, removed));
+                }
+                Statement::ForLoop {
+                    setup,
+                    on_iter,
+                    body,
+                    ..
+                } => {
+                    self.finish_block(setup, removed);
+                    self.finish_block(on_iter, removed);
+                    self.finish_block(body, removed);
+                }
+                Statement::Assignment { .. }
+                | Statement::Leave
+                | Statement::Break
+                | Statement::Continue => (),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct CallSites {
+    total: usize,
+    inlinable: usize,
+}
+
+fn should_inline(f: &FunctionDefinition, sites: CallSites, model: &CostModel) -> bool {
+    let Some(leaves) = leaves(&f.body, false) else {
+        return false;
+    };
+    if leaves == Leaves::InLoop || sites.inlinable == 0 {
+        return false;
+    }
+    let body_size = block_size(&f.body);
+    let removes_function = sites.inlinable == sites.total;
+    if removes_function && sites.total == 1 {
+        return true;
+    }
+    if body_size > model.max_size {
+        return false;
+    }
+
+    let wrapper = if leaves == Leaves::Directly {
+        LEAVE_LOOP_SIZE
+    } else {
+        0
+    };
+    let copy_size = body_size + wrapper + f.args.len() + f.rets.len() * 2;
+    let added = sites.inlinable * copy_size;
+    let mut removed = sites.inlinable * CALL_SIZE;
+    if removes_function {
+        removed += body_size + f.args.len() + f.rets.len();
+    }
+    let gas_saved = sites.inlinable * CALL_GAS * model.runs;
+    added <= removed || (added - removed) * DEPOSIT_GAS_PER_BYTE <= gas_saved
+}
+
+/// Functions calling themselves directly or indirectly.
+fn recursive_functions(callees: &BTreeMap<String, BTreeSet<String>>) -> BTreeSet<String> {
+    callees
+        .keys()
+        .filter(|start| {
+            let mut seen: BTreeSet<&String> = BTreeSet::new();
+            let mut todo: Vec<&String> = callees[*start].iter().collect();
+            while let Some(f) = todo.pop() {
+                if f == *start {
+                    return true;
+                }
+                if seen.insert(f) {
+                    todo.extend(callees.get(f).into_iter().flatten());
+                }
+            }
+            false
+        })
+        .cloned()
+        .collect()
+}
+
+fn postorder<'a>(
+    f: &'a String,
+    callees: &'a BTreeMap<String, BTreeSet<String>>,
+    seen: &mut BTreeSet<&'a String>,
+    order: &mut Vec<&'a String>,
+) {
+    if !seen.insert(f) {
+        return;
+    }
+    for callee in callees[f].iter() {
+        postorder(callee, callees, seen, order);
+    }
+    order.push(f);
+}
+
+impl Inlining for Block {
+    /// Inlines functions called once, or small enough that the gas saved over `model.runs`
+    /// executions outweighs the added deployment cost. Works bottom up through the call graph
+    /// so that already inlined callees count towards a function's size.
+    fn inline_functions(&mut self, model: &CostModel) {
+        let mut functions = BTreeMap::new();
+        collect_functions(self, &mut functions);
+
+        let mut sites: BTreeMap<String, CallSites> = BTreeMap::new();
+        let mut count = |call: &str, inlinable: bool| {
+            if functions.contains_key(call) {
+                let sites = sites.entry(call.to_owned()).or_default();
+                sites.total += 1;
+                sites.inlinable += inlinable as usize;
+            }
+        };
+        visit_calls(self, &mut count);
+        functions
+            .values()
+            .for_each(|f| visit_calls(&f.body, &mut count));
+
+        let callees: BTreeMap<String, BTreeSet<String>> = functions
+            .iter()
+            .map(|(name, f)| {
+                let mut calls = BTreeSet::new();
+                visit_calls(&f.body, &mut |call, _| {
+                    if functions.contains_key(call) {
+                        calls.insert(call.to_owned());
+                    }
+                });
+                (name.clone(), calls)
+            })
+            .collect();
+        let recursive = recursive_functions(&callees);
+
+        let mut seen = BTreeSet::new();
+        let mut order = vec![];
+        callees
+            .keys()
+            .for_each(|f| postorder(f, &callees, &mut seen, &mut order));
+
+        let mut inliner = Inliner {
+            next_id: 0,
+            functions: functions.clone(),
+            inlined: BTreeSet::new(),
+        };
+        for name in order {
+            let mut body = inliner.functions[name].body.clone();
+            inliner.inline_block(&mut body);
+            let f = inliner.functions.get_mut(name).unwrap();
+            f.body = body;
+            let sites = sites.get(name).copied().unwrap_or_default();
+            if !recursive.contains(name) && should_inline(f, sites, model) {
+                inliner.inlined.insert(name.clone());
+            }
+        }
+
+        inliner.inline_block(self);
+        let removed: BTreeSet<String> = inliner
+            .inlined
+            .iter()
+            .filter(|name| {
+                let sites = sites[*name];
+                sites.inlinable == sites.total
+            })
+            .cloned()
+            .collect();
+        inliner.finish_block(self, &removed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn r(name: &str) -> Expr {
+        Expr::VarRef(name.to_owned())
+    }
+
+    fn call(fn_name: &str, args: Vec<Expr>) -> Expr {
+        Expr::Call {
+            fn_name: fn_name.to_owned(),
+            args,
+            span: None,
+        }
+    }
+
+    fn assign(to: &[&str], expr: Expr) -> Statement {
+        Statement::Assignment {
+            to: to.iter().map(|s| s.to_string()).collect(),
+            expr,
+            span: None,
+        }
+    }
+
+    fn fn_def(name: &str, args: &[&str], rets: &[&str], body: Vec<Statement>) -> Statement {
+        Statement::FnDef(FunctionDefinition {
+            name: name.to_owned(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            rets: rets.iter().map(|s| s.to_string()).collect(),
+            body: Block(body),
+        })
+    }
+
+    fn remaining_calls(block: &Block) -> Vec<String> {
+        let mut calls = vec![];
+        visit_calls(block, &mut |call, _| calls.push(call.to_owned()));
+        calls
+    }
+
+    fn defined_functions(block: &Block) -> Vec<String> {
+        let mut functions = BTreeMap::new();
+        collect_functions(block, &mut functions);
+        functions.into_keys().collect()
+    }
+
+    #[test]
+    fn test_inline_single_call_site() {
+        let big_body: Vec<Statement> = (0..40)
+            .map(|_| assign(&["r"], call("add", vec![r("r"), r("x")])))
+            .collect();
+        let mut block = Block(vec![
+            fn_def("f", &["x"], &["r"], big_body),
+            assign(&["a"], call("f", vec![call("calldataload", vec![r("p")])])),
+        ]);
+        block.inline_functions(&CostModel::default());
+
+        assert!(defined_functions(&block).is_empty());
+        assert_eq!(
+            remaining_calls(&block),
+            vec!["calldataload".to_owned()]
+                .into_iter()
+                .chain((0..40).map(|_| "add".to_owned()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_inline_cost_model() {
+        let small = fn_def(
+            "small",
+            &["x"],
+            &["r"],
+            vec![assign(&["r"], call("add", vec![r("x"), r("x")]))],
+        );
+        let big_body: Vec<Statement> = (0..40)
+            .map(|_| assign(&["r"], call("mul", vec![r("r"), r("x")])))
+            .collect();
+        let big = fn_def("big", &["x"], &["r"], big_body);
+        let calls: Vec<Statement> = (0..3)
+            .flat_map(|_| {
+                [
+                    assign(
+                        &["a"],
+                        call("sstore", vec![call("small", vec![r("a")]), r("a")]),
+                    ),
+                    assign(&["b"], call("big", vec![r("b")])),
+                ]
+            })
+            .collect();
+        let mut block = Block([vec![small, big], calls].concat());
+        block.inline_functions(&CostModel::default());
+
+        assert_eq!(defined_functions(&block), vec!["big".to_owned()]);
+        let calls = remaining_calls(&block);
+        assert_eq!(calls.iter().filter(|c| *c == "big").count(), 3);
+        assert!(!calls.contains(&"small".to_owned()));
+    }
+
+    #[test]
+    fn test_inline_leave_and_hygiene() {
+        let f = fn_def(
+            "f",
+            &["x"],
+            &["r"],
+            vec![
+                assign(&["a"], r("x")),
+                Statement::If {
+                    cond: r("a"),
+                    body: Block(vec![Statement::Leave]),
+                    span: None,
+                },
+                assign(&["r"], r("a")),
+            ],
+        );
+        let mut block = Block(vec![f, assign(&["a"], call("f", vec![r("a")]))]);
+        block.inline_functions(&CostModel::default());
+
+        let [Statement::Block(inlined)] = block.0.as_slice() else {
+            panic!("Expected single inlined block, got {:?}", block);
+        };
+        let stmts = inlined.0.as_slice();
+        assert_eq!(stmts.len(), 4);
+        assert!(matches!(
+            &stmts[0],
+            Statement::Assignment { to, expr: Expr::VarRef(arg), .. } if *to == ["__inl__0__x__"] && arg == "a"
+        ));
+        assert!(matches!(&stmts[1], Statement::Assignment { to, .. } if *to == ["__inl__0__r__"]));
+        assert!(matches!(
+            &stmts[3],
+            Statement::Assignment { to, expr: Expr::VarRef(ret), .. } if *to == ["a"] && ret == "__inl__0__r__"
+        ));
+        let Statement::ForLoop { body, .. } = &stmts[2] else {
+            panic!("Expected loop around body with leave, got {:?}", stmts[2]);
+        };
+        assert!(
+            matches!(body.0[1], Statement::If { ref body, .. } if matches!(body.0[0], Statement::Break))
+        );
+        assert!(matches!(body.0.last(), Some(Statement::Break)));
+    }
+
+    #[test]
+    fn test_no_inline_recursive() {
+        let f = fn_def(
+            "f",
+            &["x"],
+            &["r"],
+            vec![assign(&["r"], call("f", vec![r("x")]))],
+        );
+        let mut block = Block(vec![f, assign(&["a"], call("f", vec![r("a")]))]);
+        block.inline_functions(&CostModel::default());
+        assert_eq!(defined_functions(&block), vec!["f".to_owned()]);
+        assert_eq!(remaining_calls(&block), vec!["f".to_owned()]);
+    }
+}
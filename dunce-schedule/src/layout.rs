@@ -0,0 +1,382 @@
+use crate::cfg::{BlockId, Function, Program, Terminator};
+use crate::hybrid_scheduler::{traffic_gas, HybridScheduler};
+use crate::ssa_block::{Block, Name};
+use std::collections::BTreeSet;
+
+/// Factor by which the stack traffic of a block weighs more for every loop it is part of.
+pub const LOOP_WEIGHT: usize = 10;
+/// Entry layouts of at most this many names are chosen among all their orders.
+pub const MAX_PERMUTED_LAYOUT: usize = 4;
+
+pub trait LayoutNegotiation {
+    fn negotiate_layouts(&mut self);
+}
+
+fn ident(name: &Name) -> Option<&String> {
+    match name {
+        Name::Ident(name) => Some(name),
+        Name::Intermed(_) => None,
+    }
+}
+
+/// Names the block reads before defining them and the names it defines. The branch condition
+/// and the returned values count as read at the end of the block.
+fn gen_kill(block: &Block, exit: &Terminator) -> (BTreeSet<String>, BTreeSet<String>) {
+    let mut gen: BTreeSet<String> = BTreeSet::new();
+    let mut kill: BTreeSet<String> = BTreeSet::new();
+    for stmt in block.statements.iter() {
+        for name in stmt.uses().into_iter().filter_map(ident) {
+            if !kill.contains(name) {
+                gen.insert(name.clone());
+            }
+        }
+        kill.extend(stmt.defs().into_iter().filter_map(ident).cloned());
+    }
+    let end_uses = match exit {
+        Terminator::Branch { .. } => &block.end_stack[block.end_stack.len() - 1..],
+        Terminator::Leave => &block.end_stack[..],
+        Terminator::Jump(_) | Terminator::Halt => &[],
+    };
+    gen.extend(
+        end_uses
+            .iter()
+            .filter(|name| !kill.contains(*name))
+            .cloned(),
+    );
+    (gen, kill)
+}
+
+fn find(class: &mut [usize], id: usize) -> usize {
+    let mut root = id;
+    while class[root] != root {
+        root = class[root];
+    }
+    class[id] = root;
+    root
+}
+
+/// Entry layouts shared by groups of blocks. The successors of a branch have to agree on their
+/// entry layout, as the branch leaves a single stack behind for both of them.
+struct Layouts<'f> {
+    function: &'f Function,
+    class: Vec<usize>,
+    layouts: Vec<Vec<String>>,
+    weights: Vec<usize>,
+}
+
+impl Layouts<'_> {
+    fn exit_layout(&self, id: BlockId) -> Vec<String> {
+        let block = &self.function.nodes[id].block;
+        match &self.function.nodes[id].exit {
+            Terminator::Jump(to) => self.layouts[self.class[*to]].clone(),
+            Terminator::Branch { non_zero, .. } => {
+                let mut layout = self.layouts[self.class[*non_zero]].clone();
+                layout.push(block.end_stack.last().unwrap().clone());
+                layout
+            }
+            Terminator::Leave => block.end_stack.clone(),
+            Terminator::Halt => vec![],
+        }
+    }
+
+    fn block(&self, id: BlockId) -> Block {
+        Block {
+            start_stack: self.layouts[self.class[id]].clone(),
+            statements: self.function.nodes[id].block.statements.clone(),
+            end_stack: self.exit_layout(id),
+        }
+    }
+
+    /// Stack and memory traffic of scheduling the block with the current layouts, weighted by
+    /// its loop depth.
+    fn cost(&self, id: BlockId) -> usize {
+        let block = self.block(id);
+        let (_, ops) = block.schedule_hybrid();
+        self.weights[id] * traffic_gas(&ops)
+    }
+}
+
+/// Orders the `live` names as they appear in `order` from the bottom, putting names missing
+/// from `order` on top.
+fn complete<'a>(
+    order: impl Iterator<Item = &'a String>,
+    live: &'a BTreeSet<String>,
+) -> Vec<String> {
+    let mut layout: Vec<String> = vec![];
+    for name in order.chain(live.iter()) {
+        if live.contains(name) && !layout.contains(name) {
+            layout.push(name.clone());
+        }
+    }
+    layout
+}
+
+fn permutations(names: &[String]) -> Vec<Vec<String>> {
+    if names.len() <= 1 {
+        return vec![names.to_vec()];
+    }
+    let mut all = vec![];
+    for i in 0..names.len() {
+        let mut rest = names.to_vec();
+        let first = rest.remove(i);
+        for mut permutation in permutations(&rest) {
+            permutation.push(first.clone());
+            all.push(permutation);
+        }
+    }
+    all
+}
+
+impl LayoutNegotiation for Function {
+    /// Recomputes the entry layout of every block from the names live into it, dropping dead
+    /// slots, and sets the exit layout of every block to the entry layout of its successors so
+    /// no edge needs a shuffle of its own. Among the orders blocks and their predecessors
+    /// already use, or all orders for few names, picks the one minimizing the traffic of the
+    /// affected blocks weighted by loop depth. The entry block keeps its layout, it is fixed by
+    /// the calling convention.
+    fn negotiate_layouts(&mut self) {
+        let n = self.nodes.len();
+        if n == 0 {
+            return;
+        }
+        let preds = self.predecessors();
+        let weights: Vec<usize> = self
+            .loop_depths()
+            .into_iter()
+            .map(|depth| LOOP_WEIGHT.pow(depth.min(4) as u32))
+            .collect();
+
+        let mut class: Vec<usize> = (0..n).collect();
+        for node in self.nodes.iter() {
+            if let Terminator::Branch { non_zero, zero } = node.exit {
+                let (a, b) = (find(&mut class, non_zero), find(&mut class, zero));
+                class[a.max(b)] = a.min(b);
+            }
+        }
+        let class: Vec<usize> = (0..n).map(|id| find(&mut class, id)).collect();
+
+        let (gens, kills): (Vec<_>, Vec<_>) = self
+            .nodes
+            .iter()
+            .map(|node| gen_kill(&node.block, &node.exit))
+            .unzip();
+        let mut live: Vec<BTreeSet<String>> = vec![BTreeSet::new(); n];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in (0..n).rev() {
+                let live_out: BTreeSet<String> = self.nodes[id]
+                    .exit
+                    .successors()
+                    .into_iter()
+                    .flat_map(|succ| live[class[succ]].iter().cloned())
+                    .collect();
+                let live_in = live_out.difference(&kills[id]).chain(gens[id].iter());
+                let before = live[class[id]].len();
+                live[class[id]].extend(live_in.cloned().collect::<Vec<_>>());
+                changed |= live[class[id]].len() != before;
+            }
+        }
+        let entry: BTreeSet<String> = self.nodes[0].block.start_stack.iter().cloned().collect();
+        if !live[class[0]].is_subset(&entry) {
+            return;
+        }
+
+        let classes = &class;
+        let members = |c: usize| (0..n).filter(move |id| classes[*id] == c);
+        let mut layouts: Vec<Vec<String>> = (0..n)
+            .map(|c| match members(c).next() {
+                Some(first) => complete(self.nodes[first].block.start_stack.iter(), &live[c]),
+                None => vec![],
+            })
+            .collect();
+        layouts[class[0]] = self.nodes[0].block.start_stack.clone();
+
+        let mut order: Vec<usize> = (0..n)
+            .filter(|c| class[*c] == *c && *c != class[0])
+            .collect();
+        order.sort_by_key(|c| std::cmp::Reverse(members(*c).map(|id| weights[id]).max()));
+        let mut state = Layouts {
+            function: self,
+            class: class.clone(),
+            layouts,
+            weights,
+        };
+        for _ in 0..2 {
+            for c in order.iter().copied() {
+                let affected: BTreeSet<BlockId> = members(c)
+                    .flat_map(|id| preds[id].iter().copied().chain([id]))
+                    .collect();
+                let mut candidates: Vec<Vec<String>> = vec![state.layouts[c].clone()];
+                for id in members(c) {
+                    let block = &state.function.nodes[id].block;
+                    candidates.push(complete(block.start_stack.iter(), &live[c]));
+                    for pred in preds[id].iter() {
+                        let end = &state.function.nodes[*pred].block.end_stack;
+                        candidates.push(complete(end.iter(), &live[c]));
+                    }
+                }
+                if live[c].len() <= MAX_PERMUTED_LAYOUT {
+                    let names: Vec<String> = live[c].iter().cloned().collect();
+                    candidates.extend(permutations(&names));
+                }
+                candidates.dedup();
+
+                let best = candidates
+                    .into_iter()
+                    .map(|layout| {
+                        state.layouts[c] = layout;
+                        let cost: usize = affected.iter().map(|id| state.cost(*id)).sum();
+                        (cost, state.layouts[c].clone())
+                    })
+                    .min_by_key(|(cost, _)| *cost)
+                    .unwrap();
+                state.layouts[c] = best.1;
+            }
+        }
+
+        let blocks: Vec<Block> = (0..n).map(|id| state.block(id)).collect();
+        for (node, block) in self.nodes.iter_mut().zip(blocks) {
+            node.block = block;
+        }
+    }
+}
+
+impl LayoutNegotiation for Program {
+    fn negotiate_layouts(&mut self) {
+        self.main.negotiate_layouts();
+        self.functions
+            .values_mut()
+            .for_each(|function| function.negotiate_layouts());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssa_block::Statement;
+    use crate::stack_scheduler::test::{call, lit, names, node, r};
+
+    fn assert_consistent(function: &Function) {
+        for node in function.nodes.iter() {
+            let mut end = node.block.end_stack.clone();
+            if matches!(node.exit, Terminator::Branch { .. }) {
+                end.pop();
+            }
+            for succ in node.exit.successors() {
+                assert_eq!(end, function.nodes[succ].block.start_stack);
+            }
+        }
+    }
+
+    fn weighted_cost(function: &Function) -> usize {
+        let depths = function.loop_depths();
+        function
+            .nodes
+            .iter()
+            .zip(depths)
+            .map(|(node, depth)| {
+                let (_, ops) = node.block.schedule_hybrid();
+                LOOP_WEIGHT.pow(depth as u32) * traffic_gas(&ops)
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_negotiate_drops_dead_slots() {
+        let mut function = Function {
+            nodes: vec![
+                node(
+                    &["a", "b", "c"],
+                    vec![call(&["x"], "add", vec![r("a"), r("b")])],
+                    &["a", "b", "c", "x", "x"],
+                    Terminator::Branch {
+                        non_zero: 1,
+                        zero: 2,
+                    },
+                ),
+                node(
+                    &["a", "b", "c", "x"],
+                    vec![call(&["y"], "mul", vec![r("a"), r("x")])],
+                    &["a", "b", "c", "x", "y"],
+                    Terminator::Jump(3),
+                ),
+                node(
+                    &["a", "b", "c", "x"],
+                    vec![call(&["y"], "sub", vec![r("b"), r("x")])],
+                    &["a", "b", "c", "x", "y"],
+                    Terminator::Jump(3),
+                ),
+                node(
+                    &["a", "b", "c", "x", "y"],
+                    vec![call(&[], "sstore", vec![r("y"), r("x")])],
+                    &["a", "b", "c", "x", "y"],
+                    Terminator::Halt,
+                ),
+            ],
+        };
+        function.negotiate_layouts();
+        assert_consistent(&function);
+
+        let set = |names: &[String]| names.iter().cloned().collect::<BTreeSet<_>>();
+        let expected = |expected: &[&str]| names(expected).into_iter().collect();
+        assert_eq!(
+            set(&function.nodes[1].block.start_stack),
+            expected(&["a", "b", "x"])
+        );
+        assert_eq!(
+            set(&function.nodes[3].block.start_stack),
+            expected(&["x", "y"])
+        );
+        assert!(function.nodes[3].block.end_stack.is_empty());
+    }
+
+    #[test]
+    fn test_negotiate_loop() {
+        // i := 0 ... for {} lt(i, n) { s := add(s, i)  i := add(i, 1) } sstore(0, s)
+        let mut function = Function {
+            nodes: vec![
+                node(
+                    &["n", "s", "i"],
+                    vec![],
+                    &["n", "s", "i"],
+                    Terminator::Jump(1),
+                ),
+                node(
+                    &["n", "s", "i"],
+                    vec![call(&["c"], "lt", vec![r("i"), r("n")])],
+                    &["n", "s", "i", "c"],
+                    Terminator::Branch {
+                        non_zero: 2,
+                        zero: 3,
+                    },
+                ),
+                node(
+                    &["n", "s", "i"],
+                    vec![
+                        call(&["s"], "add", vec![r("s"), r("i")]),
+                        Statement::ValueAssign {
+                            to: "one".to_owned().into(),
+                            value: lit(1),
+                            span: None,
+                        },
+                        call(&["i"], "add", vec![r("i"), r("one")]),
+                    ],
+                    &["n", "s", "i"],
+                    Terminator::Jump(1),
+                ),
+                node(
+                    &["n", "s", "i"],
+                    vec![call(&[], "sstore", vec![r("s"), r("s")])],
+                    &[],
+                    Terminator::Halt,
+                ),
+            ],
+        };
+        let before = weighted_cost(&function);
+        function.negotiate_layouts();
+        assert_consistent(&function);
+        assert_eq!(function.nodes[0].block.start_stack, vec!["n", "s", "i"]);
+        assert!(weighted_cost(&function) <= before);
+    }
+}
@@ -1,3 +1,26 @@
+pub mod assembly;
 pub mod basic_block;
+pub mod block_order;
+pub mod cfg;
+pub mod const_fold;
+pub mod cse;
+pub mod dce;
+pub mod debug_info;
+pub mod dialect;
+pub mod hybrid_scheduler;
+pub mod inline;
+pub mod layout;
+pub mod memory_layout;
+pub mod metadata;
+pub mod object;
+pub mod reorder;
+pub mod rules;
 pub mod scheduler;
+pub mod shuffle;
+pub mod slot_alloc;
+pub mod source_map;
+pub mod spill;
 pub mod ssa_block;
+pub mod stack_scheduler;
+pub mod strategy;
+pub mod verify;
@@ -0,0 +1,299 @@
+use crate::const_fold::to_literal;
+use crate::dialect::EvmVersion;
+use crate::scheduler::Op;
+use ruint::aliases::U256;
+
+pub const WORD_SIZE: usize = 32;
+/// Address of Solidity's free memory pointer.
+pub const FREE_MEMORY_POINTER: usize = 0x40;
+
+/// Where the memory slots of `MemVar*` ops live in EVM memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLayout {
+    /// Bytes at the start of memory left alone, by default Solidity's scratch space, free
+    /// memory pointer and zero slot.
+    pub reserved: usize,
+    /// Offset of the first slot from the end of the reserved region.
+    pub base: usize,
+    /// Bytes per slot, a multiple of the word size.
+    pub slot_size: usize,
+    /// Whether to move the free memory pointer past the slots, so memory allocated through it
+    /// never overlaps them.
+    pub reserve_spill_area: bool,
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self {
+            reserved: 0x80,
+            base: 0,
+            slot_size: WORD_SIZE,
+            reserve_spill_area: true,
+        }
+    }
+}
+
+impl MemoryLayout {
+    pub fn new(reserved: usize, base: usize, slot_size: usize, reserve_spill_area: bool) -> Self {
+        let layout = Self {
+            reserved,
+            base,
+            slot_size,
+            reserve_spill_area,
+        };
+        assert!(
+            slot_size > 0 && slot_size.is_multiple_of(WORD_SIZE),
+            "Slot size {} is not a multiple of the word size",
+            slot_size
+        );
+        assert!(
+            layout.address(0).is_multiple_of(WORD_SIZE),
+            "First slot at {:#x} is not word aligned",
+            layout.address(0)
+        );
+        layout
+    }
+
+    pub fn address(&self, slot: usize) -> usize {
+        self.reserved + self.base + self.slot_size * slot
+    }
+
+    /// First byte past `slots` slots.
+    pub fn end(&self, slots: usize) -> usize {
+        self.address(slots)
+    }
+
+    /// Ops to run once before any slot is used: sets the free memory pointer to the end of the
+    /// spill area if it is reserved.
+    pub fn prologue(&self, slots: usize) -> Vec<Op<'static>> {
+        match self.reserve_spill_area && slots > 0 {
+            true => vec![
+                push(self.end(slots)),
+                push(FREE_MEMORY_POINTER),
+                Op::CallFn("mstore"),
+            ],
+            false => vec![],
+        }
+    }
+
+    /// Ops a `memoryguard(size)` call becomes. Code starting with
+    /// `mstore(0x40, memoryguard(size))` claims the memory below `size` for itself and
+    /// initializes the free memory pointer to what the call returns, overwriting `prologue`,
+    /// so with the spill area reserved the call returns its end instead, the slots lying above
+    /// the claimed memory.
+    pub fn memory_guard(&self, slots: usize) -> Vec<Op<'static>> {
+        match self.reserve_spill_area && slots > 0 {
+            true => vec![Op::Pop, push(self.end(slots))],
+            false => vec![],
+        }
+    }
+
+    /// Replaces slot ops by `mload`/`mstore` of the slot addresses, so `MemVarLoad(i)` becomes
+    /// `PUSH address(i) MLOAD`. From Cancun, runs of copies between adjacent slots become a
+    /// single `mcopy`, cheaper than a load and store per word from two words on.
+    pub fn lower<'a>(&self, ops: &[Op<'a>], version: EvmVersion) -> Vec<Op<'a>> {
+        let load = |slot: usize| [push(self.address(slot)), Op::CallFn("mload")];
+        let store = |slot: usize| [push(self.address(slot)), Op::CallFn("mstore")];
+        let mut lowered = vec![];
+        let mut i = 0;
+        while i < ops.len() {
+            match ops[i] {
+                Op::MemVarLoad(slot) => lowered.extend(load(slot)),
+                Op::MemVarStore(slot) => lowered.extend(store(slot)),
+                Op::MemCopy { from, to } => {
+                    let words = match version.has_builtin("mcopy") && self.slot_size == WORD_SIZE {
+                        true => copy_run(&ops[i..]),
+                        false => 1,
+                    };
+                    if words > 1 {
+                        lowered.extend([
+                            push(words * WORD_SIZE),
+                            push(self.address(from)),
+                            push(self.address(to)),
+                            Op::CallFn("mcopy"),
+                        ]);
+                        i += words;
+                        continue;
+                    }
+                    lowered.extend(load(from));
+                    lowered.extend(store(to));
+                }
+                Op::MemSwap(a, b) => {
+                    lowered.extend(load(a));
+                    lowered.extend(load(b));
+                    lowered.extend(store(a));
+                    lowered.extend(store(b));
+                }
+                ref op => lowered.push(op.clone()),
+            }
+            i += 1;
+        }
+        lowered
+    }
+}
+
+/// Number of copies at the start of `ops` moving consecutive slots to consecutive slots, as
+/// long as copying them at once matches copying them one after another: the copied slots may
+/// only overlap the destination if they are copied downwards.
+fn copy_run(ops: &[Op]) -> usize {
+    let Some(Op::MemCopy { from, to }) = ops.first() else {
+        return 0;
+    };
+    ops.iter()
+        .enumerate()
+        .take_while(|(k, op)| {
+            matches!(op, Op::MemCopy { from: f, to: t } if *f == from + k && *t == to + k)
+                && (to < from || from + k < *to)
+        })
+        .count()
+}
+
+fn push(value: usize) -> Op<'static> {
+    Op::Push(to_literal(U256::from(value)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::const_fold::to_word;
+    use crate::hybrid_scheduler::HybridScheduler;
+    use crate::ssa_block::{Block, Statement};
+    use crate::stack_scheduler::test::{interpret, run};
+    use std::collections::HashMap;
+
+    /// Runs lowered ops of blocks without calls, `mload` and `mstore` being memory accesses.
+    fn run_lowered(ops: &[Op], start: &[u64]) -> (Vec<u64>, HashMap<usize, u64>) {
+        let mut stack = start.to_vec();
+        let mut memory: HashMap<usize, u64> = HashMap::new();
+        for op in ops {
+            match op {
+                Op::Swap(n) => {
+                    let top = stack.len() - 1;
+                    stack.swap(top, top - n);
+                }
+                Op::Dup(n) => stack.push(stack[stack.len() - n]),
+                Op::Pop => {
+                    stack.pop();
+                }
+                Op::Push(lit) => stack.push(to_word(lit).to::<u64>()),
+                Op::CallFn("mload") => {
+                    let address = stack.pop().unwrap() as usize;
+                    stack.push(memory[&address]);
+                }
+                Op::CallFn("mstore") => {
+                    let address = stack.pop().unwrap() as usize;
+                    memory.insert(address, stack.pop().unwrap());
+                }
+                Op::CallFn("mcopy") => {
+                    let to = stack.pop().unwrap() as usize;
+                    let from = stack.pop().unwrap() as usize;
+                    let size = stack.pop().unwrap() as usize;
+                    let words: Vec<u64> = (from..from + size)
+                        .step_by(WORD_SIZE)
+                        .map(|address| memory[&address])
+                        .collect();
+                    for (i, word) in words.into_iter().enumerate() {
+                        memory.insert(to + i * WORD_SIZE, word);
+                    }
+                }
+                other => panic!("Unexpected {:?}", other),
+            }
+        }
+        (stack, memory)
+    }
+
+    #[test]
+    fn test_lower_addresses() {
+        let layout = MemoryLayout::default();
+        let ops = layout.lower(&[Op::MemVarLoad(2), Op::Pop], EvmVersion::default());
+        assert_eq!(ops, vec![push(0xc0), Op::CallFn("mload"), Op::Pop]);
+        assert_eq!(
+            layout.prologue(3),
+            vec![push(0xe0), push(0x40), Op::CallFn("mstore")]
+        );
+
+        assert_eq!(layout.memory_guard(3), vec![Op::Pop, push(0xe0)]);
+        assert!(layout.memory_guard(0).is_empty());
+
+        let layout = MemoryLayout::new(0x80, 0x100, 64, false);
+        assert_eq!(layout.address(1), 0x1c0);
+        assert!(layout.prologue(3).is_empty());
+        assert!(layout.memory_guard(3).is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unaligned_layout() {
+        MemoryLayout::new(0x80, 4, 32, true);
+    }
+
+    #[test]
+    fn test_lower_spills() {
+        // Reversing 18 values needs spills, the only calls are the lowered memory accesses.
+        let names: Vec<String> = (0..18).map(|i| format!("v{}", i)).collect();
+        let block = Block {
+            start_stack: names.clone(),
+            statements: Vec::<Statement>::new(),
+            end_stack: names.iter().rev().cloned().collect(),
+        };
+        let start: Vec<u64> = (0..18).collect();
+        let (slots, ops) = block.schedule_hybrid();
+        assert_eq!(run(&block, &ops, &start), interpret(&block, &start));
+
+        let layout = MemoryLayout::default();
+        let mut lowered = layout.prologue(slots);
+        lowered.extend(layout.lower(&ops, EvmVersion::default()));
+        let (stack, memory) = run_lowered(&lowered, &start);
+        assert_eq!(stack, interpret(&block, &start));
+        assert_eq!(memory[&FREE_MEMORY_POINTER], layout.end(slots) as u64);
+        assert!(memory
+            .keys()
+            .filter(|address| **address != FREE_MEMORY_POINTER)
+            .all(|address| *address >= 0x80 && *address < layout.end(slots)));
+    }
+
+    #[test]
+    fn test_lower_copies() {
+        let ops = [
+            Op::MemVarStore(0),
+            Op::MemVarStore(1),
+            Op::MemSwap(0, 1),
+            Op::MemCopy { from: 0, to: 2 },
+            Op::MemVarLoad(2),
+            Op::MemVarLoad(1),
+        ];
+        let layout = MemoryLayout::default();
+        let (stack, _) = run_lowered(&layout.lower(&ops, EvmVersion::default()), &[7, 8]);
+        assert_eq!(stack, vec![7, 8]);
+    }
+
+    #[test]
+    fn test_lower_copies_to_mcopy() {
+        let copies = |pairs: &[(usize, usize)], slots: usize| -> Vec<Op<'static>> {
+            let mut ops = vec![Op::MemVarStore(0), Op::MemVarStore(1), Op::MemVarStore(2)];
+            ops.extend(pairs.iter().map(|(from, to)| Op::MemCopy {
+                from: *from,
+                to: *to,
+            }));
+            ops.extend((0..slots).map(Op::MemVarLoad));
+            ops
+        };
+        let layout = MemoryLayout::default();
+        let mcopies = |ops: &[Op], version| {
+            let lowered = layout.lower(ops, version);
+            let count = lowered
+                .iter()
+                .filter(|op| **op == Op::CallFn("mcopy"))
+                .count();
+            (count, run_lowered(&lowered, &[7, 8, 9]).0)
+        };
+
+        // Copying upwards into the copied slots needs a word at a time.
+        let ops = copies(&[(0, 1), (1, 2)], 3);
+        assert_eq!(mcopies(&ops, EvmVersion::Cancun), (0, vec![9, 9, 9]));
+        let ops = copies(&[(1, 0), (2, 1), (0, 3), (1, 4)], 5);
+        let expected = vec![8, 7, 7, 8, 7];
+        assert_eq!(mcopies(&ops, EvmVersion::Cancun), (2, expected.clone()));
+        assert_eq!(mcopies(&ops, EvmVersion::Shanghai), (0, expected));
+    }
+}
@@ -0,0 +1,140 @@
+/// Value of a metadata entry, encoded as the CBOR item of the same kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataValue {
+    Bytes(Vec<u8>),
+    Text(String),
+    Unsigned(u64),
+    Bool(bool),
+}
+
+/// CBOR map appended to the code like solc's metadata, where verification services look for
+/// hashes of the sources and the compiler version. Entries keep their order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub entries: Vec<(String, MetadataValue)>,
+}
+
+impl Default for Metadata {
+    /// Only the compiler version, as `major, minor, patch` bytes like solc's `solc` entry.
+    /// Panics if a part of the package version is above 255 and does not fit its byte.
+    fn default() -> Self {
+        let version = [
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            env!("CARGO_PKG_VERSION_MINOR"),
+            env!("CARGO_PKG_VERSION_PATCH"),
+        ]
+        .map(|part| {
+            part.parse()
+                .unwrap_or_else(|_| panic!("Package version part {} is not a byte", part))
+        })
+        .to_vec();
+        Self {
+            entries: vec![("dunce".to_owned(), MetadataValue::Bytes(version))],
+        }
+    }
+}
+
+/// Appends the head of a CBOR item of the `major` type with argument `value`.
+fn head(cbor: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => cbor.push(major | value as u8),
+        24..=0xff => cbor.extend([major | 24, value as u8]),
+        0x100..=0xffff => {
+            cbor.push(major | 25);
+            cbor.extend((value as u16).to_be_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            cbor.push(major | 26);
+            cbor.extend((value as u32).to_be_bytes());
+        }
+        _ => {
+            cbor.push(major | 27);
+            cbor.extend(value.to_be_bytes());
+        }
+    }
+}
+
+impl Metadata {
+    /// The entries as a CBOR map.
+    pub fn cbor(&self) -> Vec<u8> {
+        let mut cbor = vec![];
+        head(&mut cbor, 5, self.entries.len() as u64);
+        for (key, value) in self.entries.iter() {
+            head(&mut cbor, 3, key.len() as u64);
+            cbor.extend(key.as_bytes());
+            match value {
+                MetadataValue::Bytes(bytes) => {
+                    head(&mut cbor, 2, bytes.len() as u64);
+                    cbor.extend(bytes);
+                }
+                MetadataValue::Text(text) => {
+                    head(&mut cbor, 3, text.len() as u64);
+                    cbor.extend(text.as_bytes());
+                }
+                MetadataValue::Unsigned(value) => head(&mut cbor, 0, *value),
+                MetadataValue::Bool(value) => cbor.push(0xf4 | *value as u8),
+            }
+        }
+        cbor
+    }
+
+    /// The CBOR map followed by its length as two big-endian bytes, so it can be found from the
+    /// end of the code.
+    pub fn trailer(&self) -> Vec<u8> {
+        let mut trailer = self.cbor();
+        let length = u16::try_from(trailer.len())
+            .unwrap_or_else(|_| panic!("Metadata of {} bytes is too long", trailer.len()));
+        trailer.extend(length.to_be_bytes());
+        trailer
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trailer() {
+        let solc = Metadata {
+            entries: vec![(
+                "solc".to_owned(),
+                MetadataValue::Bytes(vec![0x00, 0x08, 0x17]),
+            )],
+        };
+        assert_eq!(
+            solc.trailer(),
+            [0xa1, 0x64, b's', b'o', b'l', b'c', 0x43, 0x00, 0x08, 0x17, 0x00, 0x0a]
+        );
+
+        let custom = Metadata {
+            entries: vec![
+                ("a".to_owned(), MetadataValue::Text("x".repeat(24))),
+                ("b".to_owned(), MetadataValue::Unsigned(500)),
+                ("c".to_owned(), MetadataValue::Bool(true)),
+            ],
+        };
+        let cbor = custom.cbor();
+        assert_eq!(&cbor[..5], &[0xa3, 0x61, b'a', 0x78, 24]);
+        assert_eq!(
+            &cbor[29..],
+            &[0x61, b'b', 0x19, 0x01, 0xf4, 0x61, b'c', 0xf5]
+        );
+    }
+
+    #[test]
+    fn test_default_version() {
+        let MetadataValue::Bytes(version) = &Metadata::default().entries[0].1 else {
+            panic!("Version is not bytes");
+        };
+        let part = |part: &str| part.parse::<u8>().unwrap();
+        assert_eq!(
+            version,
+            &[
+                part(env!("CARGO_PKG_VERSION_MAJOR")),
+                part(env!("CARGO_PKG_VERSION_MINOR")),
+                part(env!("CARGO_PKG_VERSION_PATCH")),
+            ]
+        );
+    }
+}
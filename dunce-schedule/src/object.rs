@@ -0,0 +1,444 @@
+use crate::assembly::{Assembler, AssemblyError, Bytecode};
+use crate::cfg::Program;
+use crate::metadata::Metadata;
+use crate::strategy::StrategySelection;
+use ir::YulObject;
+
+/// A Yul object with its code turned into instructions, ready to be assembled together with its
+/// sub-objects and data sections.
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    pub code: Assembler,
+    pub objects: Vec<(String, Object)>,
+    pub data: Vec<(String, Vec<u8>)>,
+    /// Trailer appended after the sub-objects and data sections, none if `None`.
+    pub metadata: Option<Metadata>,
+}
+
+impl Object {
+    /// Compiles `yul` to its bytecode, the code of it and of each sub-object lowered to a
+    /// `cfg::Program` appended to a copy of `assembler`, empty but for its settings, with the
+    /// strategies `selection` picks. Sub-objects and data sections follow as in `assemble`.
+    pub fn from_yul(
+        yul: &YulObject,
+        assembler: &Assembler,
+        selection: &StrategySelection,
+    ) -> Result<Bytecode, AssemblyError> {
+        Self::lower(yul, assembler, selection)?.assemble()
+    }
+
+    fn lower(
+        yul: &YulObject,
+        assembler: &Assembler,
+        selection: &StrategySelection,
+    ) -> Result<Self, AssemblyError> {
+        let mut code = assembler.clone();
+        code.program(&Program::try_from(yul.code.clone())?, selection)?;
+        Ok(Self {
+            code,
+            objects: yul
+                .objects
+                .iter()
+                .map(|(name, object)| {
+                    Ok((name.clone(), Self::lower(object, assembler, selection)?))
+                })
+                .collect::<Result<_, AssemblyError>>()?,
+            data: yul.data.clone(),
+            metadata: None,
+        })
+    }
+
+    /// Assembles the sub-objects, then the code followed by the sub-objects and the data
+    /// sections in order, so `dataoffset` and `datasize` of them resolve. Nested sub-objects
+    /// and data are referred to by their dotted path, like `runtime.meta`. The metadata trailer,
+    /// if any, comes last, and is part of the object's `datasize` in its parent.
+    pub fn assemble(&self) -> Result<Bytecode, AssemblyError> {
+        let blobs: Vec<(String, Bytecode)> = self
+            .objects
+            .iter()
+            .map(|(name, object)| Ok((name.clone(), object.assemble()?)))
+            .chain(
+                self.data
+                    .iter()
+                    .map(|(name, data)| Ok((name.clone(), data.clone().into()))),
+            )
+            .collect::<Result<_, AssemblyError>>()?;
+        let mut bytecode = self.code.assemble_with(&blobs)?;
+        if let Some(metadata) = &self.metadata {
+            bytecode.append_metadata(&metadata.trailer());
+        }
+        Ok(bytecode)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembly::test::execute;
+    use crate::assembly::{CodeTooLarge, MAX_CODE_SIZE, STOP};
+    use crate::cfg::{Function, Terminator};
+    use crate::const_fold::to_literal;
+    use crate::dialect::literal_builtin_call;
+    use crate::metadata::MetadataValue;
+    use crate::ssa_block::Statement;
+    use crate::stack_scheduler::test::{call, lit, node, r};
+    use crate::strategy::Strategy;
+    use ruint::aliases::U256;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn function(
+        start: &[&str],
+        statements: Vec<Statement>,
+        end: &[&str],
+        exit: Terminator,
+    ) -> Function {
+        Function {
+            nodes: vec![node(start, statements, end, exit)],
+        }
+    }
+
+    fn assemble(program: &Program) -> Assembler {
+        let mut assembler = Assembler::default();
+        assembler
+            .program(program, &StrategySelection::default())
+            .unwrap();
+        assembler
+    }
+
+    /// Copies the object `runtime` to memory and returns it.
+    fn deploy() -> Program {
+        let offset = literal_builtin_call("dataoffset", "runtime");
+        let size = literal_builtin_call("datasize", "runtime");
+        Program {
+            main: function(
+                &[],
+                vec![
+                    call(&["o"], &offset, vec![]),
+                    call(&["n"], &size, vec![]),
+                    call(&[], "codecopy", vec![lit(0), r("o"), r("n")]),
+                    call(&[], "return", vec![lit(0), r("n")]),
+                ],
+                &[],
+                Terminator::Halt,
+            ),
+            functions: BTreeMap::new(),
+        }
+    }
+
+    /// Stores `f(41)` with `f(x) -> y { y := add(x, 1) }`.
+    fn runtime() -> Program {
+        Program {
+            main: function(
+                &[],
+                vec![
+                    call(&["y"], "f", vec![lit(41)]),
+                    call(&[], "sstore", vec![lit(0), r("y")]),
+                ],
+                &[],
+                Terminator::Halt,
+            ),
+            functions: BTreeMap::from([(
+                "f".to_owned(),
+                function(
+                    &["x", "ret"],
+                    vec![call(&["y"], "add", vec![r("x"), lit(1)])],
+                    &["y", "ret"],
+                    Terminator::Leave,
+                ),
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_deploy_runtime() {
+        let object = Object {
+            code: assemble(&deploy()),
+            objects: vec![(
+                "runtime".to_owned(),
+                Object {
+                    code: assemble(&runtime()),
+                    objects: vec![],
+                    data: vec![("meta".to_owned(), vec![0xaa; 3])],
+                    metadata: None,
+                },
+            )],
+            data: vec![("blob".to_owned(), vec![1, 2])],
+            metadata: None,
+        };
+        let runtime = object.objects[0].1.assemble().unwrap();
+        let bytecode = object.assemble().unwrap();
+
+        let start = bytecode.data["runtime"].start;
+        assert_eq!(bytecode.data["runtime"], start..start + runtime.size());
+        assert_eq!(
+            bytecode.data["runtime.meta"],
+            start + runtime.data["meta"].start..start + runtime.data["meta"].end
+        );
+        assert_eq!(
+            bytecode.data["blob"],
+            start + runtime.size()..bytecode.size()
+        );
+        assert_eq!(&bytecode.code[bytecode.data["blob"].clone()], &[1, 2]);
+
+        let deployed = execute(&bytecode.code);
+        assert_eq!(deployed.output, runtime.code);
+        let run = execute(&deployed.output);
+        assert_eq!(run.storage[&U256::ZERO], U256::from(42));
+    }
+
+    #[test]
+    fn test_metadata_trailer() {
+        let custom = Metadata {
+            entries: vec![("ipfs".to_owned(), MetadataValue::Bytes(vec![0x12; 34]))],
+        };
+        let object = Object {
+            code: assemble(&deploy()),
+            objects: vec![(
+                "runtime".to_owned(),
+                Object {
+                    code: assemble(&runtime()),
+                    metadata: Some(custom.clone()),
+                    ..Default::default()
+                },
+            )],
+            data: vec![("blob".to_owned(), vec![1, 2])],
+            metadata: Some(Metadata::default()),
+        };
+        let runtime = object.objects[0].1.assemble().unwrap();
+        let trailer = custom.trailer();
+        assert!(runtime.code.ends_with(&trailer));
+        assert_eq!(
+            runtime.metadata,
+            Some(runtime.size() - trailer.len()..runtime.size())
+        );
+
+        // The parent's trailer follows the data, the runtime's is part of the runtime.
+        let bytecode = object.assemble().unwrap();
+        let metadata = bytecode.metadata.clone().unwrap();
+        assert_eq!(metadata.start, bytecode.data["blob"].end);
+        assert!(bytecode.code.ends_with(&Metadata::default().trailer()));
+        assert_eq!(bytecode.data["runtime"].len(), runtime.size());
+        let deployed = execute(&bytecode.code);
+        assert_eq!(deployed.output, runtime.code);
+        let run = execute(&deployed.output);
+        assert_eq!(run.storage[&U256::ZERO], U256::from(42));
+
+        // Omitted by default.
+        let mut plain = object.clone();
+        plain.metadata = None;
+        assert_eq!(plain.assemble().unwrap().size(), metadata.start);
+    }
+
+    #[test]
+    fn test_code_size_limit() {
+        let mut bytecode = Bytecode::from(vec![STOP; MAX_CODE_SIZE]);
+        assert_eq!(bytecode.check_size(), Ok(()));
+        bytecode.append_metadata(&Metadata::default().trailer());
+        assert_eq!(bytecode.check_size(), Ok(()));
+        bytecode.code.insert(0, STOP);
+        assert_eq!(
+            bytecode.check_size(),
+            Err(CodeTooLarge {
+                size: MAX_CODE_SIZE + 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_yul() {
+        let yul: YulObject = r#"
+            object "Test" {
+                code {
+                    codecopy(0, dataoffset("runtime"), datasize("runtime"))
+                    return(0, datasize("runtime"))
+                }
+                object "runtime" {
+                    code {
+                        mstore(0x40, memoryguard(0x80))
+                        // Sums 0 to n - 1 but 3, stopping past 6.
+                        function sum(n) -> s {
+                            for { let i := 0 } lt(i, n) { i := add(i, 1) } {
+                                if eq(i, 3) { continue }
+                                if gt(i, 6) { break }
+                                s := add(s, i)
+                            }
+                        }
+                        function classify(x) -> r {
+                            switch x
+                            case 0 { r := 10 }
+                            case 1 {
+                                r := 20
+                                leave
+                            }
+                            default { r := 30 }
+                            r := add(r, 1)
+                        }
+                        sstore(0, sum(10))
+                        let i := 0
+                        for {} lt(i, 3) { i := add(i, 1) } {
+                            sstore(add(i, 1), classify(i))
+                        }
+                        sstore(4, mload(0x40))
+                    }
+                    data "meta" hex"aabb"
+                }
+            }
+        "#
+        .parse()
+        .unwrap();
+        for strategy in Strategy::ALL {
+            let selection = StrategySelection {
+                default: strategy,
+                ..Default::default()
+            };
+            let bytecode = Object::from_yul(&yul, &Assembler::default(), &selection).unwrap();
+            let runtime = &bytecode.code[bytecode.data["runtime"].clone()];
+            assert!(runtime.ends_with(&[0xaa, 0xbb]));
+
+            let deployed = execute(&bytecode.code);
+            assert_eq!(deployed.output, runtime);
+            let storage = execute(&deployed.output).storage;
+            for (key, value) in [18, 11, 20, 31].into_iter().enumerate() {
+                assert_eq!(
+                    storage[&U256::from(key)],
+                    U256::from(value),
+                    "{:?}",
+                    strategy
+                );
+            }
+            // The free memory pointer starts past the spill area, if any.
+            assert!(storage[&U256::from(4)] >= U256::from(0x80));
+        }
+    }
+
+    #[test]
+    fn test_yul_source_map() {
+        let source = "{
+            let x := calldataload(0)
+            if x { sstore(0, add(x, 1)) }
+        }";
+        let yul: YulObject = source.parse().unwrap();
+        let bytecode =
+            Object::from_yul(&yul, &Assembler::default(), &StrategySelection::default()).unwrap();
+        let located: BTreeSet<&str> = bytecode
+            .source_map
+            .iter()
+            .filter_map(|location| location.span)
+            .map(|span| {
+                assert_eq!(span.source, Some(0));
+                &source[span.start..span.start + span.length]
+            })
+            .collect();
+        assert_eq!(
+            located,
+            BTreeSet::from([
+                "calldataload(0)",
+                "if x { sstore(0, add(x, 1)) }",
+                "add(x, 1)",
+                "sstore(0, add(x, 1))",
+            ])
+        );
+    }
+
+    #[test]
+    fn test_assembly_errors() {
+        let orphan = Object {
+            code: assemble(&deploy()),
+            ..Default::default()
+        };
+        assert_eq!(
+            orphan.assemble(),
+            Err(AssemblyError::UnknownData("runtime".to_owned()))
+        );
+
+        let loads = Program {
+            main: function(
+                &[],
+                vec![
+                    call(&["v"], &literal_builtin_call("loadimmutable", "x"), vec![]),
+                    call(&[], "sstore", vec![lit(0), r("v")]),
+                ],
+                &[],
+                Terminator::Halt,
+            ),
+            functions: BTreeMap::new(),
+        };
+        let sub = || Object {
+            code: assemble(&loads),
+            ..Default::default()
+        };
+        let object = Object {
+            code: assemble(&deploy()),
+            objects: vec![("runtime".to_owned(), sub()), ("other".to_owned(), sub())],
+            ..Default::default()
+        };
+        assert_eq!(
+            object.assemble(),
+            Err(AssemblyError::DuplicateImmutable("x".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_immutables_and_links() {
+        let call_of = literal_builtin_call;
+        let runtime = Program {
+            main: function(
+                &[],
+                vec![
+                    call(&["v"], &call_of("loadimmutable", "x"), vec![]),
+                    call(&[], "sstore", vec![lit(0), r("v")]),
+                    call(&["a"], &call_of("linkersymbol", "L"), vec![]),
+                    call(&[], "sstore", vec![lit(1), r("a")]),
+                    call(&["w"], &call_of("loadimmutable", "x"), vec![]),
+                    call(&[], "sstore", vec![lit(2), r("w")]),
+                ],
+                &[],
+                Terminator::Halt,
+            ),
+            functions: BTreeMap::new(),
+        };
+        let mut deploy = deploy();
+        deploy.main.nodes[0].block.statements.insert(
+            3,
+            call(&[], &call_of("setimmutable", "x"), vec![lit(0), lit(7)]),
+        );
+        let object = Object {
+            code: assemble(&deploy),
+            objects: vec![(
+                "runtime".to_owned(),
+                Object {
+                    code: assemble(&runtime),
+                    ..Default::default()
+                },
+            )],
+            data: vec![],
+            metadata: None,
+        };
+        let runtime = object.objects[0].1.assemble().unwrap();
+        assert_eq!(runtime.immutables["x"].len(), 2);
+        assert_eq!(runtime.links["L"].len(), 1);
+
+        let mut bytecode = object.assemble().unwrap();
+        assert!(bytecode.immutables.is_empty());
+        let reference = bytecode.links["L"][0];
+        assert_eq!(
+            reference.start,
+            bytecode.data["runtime"].start + runtime.links["L"][0].start
+        );
+        assert!(!bytecode.link("M", [0; 20]));
+        assert!(bytecode.link("L", [0x11; 20]));
+        assert!(bytecode.links.is_empty());
+
+        let deployed = execute(&bytecode.code);
+        let run = execute(&deployed.output);
+        let address = U256::from_be_slice(&[0x11; 20]);
+        assert_eq!(run.storage[&U256::ZERO], U256::from(7));
+        assert_eq!(run.storage[&U256::from(1)], address);
+        assert_eq!(run.storage[&U256::from(2)], U256::from(7));
+
+        let mut patched = runtime.clone();
+        assert!(patched.set_immutable("x", to_literal(U256::from(7))));
+        assert!(patched.link("L", [0x11; 20]));
+        assert_eq!(patched.code, deployed.output);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::cfg::{Function, Program};
+use crate::dialect::{self, Locations};
+use crate::ssa_block::{Block, Name, Statement, Value};
+use std::collections::HashMap;
+
+pub trait StatementReordering {
+    fn reorder_statements(&mut self);
+}
+
+/// Locations a statement reads from and writes to. Halting counts as writing everything, and
+/// statements observing `gas` or `msize` are kept in place among all statements with effects.
+fn effects(stmt: &Statement) -> (Locations, Locations) {
+    let (reads, writes) = match stmt {
+        Statement::ValueAssign { .. } => return (Locations::NONE, Locations::NONE),
+        Statement::CallAssign { calls, .. } => match dialect::builtin(calls) {
+            Some(builtin) if builtin.halts => (Locations::ALL, Locations::ALL),
+            Some(builtin) => (builtin.reads, builtin.writes),
+            None => (Locations::ALL, Locations::ALL),
+        },
+    };
+    match (reads | writes).is_empty() {
+        true => (reads, writes),
+        false if reads.intersects(Locations::EXECUTION) => (reads, writes | Locations::EXECUTION),
+        false => (reads | Locations::EXECUTION, writes),
+    }
+}
+
+fn conflicts(a: (Locations, Locations), b: (Locations, Locations)) -> bool {
+    let ((a_reads, a_writes), (b_reads, b_writes)) = (a, b);
+    a_writes.intersects(b_reads | b_writes) || b_writes.intersects(a_reads)
+}
+
+/// `succs[i]` are the statements that have to stay after statement `i`.
+fn dependences(statements: &[Statement]) -> Vec<Vec<usize>> {
+    let effects: Vec<_> = statements.iter().map(effects).collect();
+    let mut succs = vec![vec![]; statements.len()];
+    for (j, later) in statements.iter().enumerate() {
+        for (i, earlier) in statements[..j].iter().enumerate() {
+            let reads_def = later
+                .uses()
+                .iter()
+                .any(|name| earlier.defs().contains(name));
+            let overwrites = later
+                .defs()
+                .iter()
+                .any(|name| earlier.uses().contains(name) || earlier.defs().contains(name));
+            if reads_def || overwrites || conflicts(effects[i], effects[j]) {
+                succs[i].push(j);
+            }
+        }
+    }
+    succs
+}
+
+/// Original index of each statement of `block` in the order `reorder_statements` puts them.
+/// Statements are scheduled bottom up, placing the producers of each consumer's operands right
+/// before it, in the order the consumer takes them from the stack. Statements no consumer asks
+/// for next keep their relative source order.
+pub fn statement_order(block: &Block) -> Vec<usize> {
+    let succs = dependences(&block.statements);
+    let mut pending_succs: Vec<usize> = succs.iter().map(|succs| succs.len()).collect();
+    let mut preds = vec![vec![]; succs.len()];
+    for (i, succs) in succs.iter().enumerate() {
+        succs.iter().for_each(|j| preds[*j].push(i));
+    }
+
+    // Latest definition of every name before each statement and at the end of the block.
+    let mut defs: HashMap<&Name, usize> = HashMap::new();
+    let mut producers: Vec<Vec<Option<usize>>> = vec![];
+    for (i, stmt) in block.statements.iter().enumerate() {
+        let operands = match stmt {
+            Statement::CallAssign { takes, .. } => takes.iter().collect(),
+            Statement::ValueAssign { value, .. } => vec![value],
+        };
+        producers.push(
+            operands
+                .into_iter()
+                .map(|value| match value {
+                    Value::RefName(name) => defs.get(name).copied(),
+                    Value::Literal(_) => None,
+                })
+                .collect(),
+        );
+        stmt.defs().into_iter().for_each(|name| {
+            defs.insert(name, i);
+        });
+    }
+
+    // Requested producers, the next one to place on top.
+    let mut wanted: Vec<usize> = block
+        .end_stack
+        .iter()
+        .filter_map(|name| defs.get(&Name::from(name)).copied())
+        .collect();
+    let mut scheduled = vec![false; succs.len()];
+    let mut order: Vec<usize> = vec![];
+    while order.len() < succs.len() {
+        let is_ready = |i: &usize| !scheduled[*i] && pending_succs[*i] == 0;
+        let next = wanted
+            .iter()
+            .rev()
+            .find(|i| is_ready(i))
+            .copied()
+            .or_else(|| (0..succs.len()).rev().find(is_ready))
+            .expect("Dependences are acyclic");
+        scheduled[next] = true;
+        order.push(next);
+        preds[next].iter().for_each(|i| pending_succs[*i] -= 1);
+        wanted.retain(|i| !scheduled[*i]);
+        wanted.extend(producers[next].iter().rev().flatten());
+    }
+
+    order.into_iter().rev().collect()
+}
+
+impl StatementReordering for Block {
+    /// Puts the statements in `statement_order`.
+    fn reorder_statements(&mut self) {
+        let order = statement_order(self);
+        let mut statements: Vec<Option<Statement>> = std::mem::take(&mut self.statements)
+            .into_iter()
+            .map(Some)
+            .collect();
+        self.statements = order
+            .into_iter()
+            .map(|i| statements[i].take().unwrap())
+            .collect();
+    }
+}
+
+impl StatementReordering for Function {
+    fn reorder_statements(&mut self) {
+        self.nodes
+            .iter_mut()
+            .for_each(|node| node.block.reorder_statements());
+    }
+}
+
+impl StatementReordering for Program {
+    fn reorder_statements(&mut self) {
+        self.main.reorder_statements();
+        self.functions
+            .values_mut()
+            .for_each(|function| function.reorder_statements());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stack_scheduler::test::{
+        assert_schedules_equivalently, block, call, calls, interpret, lit, r, random_blocks,
+    };
+    use crate::stack_scheduler::StackScheduler;
+
+    #[test]
+    fn test_reorder_operands_before_consumer() {
+        let mut bb = block(
+            vec![
+                call(&["x"], "add", vec![r("a"), lit(1)]),
+                call(&["y"], "mul", vec![r("b"), lit(2)]),
+                call(&["z"], "sub", vec![r("x"), r("y")]),
+            ],
+            &["z"],
+        );
+        let before = bb.schedule_stack().unwrap().len();
+        bb.reorder_statements();
+        assert_eq!(calls(&bb), vec!["mul", "add", "sub"]);
+        assert!(bb.schedule_stack().unwrap().len() < before);
+    }
+
+    #[test]
+    fn test_reorder_keeps_effect_order() {
+        let mut bb = block(
+            vec![
+                call(&["x"], "mload", vec![lit(0)]),
+                call(&[], "mstore", vec![lit(0), r("a")]),
+                call(&["y"], "sload", vec![lit(0)]),
+                call(&["g"], "gas", vec![]),
+                call(&["z"], "add", vec![r("y"), r("x")]),
+                call(&[], "sstore", vec![lit(1), r("b")]),
+                call(&["w"], "mul", vec![r("z"), r("g")]),
+            ],
+            &["w"],
+        );
+        bb.reorder_statements();
+        let order = calls(&bb);
+        let pos = |name| order.iter().position(|c| *c == name).unwrap();
+        assert!(pos("mload") < pos("mstore"));
+        assert!(pos("sload") < pos("sstore"));
+        assert!(pos("mstore") < pos("gas") && pos("gas") < pos("sstore"));
+    }
+
+    #[test]
+    fn test_reorder_random() {
+        for (seed, mut block) in random_blocks(500) {
+            for stmt in block.statements.iter_mut() {
+                if let Statement::CallAssign { calls, .. } = stmt {
+                    *calls = match calls.as_str() {
+                        "f0" => "add",
+                        "f1" => "mload",
+                        _ => calls,
+                    }
+                    .to_owned();
+                }
+            }
+            let renamed = block.clone();
+            block.reorder_statements();
+            let start: Vec<u64> = (0..block.start_stack.len() as u64).collect();
+            assert_eq!(
+                interpret(&block, &start),
+                interpret(&renamed, &start),
+                "Seed {}: {:?} reordered to {:?}",
+                seed,
+                renamed,
+                block
+            );
+            assert_schedules_equivalently(seed, &block, &block.schedule_stack().unwrap());
+        }
+    }
+}
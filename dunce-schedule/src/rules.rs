@@ -0,0 +1,644 @@
+use crate::cfg::{Function, Program, Terminator};
+use crate::const_fold::{to_literal, to_word};
+use crate::dialect::{self, EvmVersion};
+use crate::ssa_block::{Block, Name, Statement, Value};
+use ruint::aliases::U256;
+use std::collections::HashMap;
+
+/// Expression shape to match against a statement and the definitions of its operands.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    /// Matches any value, binding it to the variable.
+    Any(&'static str),
+    /// Matches any literal, binding it to the variable.
+    Const(&'static str),
+    /// Matches exactly the given literal.
+    Word(U256),
+    Call(&'static str, Vec<Pattern>),
+}
+
+/// Expression to replace a match with. Arguments of `Call` may not be calls themselves.
+#[derive(Clone, Debug)]
+pub enum Replacement {
+    Var(&'static str),
+    Word(U256),
+    Computed(fn(&Bindings) -> U256),
+    Call(&'static str, Vec<Replacement>),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Bindings(HashMap<&'static str, Value>);
+
+impl Bindings {
+    pub fn get(&self, var: &str) -> &Value {
+        self.0
+            .get(var)
+            .unwrap_or_else(|| panic!("Unbound pattern variable {}", var))
+    }
+
+    /// Value of a variable bound by `Pattern::Const`.
+    pub fn word(&self, var: &str) -> U256 {
+        match self.get(var) {
+            Value::Literal(lit) => to_word(lit),
+            Value::RefName(_) => panic!("Pattern variable {} is not a constant", var),
+        }
+    }
+
+    fn bind(&mut self, var: &'static str, value: &Value) -> bool {
+        match self.0.get(var) {
+            Some(bound) => same_value(bound, value),
+            None => {
+                self.0.insert(var, value.clone());
+                true
+            }
+        }
+    }
+}
+
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::RefName(a), Value::RefName(b)) => a == b,
+        (Value::Literal(a), Value::Literal(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub name: &'static str,
+    pub pattern: Pattern,
+    pub replacement: Replacement,
+    pub condition: fn(&Bindings) -> bool,
+    /// Whether the rule only preserves truthiness and may only rewrite branch conditions.
+    pub only_as_condition: bool,
+}
+
+impl Rule {
+    pub fn new(name: &'static str, pattern: Pattern, replacement: Replacement) -> Self {
+        Self {
+            name,
+            pattern,
+            replacement,
+            condition: |_| true,
+            only_as_condition: false,
+        }
+    }
+
+    pub fn when(mut self, condition: fn(&Bindings) -> bool) -> Self {
+        self.condition = condition;
+        self
+    }
+
+    pub fn as_condition(mut self) -> Self {
+        self.only_as_condition = true;
+        self
+    }
+
+    fn apply(&self, stmt: &Statement, matcher: &Matcher) -> Option<Statement> {
+        let Statement::CallAssign {
+            assigns,
+            calls,
+            takes,
+            span,
+        } = stmt
+        else {
+            return None;
+        };
+        let [to] = assigns.as_slice() else {
+            return None;
+        };
+        let Pattern::Call(fn_name, args) = &self.pattern else {
+            return None;
+        };
+        if fn_name != calls {
+            return None;
+        }
+
+        let versions: Vec<usize> = takes.iter().map(|value| matcher.version(value)).collect();
+        let mut bindings = Bindings::default();
+        if !matcher.match_args(calls, args, takes, &versions, &mut bindings)
+            || !(self.condition)(&bindings)
+        {
+            return None;
+        }
+
+        let to = to.clone();
+        Some(match &self.replacement {
+            Replacement::Call(calls, args) => Statement::CallAssign {
+                assigns: vec![to],
+                calls: calls.to_string(),
+                takes: args.iter().map(|arg| arg.to_value(&bindings)).collect(),
+                span: *span,
+            },
+            replacement => Statement::ValueAssign {
+                to,
+                value: replacement.to_value(&bindings),
+                span: *span,
+            },
+        })
+    }
+}
+
+impl Replacement {
+    fn to_value(&self, bindings: &Bindings) -> Value {
+        match self {
+            Replacement::Var(var) => bindings.get(var).clone(),
+            Replacement::Word(word) => Value::Literal(to_literal(*word)),
+            Replacement::Computed(compute) => Value::Literal(to_literal(compute(bindings))),
+            Replacement::Call(..) => panic!("Nested call in rule replacement"),
+        }
+    }
+
+    /// Whether every builtin the replacement calls exists in `version`.
+    fn is_available(&self, version: EvmVersion) -> bool {
+        match self {
+            Replacement::Call(fn_name, args) => {
+                version.has_builtin(fn_name) && args.iter().all(|arg| arg.is_available(version))
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Latest definition of a name in the block together with the versions its operands had.
+#[derive(Debug)]
+struct Def {
+    stmt: Statement,
+    arg_versions: Vec<usize>,
+}
+
+#[derive(Debug, Default)]
+struct Matcher {
+    versions: HashMap<Name, usize>,
+    defs: HashMap<Name, Def>,
+}
+
+impl Matcher {
+    fn version(&self, value: &Value) -> usize {
+        match value {
+            Value::RefName(name) => self.versions.get(name).copied().unwrap_or_default(),
+            Value::Literal(_) => 0,
+        }
+    }
+
+    /// Whether `value` still holds what it held when it was `version`.
+    fn is_current(&self, value: &Value, version: usize) -> bool {
+        self.version(value) == version
+    }
+
+    fn match_args(
+        &self,
+        calls: &str,
+        patterns: &[Pattern],
+        takes: &[Value],
+        versions: &[usize],
+        bindings: &mut Bindings,
+    ) -> bool {
+        if patterns.len() != takes.len() {
+            return false;
+        }
+        let mut attempt = bindings.clone();
+        let in_order =
+            patterns
+                .iter()
+                .zip(takes.iter().zip(versions))
+                .all(|(pattern, (value, version))| {
+                    self.match_value(pattern, value, *version, &mut attempt)
+                });
+        if in_order {
+            *bindings = attempt;
+            return true;
+        }
+        if !dialect::is_commutative(calls) || takes.len() != 2 {
+            return false;
+        }
+        let mut attempt = bindings.clone();
+        let swapped = self.match_value(&patterns[0], &takes[1], versions[1], &mut attempt)
+            && self.match_value(&patterns[1], &takes[0], versions[0], &mut attempt);
+        if swapped {
+            *bindings = attempt;
+        }
+        swapped
+    }
+
+    fn match_value(
+        &self,
+        pattern: &Pattern,
+        value: &Value,
+        version: usize,
+        bindings: &mut Bindings,
+    ) -> bool {
+        if !self.is_current(value, version) {
+            return false;
+        }
+        match (pattern, value) {
+            (Pattern::Any(var), value) => bindings.bind(var, value),
+            (Pattern::Const(var), Value::Literal(_)) => bindings.bind(var, value),
+            (Pattern::Word(word), Value::Literal(lit)) => to_word(lit) == *word,
+            (pattern, Value::RefName(name)) => {
+                let Some(def) = self.defs.get(name) else {
+                    return false;
+                };
+                match (pattern, &def.stmt) {
+                    (pattern, Statement::ValueAssign { value, .. }) => {
+                        self.match_value(pattern, value, def.arg_versions[0], bindings)
+                    }
+                    (
+                        Pattern::Call(fn_name, args),
+                        Statement::CallAssign {
+                            assigns,
+                            calls,
+                            takes,
+                            ..
+                        },
+                    ) => {
+                        assigns.len() == 1
+                            && calls == fn_name
+                            && self.match_args(calls, args, takes, &def.arg_versions, bindings)
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn record(&mut self, stmt: &Statement) {
+        let values: Vec<Value> = match stmt {
+            Statement::CallAssign { takes, .. } => takes.clone(),
+            Statement::ValueAssign { value, .. } => vec![value.clone()],
+        };
+        let arg_versions: Vec<usize> = values.iter().map(|value| self.version(value)).collect();
+        for name in stmt.defs() {
+            *self.versions.entry(name.clone()).or_default() += 1;
+            self.defs.insert(
+                name.clone(),
+                Def {
+                    stmt: stmt.clone(),
+                    arg_versions: arg_versions.clone(),
+                },
+            );
+        }
+    }
+}
+
+/// Index of the statement defining the branch condition `cond`, if its value is not used for
+/// anything but the branch.
+fn condition_def(block: &Block, cond: &Name) -> Option<usize> {
+    let def = block
+        .statements
+        .iter()
+        .rposition(|stmt| stmt.defs().contains(&cond))?;
+    let used_after = block.statements[def + 1..]
+        .iter()
+        .any(|stmt| stmt.uses().contains(&cond));
+    let on_stack = block
+        .end_stack
+        .iter()
+        .filter(|name| Name::from(*name) == *cond)
+        .count();
+    (!used_after && on_stack == 1).then_some(def)
+}
+
+fn simplify_block(block: &mut Block, rules: &[Rule], cond: Option<&Name>) -> bool {
+    let cond_def = cond.and_then(|cond| condition_def(block, cond));
+    let mut matcher = Matcher::default();
+    let mut changed = false;
+    for (i, stmt) in block.statements.iter_mut().enumerate() {
+        let rewritten = rules
+            .iter()
+            .filter(|rule| !rule.only_as_condition || cond_def == Some(i))
+            .find_map(|rule| rule.apply(stmt, &matcher));
+        if let Some(rewritten) = rewritten {
+            *stmt = rewritten;
+            changed = true;
+        }
+        matcher.record(stmt);
+    }
+    changed
+}
+
+pub trait Simplification {
+    /// Applies the rules until none matches anymore.
+    fn simplify_with(&mut self, rules: &[Rule]);
+
+    fn simplify(&mut self) {
+        self.simplify_with(&default_rules());
+    }
+
+    fn simplify_for(&mut self, version: EvmVersion) {
+        self.simplify_with(&rules_for(version));
+    }
+}
+
+impl Simplification for Block {
+    fn simplify_with(&mut self, rules: &[Rule]) {
+        while simplify_block(self, rules, None) {}
+    }
+}
+
+impl Simplification for Function {
+    fn simplify_with(&mut self, rules: &[Rule]) {
+        for node in self.nodes.iter_mut() {
+            let cond: Option<Name> = match node.exit {
+                Terminator::Branch { .. } => node.block.end_stack.last().map(|name| name.into()),
+                _ => None,
+            };
+            while simplify_block(&mut node.block, rules, cond.as_ref()) {}
+        }
+    }
+}
+
+impl Simplification for Program {
+    fn simplify_with(&mut self, rules: &[Rule]) {
+        self.main.simplify_with(rules);
+        self.functions
+            .values_mut()
+            .for_each(|function| function.simplify_with(rules));
+    }
+}
+
+fn x() -> Pattern {
+    Pattern::Any("X")
+}
+
+fn a() -> Pattern {
+    Pattern::Const("A")
+}
+
+fn w(word: u64) -> Pattern {
+    Pattern::Word(U256::from(word))
+}
+
+fn ones() -> Pattern {
+    Pattern::Word(U256::MAX)
+}
+
+fn c(fn_name: &'static str, args: Vec<Pattern>) -> Pattern {
+    Pattern::Call(fn_name, args)
+}
+
+fn rx() -> Replacement {
+    Replacement::Var("X")
+}
+
+fn rw(word: u64) -> Replacement {
+    Replacement::Word(U256::from(word))
+}
+
+fn is_power_of_two(bindings: &Bindings) -> bool {
+    bindings.word("A").count_ones() == 1
+}
+
+fn log2(bindings: &Bindings) -> U256 {
+    U256::from(bindings.word("A").trailing_zeros())
+}
+
+/// Algebraic simplifications, commutative builtins also match with swapped arguments.
+pub fn default_rules() -> Vec<Rule> {
+    let mut rules = vec![
+        Rule::new("add-zero", c("add", vec![x(), w(0)]), rx()),
+        Rule::new("sub-zero", c("sub", vec![x(), w(0)]), rx()),
+        Rule::new("sub-self", c("sub", vec![x(), x()]), rw(0)),
+        Rule::new("mul-one", c("mul", vec![x(), w(1)]), rx()),
+        Rule::new("mul-zero", c("mul", vec![x(), w(0)]), rw(0)),
+        Rule::new("div-one", c("div", vec![x(), w(1)]), rx()),
+        Rule::new("zero-div", c("div", vec![w(0), x()]), rw(0)),
+        Rule::new("mod-one", c("mod", vec![x(), w(1)]), rw(0)),
+        Rule::new("and-self", c("and", vec![x(), x()]), rx()),
+        Rule::new("and-zero", c("and", vec![x(), w(0)]), rw(0)),
+        Rule::new("and-ones", c("and", vec![x(), ones()]), rx()),
+        Rule::new(
+            "and-not-zero",
+            c("and", vec![x(), c("not", vec![w(0)])]),
+            rx(),
+        ),
+        Rule::new("or-self", c("or", vec![x(), x()]), rx()),
+        Rule::new("or-zero", c("or", vec![x(), w(0)]), rx()),
+        Rule::new(
+            "or-ones",
+            c("or", vec![x(), ones()]),
+            Replacement::Word(U256::MAX),
+        ),
+        Rule::new("xor-self", c("xor", vec![x(), x()]), rw(0)),
+        Rule::new("xor-zero", c("xor", vec![x(), w(0)]), rx()),
+        Rule::new(
+            "xor-ones",
+            c("xor", vec![x(), ones()]),
+            Replacement::Call("not", vec![rx()]),
+        ),
+        Rule::new(
+            "sub-from-ones",
+            c("sub", vec![ones(), x()]),
+            Replacement::Call("not", vec![rx()]),
+        ),
+        Rule::new("not-not", c("not", vec![c("not", vec![x()])]), rx()),
+        Rule::new("eq-self", c("eq", vec![x(), x()]), rw(1)),
+        Rule::new(
+            "eq-zero",
+            c("eq", vec![x(), w(0)]),
+            Replacement::Call("iszero", vec![rx()]),
+        ),
+        Rule::new(
+            "iszero-iszero-iszero",
+            c("iszero", vec![c("iszero", vec![c("iszero", vec![x()])])]),
+            Replacement::Call("iszero", vec![rx()]),
+        ),
+        Rule::new(
+            "iszero-iszero",
+            c("iszero", vec![c("iszero", vec![x()])]),
+            rx(),
+        )
+        .as_condition(),
+        Rule::new(
+            "mul-pow2",
+            c("mul", vec![x(), a()]),
+            Replacement::Call("shl", vec![Replacement::Computed(log2), rx()]),
+        )
+        .when(is_power_of_two),
+        Rule::new(
+            "div-pow2",
+            c("div", vec![x(), a()]),
+            Replacement::Call("shr", vec![Replacement::Computed(log2), rx()]),
+        )
+        .when(is_power_of_two),
+        Rule::new(
+            "mod-pow2",
+            c("mod", vec![x(), a()]),
+            Replacement::Call(
+                "and",
+                vec![rx(), Replacement::Computed(|b| b.word("A") - U256::from(1))],
+            ),
+        )
+        .when(is_power_of_two),
+        Rule::new("exp-zero", c("exp", vec![x(), w(0)]), rw(1)),
+        Rule::new("exp-one", c("exp", vec![x(), w(1)]), rx()),
+        Rule::new(
+            "exp-two",
+            c("exp", vec![w(2), x()]),
+            Replacement::Call("shl", vec![rx(), rw(1)]),
+        ),
+    ];
+    for fn_name in ["div", "sdiv", "mod", "smod"] {
+        rules.push(Rule::new("by-zero", c(fn_name, vec![x(), w(0)]), rw(0)));
+    }
+    for fn_name in ["lt", "gt", "slt", "sgt"] {
+        rules.push(Rule::new("compare-self", c(fn_name, vec![x(), x()]), rw(0)));
+    }
+    for fn_name in ["shl", "shr", "sar"] {
+        rules.push(Rule::new("shift-zero", c(fn_name, vec![w(0), x()]), rx()));
+    }
+    for fn_name in ["shl", "shr"] {
+        rules.push(
+            Rule::new("shift-overflow", c(fn_name, vec![a(), x()]), rw(0))
+                .when(|b| b.word("A") >= U256::from(256)),
+        );
+    }
+    rules
+}
+
+/// The default rules whose replacements only call builtins `version` has, so no `shl` is
+/// introduced before Constantinople.
+pub fn rules_for(version: EvmVersion) -> Vec<Rule> {
+    default_rules()
+        .into_iter()
+        .filter(|rule| rule.replacement.is_available(version))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cfg::Node;
+    use crate::stack_scheduler::test::{block, call, lit, r};
+
+    fn rule(name: &str) -> Vec<Rule> {
+        default_rules()
+            .into_iter()
+            .filter(|rule| rule.name == name)
+            .collect()
+    }
+
+    fn assert_copy(stmt: &Statement, from: &str) {
+        assert!(
+            matches!(stmt, Statement::ValueAssign { value: Value::RefName(Name::Ident(name)), .. } if name == from),
+            "{:?} is not a copy of {}",
+            stmt,
+            from
+        );
+    }
+
+    #[test]
+    fn test_sub_self() {
+        let mut bb = block(vec![call(&["x"], "sub", vec![r("a"), r("a")])], &["x"]);
+        bb.simplify_with(&rule("sub-self"));
+        assert!(matches!(
+            &bb.statements[0],
+            Statement::ValueAssign { value: Value::Literal(l), .. } if *l == [0u8; 32]
+        ));
+
+        let mut bb = block(vec![call(&["x"], "sub", vec![r("a"), r("b")])], &["x"]);
+        bb.simplify_with(&rule("sub-self"));
+        assert!(matches!(&bb.statements[0], Statement::CallAssign { .. }));
+    }
+
+    #[test]
+    fn test_and_not_zero() {
+        let mut bb = block(
+            vec![
+                call(&["m"], "not", vec![lit(0)]),
+                call(&["x"], "and", vec![r("m"), r("a")]),
+            ],
+            &["x"],
+        );
+        bb.simplify_with(&rule("and-not-zero"));
+        assert_copy(&bb.statements[1], "a");
+    }
+
+    #[test]
+    fn test_mul_pow2() {
+        let mut bb = block(
+            vec![
+                call(&["x"], "mul", vec![lit(32), r("a")]),
+                call(&["y"], "mul", vec![lit(24), r("a")]),
+            ],
+            &["x", "y"],
+        );
+        bb.simplify_with(&rule("mul-pow2"));
+        assert!(matches!(
+            &bb.statements[0],
+            Statement::CallAssign { calls, takes, .. }
+                if calls == "shl" && matches!(takes[0], Value::Literal(l) if l == to_literal(U256::from(5)))
+        ));
+        assert!(matches!(&bb.statements[1], Statement::CallAssign { calls, .. } if calls == "mul"));
+    }
+
+    #[test]
+    fn test_no_shifts_before_constantinople() {
+        let mut bb = block(vec![call(&["x"], "div", vec![r("a"), lit(8)])], &["x"]);
+        bb.simplify_for(EvmVersion::Byzantium);
+        assert!(matches!(&bb.statements[0], Statement::CallAssign { calls, .. } if calls == "div"));
+        bb.simplify_for(EvmVersion::Constantinople);
+        assert!(matches!(&bb.statements[0], Statement::CallAssign { calls, .. } if calls == "shr"));
+        assert!(rules_for(EvmVersion::Byzantium)
+            .iter()
+            .all(|rule| rule.name != "exp-two"));
+    }
+
+    #[test]
+    fn test_respects_reassignment() {
+        let mut bb = block(
+            vec![
+                call(&["n"], "not", vec![r("a")]),
+                call(&["a"], "add", vec![r("a"), lit(1)]),
+                call(&["x"], "not", vec![r("n")]),
+            ],
+            &["x"],
+        );
+        bb.simplify_with(&rule("not-not"));
+        assert!(matches!(&bb.statements[2], Statement::CallAssign { .. }));
+    }
+
+    #[test]
+    fn test_iszero_iszero_only_as_condition() {
+        let statements = vec![
+            call(&["i"], "iszero", vec![r("a")]),
+            call(&["c"], "iszero", vec![r("i")]),
+        ];
+
+        let mut bb = block(statements.clone(), &["a", "c"]);
+        bb.simplify();
+        assert!(matches!(&bb.statements[1], Statement::CallAssign { .. }));
+
+        let mut function = Function {
+            nodes: vec![Node {
+                block: block(statements, &["a", "c"]),
+                exit: Terminator::Branch {
+                    non_zero: 0,
+                    zero: 0,
+                },
+            }],
+        };
+        function.simplify();
+        assert_copy(&function.nodes[0].block.statements[1], "a");
+    }
+
+    #[test]
+    fn test_fixpoint() {
+        // x := mul(sub(b, sub(a, a)), 8)
+        let mut bb = block(
+            vec![
+                call(&["t0"], "sub", vec![r("a"), r("a")]),
+                call(&["t1"], "sub", vec![r("b"), r("t0")]),
+                call(&["x"], "mul", vec![r("t1"), lit(8)]),
+            ],
+            &["x"],
+        );
+        bb.simplify();
+        assert_copy(&bb.statements[1], "b");
+        assert!(matches!(
+            &bb.statements[2],
+            Statement::CallAssign { calls, takes, .. } if calls == "shl" && matches!(&takes[1], Value::RefName(Name::Ident(n)) if n == "t1")
+        ));
+    }
+}
@@ -2,9 +2,13 @@ use crate::ssa_block::{Block, Name, Statement, Value};
 use ir::Literal;
 use std::collections::HashMap;
 
+/// Stack depths are 1-based like the EVM's `DUPn`/`SWAPn`. Calls take their first argument
+/// from the top of the stack and leave their last return value on top.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Op<'a> {
+    /// Exchanges the top with the item `n` below it.
     Swap(usize),
+    /// Pushes a copy of the item at depth `n`.
     Dup(usize),
     Pop,
     Push(Literal),
@@ -16,12 +20,16 @@ pub enum Op<'a> {
 }
 
 pub trait MemoryScheduler {
-    fn schedule_memory(&self) -> (usize, Vec<Op>);
+    fn schedule_memory(&self) -> (usize, Vec<Op<'_>>);
+    /// Like `schedule_memory`, but keeps the names in `slots` in the given slots, so a slot
+    /// allocation shared across blocks can be followed.
+    fn schedule_memory_with(&self, slots: &HashMap<Name, usize>) -> (usize, Vec<Op<'_>>);
 }
 
 struct MemoryAsRegisters {
     slots: Vec<Option<Name>>,
     remaining_ref_counts: HashMap<Name, usize>,
+    fixed: HashMap<Name, usize>,
 }
 
 impl MemoryAsRegisters {
@@ -59,17 +67,33 @@ impl MemoryAsRegisters {
         slot.map(|(i, _)| i)
     }
 
+    /// Moves `name` into the slot at `loc`, whose value it takes over without a copy.
+    fn rename(&mut self, loc: usize, name: &Name) {
+        if let Some(old_loc) = self.get_loc(name) {
+            self.slots[old_loc] = None;
+        }
+        self.slots[loc] = Some(name.clone());
+    }
+
     fn get_or_assign_loc(&mut self, name: &Name) -> usize {
         let slot = self.get_loc(name);
         if let Some(slot) = slot {
             return slot;
         }
+        if let Some(slot) = self.fixed.get(name).copied() {
+            if self.slots.len() <= slot {
+                self.slots.resize(slot + 1, None);
+            }
+            self.slots[slot] = Some(name.clone());
+            return slot;
+        }
 
+        let is_fixed = |i: usize| self.fixed.values().any(|slot| *slot == i);
         match self
             .slots
             .iter()
             .enumerate()
-            .find(|(_, stored_name)| stored_name.is_none())
+            .find(|(i, stored_name)| stored_name.is_none() && !is_fixed(*i))
         {
             Some((i, _)) => {
                 self.slots[i] = Some(name.clone());
@@ -96,11 +120,12 @@ impl From<&Block> for MemoryAsRegisters {
 
         for stmt in value.statements.iter() {
             match stmt {
-                Statement::ValueAssign { to: _, value } => inc_value_count(&mut counts, value),
+                Statement::ValueAssign { to: _, value, .. } => inc_value_count(&mut counts, value),
                 Statement::CallAssign {
                     assigns: _,
                     calls: _,
                     takes,
+                    ..
                 } => takes
                     .iter()
                     .for_each(|value| inc_value_count(&mut counts, value)),
@@ -114,13 +139,33 @@ impl From<&Block> for MemoryAsRegisters {
         Self {
             remaining_ref_counts: counts,
             slots: vec![],
+            fixed: HashMap::new(),
+        }
+    }
+}
+
+/// Whether the value `name` holds at statement `i` is not used after it, because the name is
+/// reassigned first or not used at all.
+fn dies_after(block: &Block, i: usize, name: &Name) -> bool {
+    for stmt in block.statements[i + 1..].iter() {
+        if stmt.uses().contains(&name) {
+            return false;
+        }
+        if stmt.defs().contains(&name) {
+            return true;
         }
     }
+    !block.end_stack.iter().any(|out| Name::from(out) == *name)
 }
 
 impl MemoryScheduler for Block {
-    fn schedule_memory(&self) -> (usize, Vec<Op>) {
+    fn schedule_memory(&self) -> (usize, Vec<Op<'_>>) {
+        self.schedule_memory_with(&HashMap::new())
+    }
+
+    fn schedule_memory_with(&self, slots: &HashMap<Name, usize>) -> (usize, Vec<Op<'_>>) {
         let mut memory: MemoryAsRegisters = self.into();
+        memory.fixed = slots.clone();
         let mut ops: Vec<Op> = vec![];
 
         self.start_stack.iter().rev().for_each(|name| {
@@ -133,9 +178,9 @@ impl MemoryScheduler for Block {
             }
         });
 
-        for stmt in self.statements.iter() {
+        for (i, stmt) in self.statements.iter().enumerate() {
             match stmt {
-                Statement::ValueAssign { to, value } => match value {
+                Statement::ValueAssign { to, value, .. } => match value {
                     Value::Literal(lit) => {
                         if *memory.get_rem_ref_count(to) > 0 {
                             ops.extend([
@@ -144,13 +189,24 @@ impl MemoryScheduler for Block {
                             ]);
                         }
                     }
+                    Value::RefName(name) if name == to => {
+                        memory.use_reference(name);
+                    }
                     Value::RefName(name) => {
-                        if *memory.get_rem_ref_count(&name) > 0 {
-                            let from_loc = memory.use_reference(name);
-                            ops.push(Op::MemCopy {
-                                from: from_loc,
-                                to: memory.get_or_assign_loc(to),
-                            });
+                        let from_loc = memory.use_reference(name);
+                        if *memory.get_rem_ref_count(to) > 0 {
+                            let coalesced = match memory.fixed.get(to) {
+                                Some(to_loc) => *to_loc == from_loc,
+                                None => true,
+                            };
+                            if coalesced && dies_after(self, i, name) {
+                                memory.rename(from_loc, to);
+                            } else {
+                                ops.push(Op::MemCopy {
+                                    from: from_loc,
+                                    to: memory.get_or_assign_loc(to),
+                                });
+                            }
                         }
                     }
                 },
@@ -158,6 +214,7 @@ impl MemoryScheduler for Block {
                     assigns,
                     calls,
                     takes,
+                    ..
                 } => {
                     takes.iter().rev().for_each(|value| match value {
                         Value::Literal(lit) => ops.push(Op::Push(*lit)),
@@ -168,10 +225,7 @@ impl MemoryScheduler for Block {
                     ops.push(Op::CallFn(calls));
                     assigns.iter().rev().for_each(|name| {
                         if *memory.get_rem_ref_count(name) > 0 {
-                            println!("name: {:?}", name);
-                            dbg!(&memory.slots);
                             ops.push(Op::MemVarStore(memory.get_or_assign_loc(name)));
-                            dbg!(&memory.slots);
                         } else {
                             ops.push(Op::Pop);
                         }
@@ -180,13 +234,152 @@ impl MemoryScheduler for Block {
             }
         }
 
-        dbg!(&memory.slots);
-
         self.end_stack.iter().for_each(|name| {
             let loc = memory.use_reference(&name.into());
             ops.push(Op::MemVarLoad(loc));
         });
 
-        (memory.len(), ops)
+        (memory.len(), simplify_memory_ops(&ops))
+    }
+}
+
+/// Whether the value in `slot` is read again before being overwritten.
+fn is_read_later(ops: &[Op], slot: usize) -> bool {
+    for op in ops {
+        match op {
+            Op::MemVarLoad(s) | Op::MemCopy { from: s, .. } if *s == slot => return true,
+            Op::MemSwap(a, b) if *a == slot || *b == slot => return true,
+            Op::MemVarStore(s) | Op::MemCopy { to: s, .. } if *s == slot => return false,
+            _ => (),
+        }
+    }
+    false
+}
+
+/// Removes loads of a slot stored right back, stores of a value right loaded again and never
+/// read from the slot afterwards, and turns two loads stored back crosswise into a `MemSwap`.
+pub fn simplify_memory_ops<'a>(ops: &[Op<'a>]) -> Vec<Op<'a>> {
+    let mut ops = ops.to_vec();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let mut i = 0;
+        while i < ops.len() {
+            let window = &ops[i..ops.len().min(i + 4)];
+            let (remove, replacement) = match window {
+                [Op::MemVarLoad(a), Op::MemVarStore(b), ..] if a == b => (2, None),
+                [Op::MemVarStore(a), Op::MemVarLoad(b), ..]
+                    if a == b && !is_read_later(&ops[i + 2..], *a) =>
+                {
+                    (2, None)
+                }
+                [Op::MemVarLoad(a), Op::MemVarLoad(b), Op::MemVarStore(c), Op::MemVarStore(d)]
+                    if a == c && b == d && a != b =>
+                {
+                    (4, Some(Op::MemSwap(*a, *b)))
+                }
+                _ => (0, None),
+            };
+            if remove == 0 {
+                i += 1;
+                continue;
+            }
+            ops.splice(i..i + remove, replacement);
+            changed = true;
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stack_scheduler::test::{
+        assert_schedules_equivalently, block, call, interpret, lit, names, r, random_blocks, run,
+    };
+
+    #[test]
+    fn test_schedule_memory_random() {
+        for (seed, block) in random_blocks(500) {
+            let (_, ops) = block.schedule_memory();
+            assert_schedules_equivalently(seed, &block, &ops);
+        }
+    }
+
+    #[test]
+    fn test_schedule_memory_coalesces_copies() {
+        // t := a  a := b  b := t swaps without touching memory.
+        let copy = |to: &str, from: &str| Statement::ValueAssign {
+            to: to.to_owned().into(),
+            value: r(from),
+            span: None,
+        };
+        let block = Block {
+            start_stack: names(&["a", "b"]),
+            statements: vec![copy("t", "a"), copy("a", "b"), copy("b", "t")],
+            end_stack: names(&["a", "b"]),
+        };
+        let (slots, ops) = block.schedule_memory();
+        assert_eq!(slots, 2);
+        assert!(ops.iter().all(|op| !matches!(op, Op::MemCopy { .. })));
+        assert_eq!(run(&block, &ops, &[1, 2]), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_schedule_memory_self_assignment() {
+        // x := add(a, b)  y := add(x, 1)  x := x  z := add(x, y)  w := add(z, 1)  v := add(z, w)
+        let statements = |self_assign: bool| {
+            let mut statements = vec![
+                call(&["x"], "add", vec![r("a"), r("b")]),
+                call(&["y"], "add", vec![r("x"), lit(1)]),
+                call(&["z"], "add", vec![r("x"), r("y")]),
+                call(&["w"], "add", vec![r("z"), lit(1)]),
+                call(&["v"], "add", vec![r("z"), r("w")]),
+            ];
+            if self_assign {
+                statements.insert(
+                    2,
+                    Statement::ValueAssign {
+                        to: "x".to_owned().into(),
+                        value: r("x"),
+                        span: None,
+                    },
+                );
+            }
+            statements
+        };
+        let plain = block(statements(false), &["v"]);
+        let block = block(statements(true), &["v"]);
+        let (slots, ops) = block.schedule_memory();
+        assert_eq!(slots, plain.schedule_memory().0);
+        assert_eq!(run(&block, &ops, &[1, 2]), interpret(&block, &[1, 2]));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_simplify_memory_ops() {
+        let ops = [
+            Op::MemVarLoad(0),
+            Op::MemVarStore(0),
+            Op::MemVarStore(1),
+            Op::MemVarLoad(1),
+            Op::MemVarStore(2),
+            Op::MemVarLoad(2),
+            Op::CallFn("f"),
+            Op::MemVarLoad(3),
+            Op::MemVarLoad(4),
+            Op::MemVarStore(3),
+            Op::MemVarStore(4),
+            Op::MemVarLoad(2),
+        ];
+        assert_eq!(
+            simplify_memory_ops(&ops),
+            vec![
+                Op::MemVarStore(2),
+                Op::MemVarLoad(2),
+                Op::CallFn("f"),
+                Op::MemSwap(3, 4),
+                Op::MemVarLoad(2),
+            ]
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use crate::scheduler::Op;
+use crate::ssa_block::Name;
+use crate::stack_scheduler::{Stack, StackTooDeep, MAX_REACH};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+const DUP_GAS: usize = 3;
+const SWAP_GAS: usize = 3;
+const POP_GAS: usize = 2;
+const LOAD_GAS: usize = 6;
+
+/// Bounds on the search for an optimal shuffle, beyond which the greedy shuffle is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShuffleLimits {
+    /// Longest source or target layout searched.
+    pub max_search_len: usize,
+    /// Number of stack layouts visited before giving up on the search.
+    pub max_states: usize,
+}
+
+impl Default for ShuffleLimits {
+    fn default() -> Self {
+        Self {
+            max_search_len: 8,
+            max_states: 10_000,
+        }
+    }
+}
+
+/// Cheapest `Dup`/`Swap`/`Pop` sequence turning the stack `from` into exactly `to`, last item
+/// being the top. Panics if a name in `to` is not in `from` or is out of reach.
+pub fn shuffle(from: &[Name], to: &[Name]) -> Vec<Op<'static>> {
+    try_shuffle(from, to, &ShuffleLimits::default())
+        .unwrap_or_else(|err| panic!("Cannot shuffle {:?} to {:?}: {:?}", from, to, err))
+}
+
+pub fn try_shuffle(
+    from: &[Name],
+    to: &[Name],
+    limits: &ShuffleLimits,
+) -> Result<Vec<Op<'static>>, StackTooDeep> {
+    let mut stack = Stack::new(from);
+    stack.shuffle_within(to, limits)?;
+    Ok(stack.ops)
+}
+
+/// Stack layout over name ids. Dead slots share an id that is never wanted.
+type Layout = Vec<u8>;
+
+struct Search<'s> {
+    target: Layout,
+    wanted: Vec<usize>,
+    /// Memory slot of each spilled id, loadable from any depth.
+    loads: Vec<(u8, usize)>,
+    max_len: usize,
+    limits: &'s ShuffleLimits,
+}
+
+impl Search<'_> {
+    /// Lower bound on the gas still needed: every missing copy takes at least a `Dup` and
+    /// every surplus item a `Pop`. Also every slot differing from the target has to change,
+    /// and no op changes more than the top and one other slot, at 1.5 gas per slot for a
+    /// `Swap`.
+    fn estimate(&self, layout: &Layout) -> usize {
+        let mut counts = vec![0; self.wanted.len()];
+        layout.iter().for_each(|id| counts[*id as usize] += 1);
+        let by_count = counts
+            .iter()
+            .zip(self.wanted.iter())
+            .map(|(count, wanted)| match count < wanted {
+                true => (wanted - count) * DUP_GAS,
+                false => (count - wanted) * POP_GAS,
+            })
+            .sum();
+        let differing = (0..layout.len().max(self.target.len()))
+            .filter(|i| layout.get(*i) != self.target.get(*i))
+            .count();
+        (differing * SWAP_GAS).div_ceil(2).max(by_count)
+    }
+
+    fn moves(&self, layout: &Layout) -> Vec<(Op<'static>, usize, Layout)> {
+        let len = layout.len();
+        let mut moves = vec![];
+        if len > 0 {
+            let mut next = layout.clone();
+            next.pop();
+            moves.push((Op::Pop, POP_GAS, next));
+        }
+        for n in 1..len.min(MAX_REACH + 1) {
+            let mut next = layout.clone();
+            next.swap(len - 1, len - 1 - n);
+            moves.push((Op::Swap(n), SWAP_GAS, next));
+        }
+        if len < self.max_len {
+            let short = |id: u8| {
+                layout.iter().filter(|other| **other == id).count() < self.wanted[id as usize]
+            };
+            for depth in 1..=len.min(MAX_REACH) {
+                let id = layout[len - depth];
+                if short(id) {
+                    let mut next = layout.clone();
+                    next.push(id);
+                    moves.push((Op::Dup(depth), DUP_GAS, next));
+                }
+            }
+            for (id, slot) in self.loads.iter().filter(|(id, _)| short(*id)) {
+                let mut next = layout.clone();
+                next.push(*id);
+                moves.push((Op::MemVarLoad(*slot), LOAD_GAS, next));
+            }
+        }
+        moves
+    }
+
+    /// A* over stack layouts, `None` if the target is not found within the state limit.
+    fn run(&self, start: Layout) -> Option<Vec<Op<'static>>> {
+        let mut layouts: Vec<(Layout, Option<(usize, Op<'static>)>)> = vec![];
+        let mut best: HashMap<Layout, usize> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        queue.push(Reverse((self.estimate(&start), 0, 0)));
+        best.insert(start.clone(), 0);
+        layouts.push((start, None));
+
+        while let Some(Reverse((_, gas, i))) = queue.pop() {
+            if best[&layouts[i].0] < gas {
+                continue;
+            }
+            if layouts[i].0 == self.target {
+                let mut ops = vec![];
+                let mut at = i;
+                while let Some((parent, op)) = layouts[at].1.clone() {
+                    ops.push(op);
+                    at = parent;
+                }
+                ops.reverse();
+                return Some(ops);
+            }
+            for (op, cost, next) in self.moves(&layouts[i].0) {
+                let gas = gas + cost;
+                if best.get(&next).is_some_and(|known| *known <= gas) {
+                    continue;
+                }
+                if layouts.len() >= self.limits.max_states {
+                    return None;
+                }
+                best.insert(next.clone(), gas);
+                queue.push(Reverse((gas + self.estimate(&next), gas, layouts.len())));
+                layouts.push((next, Some((i, op))));
+            }
+        }
+        None
+    }
+}
+
+/// Searches for the cheapest shuffle from `slots` to `target`, `None` if the layouts are too
+/// long or the search exceeds `limits`.
+pub(crate) fn search(
+    slots: &[Option<Name>],
+    target: &[Name],
+    spills: &HashMap<Name, usize>,
+    limits: &ShuffleLimits,
+) -> Option<Vec<Op<'static>>> {
+    if slots.len() > limits.max_search_len || target.len() > limits.max_search_len {
+        return None;
+    }
+    let mut names: Vec<&Name> = slots.iter().flatten().chain(target).collect();
+    names.sort();
+    names.dedup();
+    if names.len() >= u8::MAX as usize {
+        return None;
+    }
+    let id = |name: &Name| names.binary_search(&name).unwrap() as u8;
+    let dead = names.len() as u8;
+
+    let mut wanted = vec![0; names.len() + 1];
+    target
+        .iter()
+        .for_each(|name| wanted[id(name) as usize] += 1);
+    let start: Layout = slots
+        .iter()
+        .map(|slot| slot.as_ref().map_or(dead, id))
+        .collect();
+    let loads = spills
+        .iter()
+        .filter(|(name, _)| names.binary_search(name).is_ok())
+        .map(|(name, slot)| (id(name), *slot))
+        .filter(|(id, _)| wanted[*id as usize] > 0)
+        .collect();
+    let search = Search {
+        target: target.iter().map(id).collect(),
+        wanted,
+        loads,
+        max_len: slots.len().max(target.len()) + 1,
+        limits,
+    };
+    search.run(start)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(s: &str) -> Vec<Name> {
+        s.chars().map(|c| Name::Ident(c.to_string())).collect()
+    }
+
+    fn apply(from: &[Name], ops: &[Op]) -> Vec<Name> {
+        let mut stack = from.to_vec();
+        for op in ops {
+            let top = stack.len() - 1;
+            match op {
+                Op::Swap(n) => stack.swap(top, top - n),
+                Op::Dup(n) => stack.push(stack[stack.len() - n].clone()),
+                Op::Pop => {
+                    stack.pop();
+                }
+                other => panic!("Unexpected {:?}", other),
+            }
+        }
+        stack
+    }
+
+    fn gas(ops: &[Op]) -> usize {
+        ops.iter()
+            .map(|op| match op {
+                Op::Pop => POP_GAS,
+                _ => DUP_GAS,
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_shuffle_optimal() {
+        assert_eq!(shuffle(&names("ab"), &names("ba")), vec![Op::Swap(1)]);
+        assert_eq!(shuffle(&names("abc"), &names("cba")), vec![Op::Swap(2)]);
+        assert_eq!(shuffle(&names("abc"), &names("ab")), vec![Op::Pop]);
+        assert_eq!(
+            shuffle(&names("abc"), &names("bc")),
+            vec![Op::Swap(2), Op::Pop, Op::Swap(1)]
+        );
+        assert_eq!(shuffle(&names("ab"), &names("aba")), vec![Op::Dup(2)]);
+        assert_eq!(shuffle(&names("abc"), &names("bca")).len(), 2);
+    }
+
+    #[test]
+    fn test_shuffle_beats_greedy() {
+        let layouts = [
+            "abc", "abcd", "abcde", "aabc", "abcb", "cab", "ca", "dcba", "bbaa",
+        ];
+        for from in layouts {
+            for to in layouts {
+                let (from, to) = (names(from), names(to));
+                if to.iter().any(|name| !from.contains(name)) {
+                    continue;
+                }
+                let ops = shuffle(&from, &to);
+                assert_eq!(apply(&from, &ops), to);
+
+                let mut greedy = Stack::new(&from);
+                greedy.shuffle_greedy(&to).unwrap();
+                assert!(
+                    gas(&ops) <= gas(&greedy.ops),
+                    "{:?} to {:?}: {:?} vs {:?}",
+                    from,
+                    to,
+                    ops,
+                    greedy.ops
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_shuffle_falls_back_to_greedy() {
+        let from: Vec<Name> = (0..12).map(Name::Intermed).collect();
+        let to: Vec<Name> = from.iter().rev().cloned().collect();
+        let limits = ShuffleLimits::default();
+        assert!(search(
+            &from.iter().cloned().map(Some).collect::<Vec<_>>(),
+            &to,
+            &HashMap::new(),
+            &limits
+        )
+        .is_none());
+        assert_eq!(apply(&from, &shuffle(&from, &to)), to);
+
+        let small = ShuffleLimits {
+            max_states: 10,
+            ..limits
+        };
+        let (from, to) = (names("abcde"), names("edcba"));
+        let ops = try_shuffle(&from, &to, &small).unwrap();
+        assert_eq!(apply(&from, &ops), to);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::cfg::{BlockId, Function};
+use crate::ssa_block::{Name, Statement, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// A value kept in a memory slot. Identifiers get one slot for the whole function, while
+/// intermediates only exist within their block.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Var {
+    Ident(String),
+    Intermed(BlockId, usize),
+}
+
+impl Var {
+    fn new(block: BlockId, name: &Name) -> Self {
+        match name {
+            Name::Ident(name) => Var::Ident(name.clone()),
+            Name::Intermed(id) => Var::Intermed(block, *id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SlotAllocation {
+    pub count: usize,
+    pub slots: BTreeMap<Var, usize>,
+}
+
+impl SlotAllocation {
+    /// Slots of the names used in `block`, as taken by `schedule_memory_with`.
+    pub fn block_slots(&self, block: BlockId) -> HashMap<Name, usize> {
+        self.slots
+            .iter()
+            .filter_map(|(var, slot)| match var {
+                Var::Ident(name) => Some((Name::Ident(name.clone()), *slot)),
+                Var::Intermed(of, id) if *of == block => Some((Name::Intermed(*id), *slot)),
+                Var::Intermed(..) => None,
+            })
+            .collect()
+    }
+}
+
+pub trait SlotAllocator {
+    fn allocate_slots(&self) -> SlotAllocation;
+}
+
+#[derive(Debug, Default)]
+struct InterferenceGraph {
+    edges: BTreeMap<Var, BTreeSet<Var>>,
+    /// Copy related variables, preferably sharing a slot.
+    hints: BTreeMap<Var, BTreeSet<Var>>,
+}
+
+impl InterferenceGraph {
+    fn add(&mut self, var: &Var) {
+        self.edges.entry(var.clone()).or_default();
+    }
+
+    fn interfere(&mut self, a: &Var, b: &Var) {
+        if a != b {
+            self.edges.entry(a.clone()).or_default().insert(b.clone());
+            self.edges.entry(b.clone()).or_default().insert(a.clone());
+        }
+    }
+
+    fn clique(&mut self, vars: &BTreeSet<Var>) {
+        for a in vars.iter() {
+            self.add(a);
+            vars.iter().for_each(|b| self.interfere(a, b));
+        }
+    }
+
+    fn hint(&mut self, a: &Var, b: &Var) {
+        self.hints.entry(a.clone()).or_default().insert(b.clone());
+        self.hints.entry(b.clone()).or_default().insert(a.clone());
+    }
+
+    /// Greedy coloring, most constrained variables first. A variable takes the slot of a copy
+    /// related variable if it can, otherwise the lowest slot none of its neighbors has.
+    fn color(&self) -> SlotAllocation {
+        let mut order: Vec<&Var> = self.edges.keys().collect();
+        order.sort_by_key(|var| std::cmp::Reverse(self.edges[*var].len()));
+
+        let mut slots: BTreeMap<Var, usize> = BTreeMap::new();
+        for var in order {
+            let taken: BTreeSet<usize> = self.edges[var]
+                .iter()
+                .filter_map(|other| slots.get(other).copied())
+                .collect();
+            let hinted = self
+                .hints
+                .get(var)
+                .into_iter()
+                .flatten()
+                .filter_map(|other| slots.get(other).copied())
+                .filter(|slot| !taken.contains(slot))
+                .min();
+            let slot = hinted.unwrap_or_else(|| (0..).find(|slot| !taken.contains(slot)).unwrap());
+            slots.insert(var.clone(), slot);
+        }
+        SlotAllocation {
+            count: slots.values().max().map_or(0, |max| max + 1),
+            slots,
+        }
+    }
+}
+
+impl SlotAllocator for Function {
+    /// Builds the interference graph of all values the memory scheduler stores in the
+    /// function's blocks: the used part of the `start_stack`, stored at once on entry, and every
+    /// definition, which interferes with everything live after it. A copy whose
+    /// source dies at it creates no interference, and the two are hinted to share a slot.
+    fn allocate_slots(&self) -> SlotAllocation {
+        let mut graph = InterferenceGraph::default();
+        for (id, node) in self.nodes.iter().enumerate() {
+            let block = &node.block;
+            let var = |name: &Name| Var::new(id, name);
+            let mut live: BTreeSet<Var> = block
+                .end_stack
+                .iter()
+                .map(|name| var(&name.into()))
+                .collect();
+            graph.clique(&live);
+
+            for stmt in block.statements.iter().rev() {
+                // Dead definitions are stored too when their name is used elsewhere, so they
+                // interfere as well.
+                for def in stmt.defs().into_iter().map(var) {
+                    graph.add(&def);
+                    live.iter().for_each(|other| graph.interfere(&def, other));
+                }
+                if let Statement::ValueAssign {
+                    to,
+                    value: Value::RefName(from),
+                    ..
+                } = stmt
+                {
+                    graph.hint(&var(to), &var(from));
+                }
+                stmt.defs().into_iter().for_each(|def| {
+                    live.remove(&var(def));
+                });
+                live.extend(stmt.uses().into_iter().map(var));
+            }
+
+            let used: BTreeSet<Name> = block
+                .statements
+                .iter()
+                .flat_map(|stmt| stmt.uses().into_iter().cloned())
+                .chain(block.end_stack.iter().map(|name| name.into()))
+                .collect();
+            live.extend(
+                block
+                    .start_stack
+                    .iter()
+                    .map(Name::from)
+                    .filter(|name| used.contains(name))
+                    .map(|name| var(&name)),
+            );
+            graph.clique(&live);
+        }
+        graph.color()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cfg::{Node, Terminator};
+    use crate::scheduler::{MemoryScheduler, Op};
+    use crate::ssa_block::Block;
+    use crate::stack_scheduler::test::{
+        assert_schedules_equivalently, block, call, names, r, random_block,
+    };
+
+    fn function(blocks: Vec<Block>) -> Function {
+        let n = blocks.len();
+        Function {
+            nodes: blocks
+                .into_iter()
+                .enumerate()
+                .map(|(id, block)| Node {
+                    block,
+                    exit: match id + 1 < n {
+                        true => Terminator::Jump(id + 1),
+                        false => Terminator::Halt,
+                    },
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_allocate_slots_random() {
+        for seed in 0..500 {
+            let blocks: Vec<Block> = (0..3)
+                .map(|i| random_block(seed * 3 + i, (seed % 12) as usize))
+                .collect();
+            let function = function(blocks);
+            let allocation = function.allocate_slots();
+            for (id, node) in function.nodes.iter().enumerate() {
+                let (slots, ops) = node.block.schedule_memory_with(&allocation.block_slots(id));
+                assert!(slots <= allocation.count.max(1));
+                assert_schedules_equivalently(seed, &node.block, &ops);
+            }
+        }
+    }
+
+    #[test]
+    fn test_allocate_slots_coalesces_copies() {
+        let block = block(
+            vec![
+                call(&["x"], "add", vec![r("a"), r("b")]),
+                Statement::ValueAssign {
+                    to: "y".to_owned().into(),
+                    value: r("x"),
+                    span: None,
+                },
+                call(&["z"], "mul", vec![r("y"), r("b")]),
+            ],
+            &["z"],
+        );
+        let function = function(vec![block]);
+        let allocation = function.allocate_slots();
+        let slot = |name: &str| allocation.slots[&Var::Ident(name.to_owned())];
+        assert_eq!(allocation.count, 2);
+        assert_eq!(slot("x"), slot("y"));
+        assert_ne!(slot("y"), slot("b"));
+
+        let (_, ops) = function.nodes[0]
+            .block
+            .schedule_memory_with(&allocation.block_slots(0));
+        assert!(ops.iter().all(|op| !matches!(op, Op::MemCopy { .. })));
+    }
+
+    #[test]
+    fn test_allocate_slots_across_blocks() {
+        // `a` and `b` are live together only in the first block, `b` and `c` only in the
+        // second, so `a` and `c` share a slot.
+        let first = block(vec![call(&[], "sstore", vec![r("a"), r("b")])], &["b"]);
+        let second = Block {
+            start_stack: names(&["b"]),
+            statements: vec![
+                call(&["c"], "sload", vec![r("b")]),
+                call(&[], "sstore", vec![r("b"), r("c")]),
+            ],
+            end_stack: vec![],
+        };
+        let allocation = function(vec![first, second]).allocate_slots();
+        let slot = |name: &str| allocation.slots[&Var::Ident(name.to_owned())];
+        assert_eq!(allocation.count, 2);
+        assert_eq!(slot("a"), slot("c"));
+        assert_ne!(slot("a"), slot("b"));
+    }
+}
@@ -0,0 +1,164 @@
+use crate::scheduler::Op;
+use crate::ssa_block::{Block, Statement};
+use ir::Span;
+use std::fmt::Write;
+
+/// The `j` field of a source map entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Jump {
+    /// Any instruction but the jumps below, `-`.
+    #[default]
+    Regular,
+    /// The jump calling an internal function, `i`.
+    Into,
+    /// The jump returning from an internal function, `o`.
+    Out,
+}
+
+impl Jump {
+    fn symbol(&self) -> char {
+        match self {
+            Jump::Regular => '-',
+            Jump::Into => 'i',
+            Jump::Out => 'o',
+        }
+    }
+}
+
+/// Source location of one instruction of the bytecode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SourceLocation {
+    pub span: Option<Span>,
+    pub jump: Jump,
+}
+
+/// Index of the statement each op works towards: the call it is the `CallFn` of or prepares the
+/// operands of. Ops after the last call, which arrange the end stack, belong to the last
+/// statement.
+pub fn op_statements(block: &Block, ops: &[Op]) -> Vec<Option<usize>> {
+    let calls: Vec<usize> = block
+        .statements
+        .iter()
+        .enumerate()
+        .filter(|(_, stmt)| matches!(stmt, Statement::CallAssign { .. }))
+        .map(|(i, _)| i)
+        .collect();
+    let last = block.statements.len().checked_sub(1);
+    let mut statements = vec![];
+    let mut next = 0;
+    for op in ops {
+        statements.push(calls.get(next).copied().or(last));
+        if let Op::CallFn(_) = op {
+            next += 1;
+        }
+    }
+    statements
+}
+
+/// Encodes the locations of the instructions in solc's compressed `s:l:f:j:m` format. Entries
+/// are separated by `;`, fields equal to those of the previous entry are left empty and
+/// trailing empty fields are dropped. Unknown locations and sources are `-1`, and the modifier
+/// depth is always 0.
+pub fn compress(locations: &[SourceLocation]) -> String {
+    let mut map = String::new();
+    let mut previous: [String; 5] = ["-1", "-1", "-1", "", "-1"].map(str::to_owned);
+    for (i, location) in locations.iter().enumerate() {
+        if i > 0 {
+            map.push(';');
+        }
+        let (start, length, source) = match location.span {
+            Some(span) => (
+                span.start.to_string(),
+                span.length.to_string(),
+                span.source
+                    .map_or("-1".to_owned(), |source| source.to_string()),
+            ),
+            None => ("-1".to_owned(), "-1".to_owned(), "-1".to_owned()),
+        };
+        let fields = [
+            start,
+            length,
+            source,
+            location.jump.symbol().to_string(),
+            "0".to_owned(),
+        ];
+        let changed = (0..5).rev().find(|i| fields[*i] != previous[*i]);
+        if let Some(last) = changed {
+            for (j, field) in fields.iter().enumerate().take(last + 1) {
+                if j > 0 {
+                    map.push(':');
+                }
+                if *field != previous[j] {
+                    write!(map, "{}", field).unwrap();
+                }
+            }
+        }
+        previous = fields;
+    }
+    map
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssa_block::Value;
+    use ir::Literal;
+
+    fn span(start: usize, length: usize) -> Option<Span> {
+        Some(Span {
+            start,
+            length,
+            source: Some(0),
+        })
+    }
+
+    #[test]
+    fn test_compress() {
+        let at = |span, jump| SourceLocation { span, jump };
+        let locations = [
+            at(span(0, 100), Jump::Regular),
+            at(span(0, 100), Jump::Regular),
+            at(span(10, 5), Jump::Regular),
+            at(span(10, 5), Jump::Into),
+            at(None, Jump::Regular),
+            at(span(20, 5), Jump::Out),
+        ];
+        assert_eq!(
+            compress(&locations),
+            "0:100:0:-:0;;10:5;:::i;-1:-1:-1:-;20:5:0:o"
+        );
+        assert_eq!(compress(&[SourceLocation::default()]), ":::-:0");
+    }
+
+    #[test]
+    fn test_op_statements() {
+        let lit: Literal = [0; 32];
+        let block = Block {
+            start_stack: vec![],
+            statements: vec![
+                Statement::ValueAssign {
+                    to: "a".to_owned().into(),
+                    value: Value::Literal(lit),
+                    span: None,
+                },
+                Statement::CallAssign {
+                    assigns: vec!["x".to_owned().into()],
+                    calls: "not".to_owned(),
+                    takes: vec![Value::Literal(lit)],
+                    span: None,
+                },
+                Statement::ValueAssign {
+                    to: "b".to_owned().into(),
+                    value: Value::Literal(lit),
+                    span: None,
+                },
+            ],
+            end_stack: vec!["x".to_owned()],
+        };
+        let ops = [Op::Push(lit), Op::CallFn("not"), Op::Dup(1), Op::Pop];
+        assert_eq!(
+            op_statements(&block, &ops),
+            vec![Some(1), Some(1), Some(2), Some(2)]
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use crate::const_fold::to_literal;
+use crate::debug_info::Location;
+use crate::dialect::{EvmVersion, Unavailable};
+use crate::hybrid_scheduler::{LOAD_GAS, STORE_GAS};
+use crate::memory_layout::MemoryLayout;
+use crate::scheduler::Op;
+use ruint::aliases::U256;
+
+/// Gas of accessing a spill slot, including pushing its address or key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpillCost {
+    pub load: usize,
+    pub store: usize,
+}
+
+impl SpillCost {
+    /// `PUSH slot MLOAD` and `PUSH slot MSTORE`.
+    pub const MEMORY: Self = Self {
+        load: LOAD_GAS,
+        store: STORE_GAS,
+    };
+    /// `PUSH key TLOAD` and `PUSH key TSTORE`, warm access costing 100 each.
+    pub const TRANSIENT: Self = Self {
+        load: 103,
+        store: 103,
+    };
+
+    /// Gas of the stack and slot traffic in `ops`, ignoring the calls themselves.
+    pub fn traffic_gas(&self, ops: &[Op]) -> usize {
+        ops.iter()
+            .map(|op| match op {
+                Op::Swap(_) | Op::Dup(_) | Op::Push(_) => 3,
+                Op::Pop => 2,
+                Op::MemVarLoad(_) => self.load,
+                Op::MemVarStore(_) => self.store,
+                Op::MemSwap(_, _) | Op::MemCopy { .. } => 2 * (self.load + self.store),
+                Op::CallFn(_) => 0,
+            })
+            .sum()
+    }
+}
+
+impl Default for SpillCost {
+    fn default() -> Self {
+        Self::MEMORY
+    }
+}
+
+/// Where the slots of `MemVar*` ops live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpillBackend {
+    /// Slots in memory, cheap but gone once the call frame returns.
+    Memory(MemoryLayout),
+    /// Slots in transient storage at the keys from `base` on, from Cancun. Values stay put
+    /// across external calls, but each access costs about 17 times a memory access and the
+    /// slots are shared with every frame of the contract in the transaction, so code reentered
+    /// while it has values spilled overwrites them.
+    Transient { base: usize },
+}
+
+impl Default for SpillBackend {
+    fn default() -> Self {
+        SpillBackend::Memory(MemoryLayout::default())
+    }
+}
+
+impl From<MemoryLayout> for SpillBackend {
+    fn from(layout: MemoryLayout) -> Self {
+        SpillBackend::Memory(layout)
+    }
+}
+
+impl SpillBackend {
+    pub fn cost(&self) -> SpillCost {
+        match self {
+            SpillBackend::Memory(_) => SpillCost::MEMORY,
+            SpillBackend::Transient { .. } => SpillCost::TRANSIENT,
+        }
+    }
+
+    /// The backend with slot `i` moved to where slot `first + i` is, giving the slots of a
+    /// function a range of their own.
+    pub fn skip(&self, first: usize) -> Self {
+        match *self {
+            SpillBackend::Memory(layout) => SpillBackend::Memory(MemoryLayout {
+                base: layout.base + layout.slot_size * first,
+                ..layout
+            }),
+            SpillBackend::Transient { base } => SpillBackend::Transient { base: base + first },
+        }
+    }
+
+    /// Where the value of slot `slot` lives.
+    pub fn location(&self, slot: usize) -> Location {
+        match self {
+            SpillBackend::Memory(layout) => Location::Memory {
+                offset: layout.address(slot),
+            },
+            SpillBackend::Transient { base } => Location::Transient { key: base + slot },
+        }
+    }
+
+    /// Fails if `version` lacks the builtins of the backend, transient slots needing Cancun.
+    pub fn check(&self, version: EvmVersion) -> Result<(), Unavailable> {
+        match self {
+            SpillBackend::Memory(_) => Ok(()),
+            SpillBackend::Transient { .. } => version.check("tstore"),
+        }
+    }
+
+    /// Ops to run once before any of `slots` slots is used.
+    pub fn prologue(&self, slots: usize) -> Vec<Op<'static>> {
+        match self {
+            SpillBackend::Memory(layout) => layout.prologue(slots),
+            SpillBackend::Transient { .. } => vec![],
+        }
+    }
+
+    /// Ops a `memoryguard` call becomes, see `MemoryLayout::memory_guard`. Transient slots
+    /// take no memory, leaving the call's argument.
+    pub fn memory_guard(&self, slots: usize) -> Vec<Op<'static>> {
+        match self {
+            SpillBackend::Memory(layout) => layout.memory_guard(slots),
+            SpillBackend::Transient { .. } => vec![],
+        }
+    }
+
+    /// Replaces slot ops by accesses of the backend, `TLOAD`/`TSTORE` of `base + slot` for
+    /// transient slots.
+    pub fn lower<'a>(&self, ops: &[Op<'a>], version: EvmVersion) -> Vec<Op<'a>> {
+        let base = match self {
+            SpillBackend::Memory(layout) => return layout.lower(ops, version),
+            SpillBackend::Transient { base } => *base,
+        };
+        let key = |slot: usize| Op::Push(to_literal(U256::from(base + slot)));
+        let load = |slot: usize| [key(slot), Op::CallFn("tload")];
+        let store = |slot: usize| [key(slot), Op::CallFn("tstore")];
+        let mut lowered = vec![];
+        for op in ops {
+            match *op {
+                Op::MemVarLoad(slot) => lowered.extend(load(slot)),
+                Op::MemVarStore(slot) => lowered.extend(store(slot)),
+                Op::MemCopy { from, to } => {
+                    lowered.extend(load(from));
+                    lowered.extend(store(to));
+                }
+                Op::MemSwap(a, b) => {
+                    lowered.extend(load(a));
+                    lowered.extend(load(b));
+                    lowered.extend(store(a));
+                    lowered.extend(store(b));
+                }
+                ref op => lowered.push(op.clone()),
+            }
+        }
+        lowered
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hybrid_scheduler::HybridScheduler;
+    use crate::ssa_block::{Block, Statement};
+
+    /// Reverses 18 values, too deep for the stack alone.
+    fn reverse() -> Block {
+        let names: Vec<String> = (0..18).map(|i| format!("v{}", i)).collect();
+        Block {
+            start_stack: names.clone(),
+            statements: Vec::<Statement>::new(),
+            end_stack: names.iter().rev().cloned().collect(),
+        }
+    }
+
+    #[test]
+    fn test_lower_transient() {
+        let backend = SpillBackend::Transient { base: 5 }.skip(2);
+        let key = |key: u64| Op::Push(to_literal(U256::from(key)));
+        assert!(backend.prologue(3).is_empty());
+        assert_eq!(backend.location(1), Location::Transient { key: 8 });
+        assert_eq!(
+            backend.lower(
+                &[Op::MemVarStore(0), Op::MemCopy { from: 0, to: 1 }, Op::Pop],
+                EvmVersion::Cancun
+            ),
+            vec![
+                key(7),
+                Op::CallFn("tstore"),
+                key(7),
+                Op::CallFn("tload"),
+                key(8),
+                Op::CallFn("tstore"),
+                Op::Pop,
+            ]
+        );
+        assert_eq!(backend.check(EvmVersion::Cancun), Ok(()));
+        assert_eq!(
+            backend.check(EvmVersion::Shanghai),
+            Err(Unavailable {
+                builtin: "tstore".to_owned(),
+                since: EvmVersion::Cancun,
+                version: EvmVersion::Shanghai,
+            })
+        );
+        let memory = SpillBackend::default().skip(2);
+        assert_eq!(memory.check(EvmVersion::Homestead), Ok(()));
+        assert_eq!(
+            memory,
+            SpillBackend::Memory(MemoryLayout::new(0x80, 0x40, 32, true))
+        );
+    }
+
+    #[test]
+    fn test_cost_models() {
+        let block = reverse();
+        let (_, ops) = block.schedule_hybrid();
+        let memory = SpillCost::MEMORY.traffic_gas(&ops);
+        let transient = SpillCost::TRANSIENT.traffic_gas(&ops);
+        assert!(transient > memory);
+        assert_eq!(SpillBackend::default().cost(), SpillCost::MEMORY);
+
+        // Spills chosen for transient slots are no worse there than those chosen for memory.
+        let (_, weighed) = block.schedule_hybrid_with(&SpillCost::TRANSIENT);
+        assert!(SpillCost::TRANSIENT.traffic_gas(&weighed) <= transient);
+
+        // Without spills both cost the same.
+        let ops = [Op::Push(to_literal(U256::from(1))), Op::Pop];
+        assert_eq!(SpillCost::TRANSIENT.traffic_gas(&ops), 5);
+        assert_eq!(SpillCost::MEMORY.traffic_gas(&ops), 5);
+    }
+}
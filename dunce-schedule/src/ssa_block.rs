@@ -1,4 +1,4 @@
-use ir::Literal;
+use ir::{Literal, Span};
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Name {
@@ -36,16 +36,19 @@ impl From<Name> for Value {
     }
 }
 
+/// Statements, located at the `span` of the code they were lowered from, `None` if unknown.
 #[derive(Clone, Debug)]
 pub enum Statement {
     CallAssign {
         assigns: Vec<Name>,
         calls: String,
         takes: Vec<Value>,
+        span: Option<Span>,
     },
     ValueAssign {
         to: Name,
         value: Value,
+        span: Option<Span>,
     },
 }
 
@@ -55,3 +58,43 @@ pub struct Block {
     pub statements: Vec<Statement>,
     pub end_stack: Vec<String>,
 }
+
+impl Statement {
+    /// Names defined by the statement.
+    pub fn defs(&self) -> Vec<&Name> {
+        match self {
+            Statement::CallAssign { assigns, .. } => assigns.iter().collect(),
+            Statement::ValueAssign { to, .. } => vec![to],
+        }
+    }
+
+    /// Names referenced by the statement.
+    pub fn uses(&self) -> Vec<&Name> {
+        let values = match self {
+            Statement::CallAssign { takes, .. } => takes.as_slice(),
+            Statement::ValueAssign { value, .. } => std::slice::from_ref(value),
+        };
+        values
+            .iter()
+            .filter_map(|value| match value {
+                Value::RefName(name) => Some(name),
+                Value::Literal(_) => None,
+            })
+            .collect()
+    }
+
+    /// Span of the code the statement was lowered from.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Statement::CallAssign { span, .. } | Statement::ValueAssign { span, .. } => *span,
+        }
+    }
+
+    /// Name of the called function, if any.
+    pub fn callee(&self) -> Option<&str> {
+        match self {
+            Statement::CallAssign { calls, .. } => Some(calls),
+            Statement::ValueAssign { .. } => None,
+        }
+    }
+}
@@ -0,0 +1,667 @@
+use crate::scheduler::Op;
+use crate::shuffle::{self, ShuffleLimits};
+use crate::ssa_block::{Block, Name, Statement, Value};
+use std::collections::HashMap;
+
+/// Deepest stack item reachable by `DUP16` and `SWAP16`.
+pub const MAX_REACH: usize = 16;
+
+/// A value needed by the schedule sits below the reach of `DUP16`/`SWAP16`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackTooDeep {
+    pub name: Option<Name>,
+    pub depth: usize,
+}
+
+pub trait StackScheduler {
+    /// Schedules the block keeping all values on the operand stack, without memory spills.
+    fn schedule_stack(&self) -> Result<Vec<Op<'_>>, StackTooDeep>;
+}
+
+/// Operand stack of named values, `None` for slots holding dead values. Last item is the top.
+/// Names in `spills` live in the given memory slot and are only loaded onto the stack right
+/// before they are needed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Stack<'a> {
+    pub(crate) slots: Vec<Option<Name>>,
+    pub(crate) ops: Vec<Op<'a>>,
+    pub(crate) spills: HashMap<Name, usize>,
+}
+
+impl<'a> Stack<'a> {
+    pub(crate) fn new(names: &[Name]) -> Self {
+        Self {
+            slots: names.iter().cloned().map(Some).collect(),
+            ops: vec![],
+            spills: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn top(&self) -> Option<&Option<Name>> {
+        self.slots.last()
+    }
+
+    /// Depth of the shallowest copy of `name`, 1 being the top.
+    pub(crate) fn depth_of(&self, name: &Name) -> Option<usize> {
+        self.slots
+            .iter()
+            .rev()
+            .position(|slot| slot.as_ref() == Some(name))
+            .map(|i| i + 1)
+    }
+
+    pub(crate) fn count(&self, name: &Name) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.as_ref() == Some(name))
+            .count()
+    }
+
+    fn too_deep(&self, name: Option<&Name>, depth: usize) -> StackTooDeep {
+        StackTooDeep {
+            name: name.cloned(),
+            depth,
+        }
+    }
+
+    /// Pushes a copy of `name`, duplicating it if it is in reach and loading it otherwise.
+    pub(crate) fn dup(&mut self, name: &Name) -> Result<(), StackTooDeep> {
+        let op = match (self.depth_of(name), self.spills.get(name)) {
+            (Some(depth), _) if depth <= MAX_REACH => Op::Dup(depth),
+            (_, Some(slot)) => Op::MemVarLoad(*slot),
+            (Some(depth), None) => return Err(self.too_deep(Some(name), depth)),
+            (None, None) => panic!("Undefined reference {:?}", name),
+        };
+        self.push(op, Some(name.clone()));
+        Ok(())
+    }
+
+    /// Moves the value on top into its memory slot if its name is spilled.
+    pub(crate) fn store_if_spilled(&mut self) {
+        let slot = match self.top() {
+            Some(Some(name)) => self.spills.get(name).copied(),
+            _ => None,
+        };
+        if let Some(slot) = slot {
+            self.ops.push(Op::MemVarStore(slot));
+            self.slots.pop();
+        }
+    }
+
+    /// Stores the live spilled values among the top `n` slots, swapping each up first.
+    pub(crate) fn store_spilled(
+        &mut self,
+        n: usize,
+        remaining: &HashMap<Name, usize>,
+    ) -> Result<(), StackTooDeep> {
+        loop {
+            self.pop_dead(remaining);
+            let len = self.slots.len();
+            let spilled = (len.saturating_sub(n)..len)
+                .rev()
+                .find(|i| match &self.slots[*i] {
+                    Some(name) => self.spills.contains_key(name) && get_rem(remaining, name) > 0,
+                    None => false,
+                });
+            let Some(i) = spilled else {
+                return Ok(());
+            };
+            if i != len - 1 {
+                self.swap_with(i)?;
+            }
+            self.store_if_spilled();
+        }
+    }
+
+    /// Exchanges the top with the slot at index `i` from the bottom.
+    pub(crate) fn swap_with(&mut self, i: usize) -> Result<(), StackTooDeep> {
+        let top = self.slots.len() - 1;
+        let n = top - i;
+        if n > MAX_REACH {
+            return Err(self.too_deep(self.slots[i].as_ref(), n + 1));
+        }
+        self.ops.push(Op::Swap(n));
+        self.slots.swap(i, top);
+        Ok(())
+    }
+
+    pub(crate) fn pop(&mut self) {
+        self.ops.push(Op::Pop);
+        self.slots.pop();
+    }
+
+    pub(crate) fn push(&mut self, op: Op<'a>, name: Option<Name>) {
+        self.ops.push(op);
+        self.slots.push(name);
+    }
+
+    /// Marks all slots holding `name` as dead, used when it is reassigned.
+    pub(crate) fn kill(&mut self, name: &Name) {
+        self.slots
+            .iter_mut()
+            .filter(|slot| slot.as_ref() == Some(name))
+            .for_each(|slot| *slot = None);
+    }
+
+    pub(crate) fn matches(&self, target: &[Name]) -> bool {
+        self.slots.len() == target.len()
+            && self
+                .slots
+                .iter()
+                .zip(target)
+                .all(|(slot, name)| slot.as_ref() == Some(name))
+    }
+
+    /// Rearranges the stack into exactly `target`, searching for the cheapest sequence if the
+    /// layouts are short.
+    pub(crate) fn shuffle_to(&mut self, target: &[Name]) -> Result<(), StackTooDeep> {
+        self.shuffle_within(target, &ShuffleLimits::default())
+    }
+
+    pub(crate) fn shuffle_within(
+        &mut self,
+        target: &[Name],
+        limits: &ShuffleLimits,
+    ) -> Result<(), StackTooDeep> {
+        match shuffle::search(&self.slots, target, &self.spills, limits) {
+            Some(ops) => {
+                self.ops.extend(ops);
+                self.slots = target.iter().cloned().map(Some).collect();
+                Ok(())
+            }
+            None => self.shuffle_greedy(target),
+        }
+    }
+
+    /// Greedily rearranges the stack into exactly `target` using `Dup`, `Swap` and `Pop`.
+    pub(crate) fn shuffle_greedy(&mut self, target: &[Name]) -> Result<(), StackTooDeep> {
+        let wanted = |name: &Name| target.iter().filter(|t| *t == name).count();
+        let is_placed = |slots: &[Option<Name>], i: usize| {
+            i < target.len() && slots[i].as_ref() == Some(&target[i])
+        };
+
+        for _ in 0..10_000 {
+            let len = self.slots.len();
+            let top_placed = len > 0 && is_placed(&self.slots, len - 1);
+            if let (false, Some(Some(top))) = (top_placed, self.top()) {
+                let wrong_spot = (0..(len - 1).min(target.len()))
+                    .find(|i| target[*i] == *top && !is_placed(&self.slots, *i));
+                if let Some(i) = wrong_spot {
+                    self.swap_with(i)?;
+                    continue;
+                }
+            }
+
+            let surplus = match self.top() {
+                Some(None) => true,
+                Some(Some(name)) => self.count(name) > wanted(name),
+                None => false,
+            };
+            if surplus {
+                self.pop();
+                continue;
+            }
+            if self.matches(target) {
+                return Ok(());
+            }
+
+            let lowest_mismatch = (0..len.min(target.len())).find(|i| !is_placed(&self.slots, *i));
+            let missing = match lowest_mismatch {
+                Some(i) => target[i].clone(),
+                None => target[len].clone(),
+            };
+            let misplaced_copy = (0..len)
+                .rev()
+                .find(|j| self.slots[*j].as_ref() == Some(&missing) && !is_placed(&self.slots, *j));
+            match (lowest_mismatch, misplaced_copy, top_placed) {
+                (Some(i), Some(j), false) if j > i => self.swap_with(j)?,
+                _ => self.dup(&missing)?,
+            }
+        }
+        panic!(
+            "Shuffle from {:?} to {:?} does not terminate",
+            self.slots, target
+        );
+    }
+
+    /// Pops dead values off the top of the stack.
+    pub(crate) fn pop_dead(&mut self, remaining: &HashMap<Name, usize>) {
+        while let Some(slot) = self.top() {
+            let dead = match slot {
+                Some(name) => get_rem(remaining, name) == 0,
+                None => true,
+            };
+            if !dead {
+                break;
+            }
+            self.pop();
+        }
+    }
+}
+
+/// Number of uses of every name in the block, counting the `end_stack` as uses.
+pub(crate) fn use_counts(block: &Block) -> HashMap<Name, usize> {
+    let mut counts: HashMap<Name, usize> = HashMap::new();
+    let end_names = block.end_stack.iter().map(|name| name.into());
+    for name in block
+        .statements
+        .iter()
+        .flat_map(|stmt| stmt.uses().into_iter().cloned())
+        .chain(end_names)
+    {
+        *counts.entry(name).or_default() += 1;
+    }
+    counts
+}
+
+pub(crate) fn get_rem(remaining: &HashMap<Name, usize>, name: &Name) -> usize {
+    remaining.get(name).copied().unwrap_or_default()
+}
+
+/// Places `takes` on top of the stack, first argument on top. Last uses already on top in the
+/// right order are consumed in place, otherwise the deepest argument may be swapped up if it is
+/// a last use. Everything else is duplicated.
+pub(crate) fn prepare_args<'a>(
+    stack: &mut Stack<'a>,
+    takes: &[Value],
+    remaining: &HashMap<Name, usize>,
+) -> Result<(), StackTooDeep> {
+    let n = takes.len();
+    let last_use = |i: usize| match &takes[i] {
+        Value::RefName(name) => {
+            get_rem(remaining, name) == 0
+                && !takes[..i]
+                    .iter()
+                    .any(|other| matches!(other, Value::RefName(other) if other == name))
+        }
+        Value::Literal(_) => false,
+    };
+    let len = stack.slots.len();
+    let in_place = (1..=n.min(len))
+        .rev()
+        .find(|k| {
+            (0..*k).all(|j| {
+                let i = n - 1 - j;
+                last_use(i)
+                    && matches!(&takes[i], Value::RefName(name) if stack.slots[len - k + j].as_ref() == Some(name))
+            })
+        })
+        .unwrap_or(0);
+
+    for (i, value) in takes[..n - in_place].iter().enumerate().rev() {
+        match value {
+            Value::Literal(lit) => stack.push(Op::Push(*lit), None),
+            Value::RefName(name) => {
+                let depth = stack.depth_of(name);
+                match (in_place == 0 && i == n - 1 && last_use(i), depth) {
+                    (true, Some(depth)) if depth - 1 <= MAX_REACH => {
+                        stack.swap_with(stack.slots.len() - depth)?
+                    }
+                    _ => stack.dup(name)?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Schedules `block` onto `stack`, which holds the block's `start_stack` and decides which
+/// names are spilled to memory.
+pub(crate) fn schedule_onto<'a>(
+    block: &'a Block,
+    stack: &mut Stack<'a>,
+) -> Result<(), StackTooDeep> {
+    let mut remaining = use_counts(block);
+    let len = stack.slots.len();
+    stack.store_spilled(len, &remaining)?;
+
+    for stmt in block.statements.iter() {
+        for name in stmt.uses() {
+            *remaining.get_mut(name).unwrap() -= 1;
+        }
+        match stmt {
+            Statement::ValueAssign { to, value, .. } => {
+                if matches!(value, Value::RefName(name) if name == to) {
+                    continue;
+                }
+                stack.kill(to);
+                if get_rem(&remaining, to) == 0 {
+                    continue;
+                }
+                match value {
+                    Value::Literal(lit) => stack.push(Op::Push(*lit), Some(to.clone())),
+                    Value::RefName(name) => {
+                        let spilled_from = stack.spills.get(name).copied();
+                        let spilled_to = stack.spills.get(to).copied();
+                        match (stack.depth_of(name), spilled_from, spilled_to) {
+                            (None, Some(from), Some(to)) => {
+                                stack.ops.push(Op::MemCopy { from, to });
+                            }
+                            (Some(depth), _, None) if get_rem(&remaining, name) == 0 => {
+                                let i = stack.slots.len() - depth;
+                                stack.kill(name);
+                                stack.slots[i] = Some(to.clone());
+                            }
+                            _ => {
+                                stack.dup(name)?;
+                                *stack.slots.last_mut().unwrap() = Some(to.clone());
+                            }
+                        }
+                    }
+                }
+                stack.store_if_spilled();
+            }
+            Statement::CallAssign {
+                assigns,
+                calls,
+                takes,
+                ..
+            } => {
+                prepare_args(stack, takes, &remaining)?;
+                stack.ops.push(Op::CallFn(calls));
+                stack.slots.truncate(stack.slots.len() - takes.len());
+                assigns.iter().for_each(|name| stack.kill(name));
+                stack
+                    .slots
+                    .extend(assigns.iter().map(|name| Some(name.clone())));
+                stack.store_spilled(assigns.len(), &remaining)?;
+            }
+        }
+        stack.pop_dead(&remaining);
+    }
+
+    let end: Vec<Name> = block.end_stack.iter().map(|name| name.into()).collect();
+    stack.shuffle_to(&end)
+}
+
+impl StackScheduler for Block {
+    fn schedule_stack(&self) -> Result<Vec<Op<'_>>, StackTooDeep> {
+        let start: Vec<Name> = self.start_stack.iter().map(|name| name.into()).collect();
+        let mut stack = Stack::new(&start);
+        schedule_onto(self, &mut stack)?;
+        Ok(stack.ops)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::cfg::{Node, Terminator};
+    use crate::const_fold::to_literal;
+    use ruint::aliases::U256;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    pub(crate) fn lit(value: u64) -> Value {
+        Value::Literal(to_literal(U256::from(value)))
+    }
+
+    pub(crate) fn r(name: &str) -> Value {
+        Value::RefName(name.to_owned().into())
+    }
+
+    pub(crate) fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// `to := calls(takes...)`, with `to` empty for calls returning nothing.
+    pub(crate) fn call(to: &[&str], calls: &str, takes: Vec<Value>) -> Statement {
+        Statement::CallAssign {
+            assigns: to.iter().map(|s| s.to_string().into()).collect(),
+            calls: calls.to_owned(),
+            takes,
+            span: None,
+        }
+    }
+
+    /// Names of the functions the statements call, in order.
+    pub(crate) fn calls(block: &Block) -> Vec<&str> {
+        block.statements.iter().filter_map(|s| s.callee()).collect()
+    }
+
+    /// Block starting from `a` and `b`.
+    pub(crate) fn block(statements: Vec<Statement>, end_stack: &[&str]) -> Block {
+        Block {
+            start_stack: names(&["a", "b"]),
+            statements,
+            end_stack: names(end_stack),
+        }
+    }
+
+    pub(crate) fn node(
+        start: &[&str],
+        statements: Vec<Statement>,
+        end: &[&str],
+        exit: Terminator,
+    ) -> Node {
+        Node {
+            block: Block {
+                start_stack: names(start),
+                statements,
+                end_stack: names(end),
+            },
+            exit,
+        }
+    }
+
+    fn eval(calls: &str, args: &[u64], outputs: usize) -> Vec<u64> {
+        (0..outputs)
+            .map(|k| {
+                let mut hasher = DefaultHasher::new();
+                (calls, args, k).hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect()
+    }
+
+    fn lit_value(lit: &ir::Literal) -> u64 {
+        u64::from_be_bytes(lit[24..].try_into().unwrap())
+    }
+
+    /// Executes the block on names, the reference the schedules are checked against.
+    pub(crate) fn interpret(block: &Block, start: &[u64]) -> Vec<u64> {
+        let mut env: HashMap<Name, u64> = block
+            .start_stack
+            .iter()
+            .map(|name| name.into())
+            .zip(start.iter().copied())
+            .collect();
+        let get = |env: &HashMap<Name, u64>, value: &Value| match value {
+            Value::RefName(name) => env[name],
+            Value::Literal(lit) => lit_value(lit),
+        };
+        for stmt in block.statements.iter() {
+            match stmt {
+                Statement::ValueAssign { to, value, .. } => {
+                    let value = get(&env, value);
+                    env.insert(to.clone(), value);
+                }
+                Statement::CallAssign {
+                    assigns,
+                    calls,
+                    takes,
+                    ..
+                } => {
+                    let args: Vec<u64> = takes.iter().map(|value| get(&env, value)).collect();
+                    for (name, value) in assigns.iter().zip(eval(calls, &args, assigns.len())) {
+                        env.insert(name.clone(), value);
+                    }
+                }
+            }
+        }
+        block
+            .end_stack
+            .iter()
+            .map(|name| env[&name.into()])
+            .collect()
+    }
+
+    /// Executes stack ops, taking the arity of calls from the block's statements in order.
+    pub(crate) fn run(block: &Block, ops: &[Op], start: &[u64]) -> Vec<u64> {
+        let mut stack = start.to_vec();
+        let mut memory: HashMap<usize, u64> = HashMap::new();
+        let mut calls = block.statements.iter().filter_map(|stmt| match stmt {
+            Statement::CallAssign { assigns, takes, .. } => Some((assigns.len(), takes.len())),
+            Statement::ValueAssign { .. } => None,
+        });
+        for op in ops {
+            match op {
+                Op::Swap(n) => {
+                    let top = stack.len() - 1;
+                    stack.swap(top, top - n);
+                }
+                Op::Dup(n) => stack.push(stack[stack.len() - n]),
+                Op::Pop => {
+                    stack.pop().unwrap();
+                }
+                Op::Push(lit) => stack.push(lit_value(lit)),
+                Op::MemSwap(a, b) => {
+                    let (va, vb) = (memory[a], memory[b]);
+                    memory.insert(*a, vb);
+                    memory.insert(*b, va);
+                }
+                Op::MemVarLoad(slot) => stack.push(memory[slot]),
+                Op::MemVarStore(slot) => {
+                    memory.insert(*slot, stack.pop().unwrap());
+                }
+                Op::MemCopy { from, to } => {
+                    memory.insert(*to, memory[from]);
+                }
+                Op::CallFn(name) => {
+                    let (outputs, inputs) = calls.next().unwrap();
+                    let args: Vec<u64> = (0..inputs).map(|_| stack.pop().unwrap()).collect();
+                    stack.extend(eval(name, &args, outputs));
+                }
+            }
+        }
+        stack
+    }
+
+    /// Checks that running `ops` leaves what interpreting `block` does, `seed` naming the random
+    /// block in failures.
+    pub(crate) fn assert_schedules_equivalently(seed: u64, block: &Block, ops: &[Op]) {
+        let start: Vec<u64> = (0..block.start_stack.len() as u64)
+            .map(|i| 1000 + i)
+            .collect();
+        assert_eq!(
+            run(block, ops, &start),
+            interpret(block, &start),
+            "Seed {}: {:?} scheduled as {:?}",
+            seed,
+            block,
+            ops
+        );
+    }
+
+    /// The random blocks of the first `count` seeds, growing up to 11 statements.
+    pub(crate) fn random_blocks(count: u64) -> impl Iterator<Item = (u64, Block)> {
+        (0..count).map(|seed| (seed, random_block(seed, (seed % 12) as usize)))
+    }
+
+    /// Deterministic pseudo random blocks over a handful of names.
+    pub(crate) fn random_block(seed: u64, size: usize) -> Block {
+        let mut state = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let mut next = |bound: usize| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((state >> 33) as usize) % bound
+        };
+        let names = ["a", "b", "c", "d", "e", "f", "g"];
+        let name = |i: usize| Name::Ident(names[i].to_owned());
+        let start_len = 1 + next(4);
+        let mut defined: Vec<usize> = (0..start_len).collect();
+        let mut statements = vec![];
+        for _ in 0..size {
+            let to = next(names.len());
+            let value = |next: &mut dyn FnMut(usize) -> usize, defined: &Vec<usize>| {
+                if next(5) == 0 {
+                    let mut lit = [0u8; 32];
+                    lit[31] = next(256) as u8;
+                    Value::Literal(lit)
+                } else {
+                    Value::RefName(name(defined[next(defined.len())]))
+                }
+            };
+            let stmt = if next(4) == 0 {
+                Statement::ValueAssign {
+                    to: name(to),
+                    value: value(&mut next, &defined),
+                    span: None,
+                }
+            } else {
+                let takes = (0..next(4)).map(|_| value(&mut next, &defined)).collect();
+                let mut assigns = vec![name(to)];
+                if next(6) == 0 {
+                    let other = next(names.len());
+                    if other != to {
+                        assigns.push(name(other));
+                    }
+                }
+                Statement::CallAssign {
+                    assigns,
+                    calls: format!("f{}", next(3)),
+                    takes,
+                    span: None,
+                }
+            };
+            for def in stmt.defs() {
+                let Name::Ident(def) = def else {
+                    unreachable!()
+                };
+                let i = names.iter().position(|n| n == def).unwrap();
+                if !defined.contains(&i) {
+                    defined.push(i);
+                }
+            }
+            statements.push(stmt);
+        }
+        let end_len = next(6);
+        let end_stack = (0..end_len)
+            .map(|_| names[defined[next(defined.len())]].to_owned())
+            .collect();
+        Block {
+            start_stack: (0..start_len).map(|i| names[i].to_owned()).collect(),
+            statements,
+            end_stack,
+        }
+    }
+
+    #[test]
+    fn test_schedule_stack_random() {
+        for (seed, block) in random_blocks(500) {
+            let ops = block
+                .schedule_stack()
+                .unwrap_or_else(|err| panic!("Seed {}: {:?}", seed, err));
+            assert_schedules_equivalently(seed, &block, &ops);
+        }
+    }
+
+    #[test]
+    fn test_schedule_stack_consumes_in_place() {
+        let block = block(vec![call(&["c"], "sub", vec![r("b"), r("a")])], &["c"]);
+        assert_eq!(block.schedule_stack(), Ok(vec![Op::CallFn("sub")]));
+
+        let block = Block {
+            end_stack: names(&["b", "c"]),
+            ..block
+        };
+        assert_eq!(
+            block.schedule_stack(),
+            Ok(vec![Op::Swap(1), Op::Dup(2), Op::CallFn("sub")])
+        );
+    }
+
+    #[test]
+    fn test_schedule_stack_too_deep() {
+        let names: Vec<String> = (0..18).map(|i| format!("v{}", i)).collect();
+        let block = Block {
+            start_stack: names.clone(),
+            statements: vec![],
+            end_stack: names.iter().rev().cloned().collect(),
+        };
+        assert!(block.schedule_stack().is_err());
+    }
+}
@@ -0,0 +1,440 @@
+use crate::cfg::{Function, Program};
+use crate::hybrid_scheduler::{traffic_gas, HybridScheduler};
+use crate::reorder::StatementReordering;
+use crate::scheduler::{MemoryScheduler, Op};
+use crate::slot_alloc::SlotAllocator;
+use crate::spill::SpillCost;
+use crate::ssa_block::{Block, Statement};
+use crate::stack_scheduler::{StackScheduler, StackTooDeep};
+use crate::verify::{verify, VerifyError};
+use std::collections::BTreeMap;
+
+/// Output shared by all scheduling strategies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule<'a> {
+    pub ops: Vec<Op<'a>>,
+    /// Number of `MemVar*` slots the ops use.
+    pub slots: usize,
+    /// Stack height after each op, the block's `start_stack` being the height before the first.
+    pub heights: Vec<usize>,
+}
+
+/// Failure to schedule a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleError {
+    StackTooDeep(StackTooDeep),
+    /// The ops of a strategy fail symbolic verification against the block, a bug of the
+    /// strategy.
+    Invalid(VerifyError),
+}
+
+impl From<StackTooDeep> for ScheduleError {
+    fn from(err: StackTooDeep) -> Self {
+        ScheduleError::StackTooDeep(err)
+    }
+}
+
+impl From<VerifyError> for ScheduleError {
+    fn from(err: VerifyError) -> Self {
+        ScheduleError::Invalid(err)
+    }
+}
+
+impl<'a> Schedule<'a> {
+    /// Fails if `ops` fail symbolic verification against the block, so every strategy's output
+    /// is checked, in release builds too.
+    pub fn new(block: &Block, slots: usize, ops: Vec<Op<'a>>) -> Result<Self, VerifyError> {
+        verify(block, &ops)?;
+        let heights = stack_heights(block, &ops);
+        Ok(Self {
+            ops,
+            slots,
+            heights,
+        })
+    }
+
+    pub fn gas(&self) -> usize {
+        traffic_gas(&self.ops)
+    }
+
+    pub fn max_height(&self) -> usize {
+        self.heights.iter().copied().max().unwrap_or(0)
+    }
+}
+
+/// Stack height after each of `ops` scheduled for `block`, taking the arity of calls from the
+/// block's statements in order.
+pub fn stack_heights(block: &Block, ops: &[Op]) -> Vec<usize> {
+    let mut calls = block.statements.iter().filter_map(|stmt| match stmt {
+        Statement::CallAssign {
+            assigns,
+            calls,
+            takes,
+            ..
+        } => Some((calls, takes.len(), assigns.len())),
+        Statement::ValueAssign { .. } => None,
+    });
+    let mut height = block.start_stack.len();
+    ops.iter()
+        .map(|op| {
+            let (pops, pushes) = match op {
+                Op::Swap(_) | Op::MemSwap(..) | Op::MemCopy { .. } => (0, 0),
+                Op::Dup(_) | Op::Push(_) | Op::MemVarLoad(_) => (0, 1),
+                Op::Pop | Op::MemVarStore(_) => (1, 0),
+                Op::CallFn(name) => match calls.next() {
+                    Some((calls, takes, assigns)) if calls == name => (takes, assigns),
+                    _ => panic!("Call of {} not matching the block's statements", name),
+                },
+            };
+            height = height
+                .checked_sub(pops)
+                .unwrap_or_else(|| panic!("Stack underflow at {:?}", op));
+            height += pushes;
+            height
+        })
+        .collect()
+}
+
+pub trait Scheduler {
+    fn schedule<'a>(&self, block: &'a Block) -> Result<Schedule<'a>, ScheduleError>;
+
+    /// Schedules every node of the function, in node order.
+    fn schedule_function<'a>(
+        &self,
+        function: &'a Function,
+    ) -> Result<Vec<Schedule<'a>>, ScheduleError> {
+        function
+            .nodes
+            .iter()
+            .map(|node| self.schedule(&node.block))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Strategy {
+    /// Every value lives in a memory slot, see `MemoryScheduler`.
+    Memory,
+    /// Every value stays on the operand stack, failing if it gets too deep.
+    Stack,
+    /// On the operand stack, spilling to memory only where needed.
+    Hybrid,
+    /// The cheapest schedule by `traffic_gas` of all other strategies, fewer slots breaking
+    /// ties.
+    Optimal,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 4] = [
+        Strategy::Memory,
+        Strategy::Stack,
+        Strategy::Hybrid,
+        Strategy::Optimal,
+    ];
+
+    /// The block next to a copy with its statements in `statement_order`, for comparing every
+    /// strategy on both side by side.
+    pub fn compare(block: &Block) -> Comparison<'_> {
+        let mut reordered = block.clone();
+        reordered.reorder_statements();
+        Comparison { block, reordered }
+    }
+}
+
+/// A block and its reordered copy, see `Strategy::compare`.
+#[derive(Debug, Clone)]
+pub struct Comparison<'a> {
+    pub block: &'a Block,
+    pub reordered: Block,
+}
+
+/// The schedule of one strategy in a `Comparison`.
+#[derive(Debug, Clone)]
+pub struct Candidate<'a> {
+    pub strategy: Strategy,
+    /// The block scheduled, either the compared block or its reordered copy. The ops follow
+    /// its order of statements.
+    pub block: &'a Block,
+    pub reordered: bool,
+    pub schedule: Result<Schedule<'a>, ScheduleError>,
+}
+
+impl Comparison<'_> {
+    /// Every strategy on the block as it is, then every strategy on the reordered copy.
+    pub fn candidates(&self) -> Vec<Candidate<'_>> {
+        [(self.block, false), (&self.reordered, true)]
+            .into_iter()
+            .flat_map(|(block, reordered)| {
+                Strategy::ALL.iter().map(move |strategy| Candidate {
+                    strategy: *strategy,
+                    block,
+                    reordered,
+                    schedule: strategy.schedule(block),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The cheapest of the candidates that are not too deep, failing on invalid ones.
+fn cheapest<'a>(
+    candidates: impl Iterator<Item = Result<Schedule<'a>, ScheduleError>>,
+    cost: &SpillCost,
+) -> Result<Schedule<'a>, ScheduleError> {
+    Ok(candidates
+        .filter(|candidate| !matches!(candidate, Err(ScheduleError::StackTooDeep(_))))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .min_by_key(|schedule| (cost.traffic_gas(&schedule.ops), schedule.slots))
+        .expect("Memory scheduling never fails"))
+}
+
+impl Strategy {
+    /// Schedules the block for slots costing `cost`, which the hybrid and optimal strategies
+    /// weigh spills by.
+    pub fn schedule_with<'a>(
+        &self,
+        block: &'a Block,
+        cost: &SpillCost,
+    ) -> Result<Schedule<'a>, ScheduleError> {
+        match self {
+            Strategy::Memory => {
+                let (slots, ops) = block.schedule_memory();
+                Ok(Schedule::new(block, slots, ops)?)
+            }
+            Strategy::Stack => Ok(Schedule::new(block, 0, block.schedule_stack()?)?),
+            Strategy::Hybrid => {
+                let (slots, ops) = block.schedule_hybrid_with(cost);
+                Ok(Schedule::new(block, slots, ops)?)
+            }
+            Strategy::Optimal => cheapest(
+                [Strategy::Memory, Strategy::Stack, Strategy::Hybrid]
+                    .iter()
+                    .map(|strategy| strategy.schedule_with(block, cost)),
+                cost,
+            ),
+        }
+    }
+
+    /// Memory scheduling shares one slot allocation over the whole function, so the slots of
+    /// identifiers agree between blocks.
+    pub fn schedule_function_with<'a>(
+        &self,
+        function: &'a Function,
+        cost: &SpillCost,
+    ) -> Result<Vec<Schedule<'a>>, ScheduleError> {
+        match self {
+            Strategy::Memory => {
+                let allocation = function.allocate_slots();
+                function
+                    .nodes
+                    .iter()
+                    .enumerate()
+                    .map(|(id, node)| {
+                        let (_, ops) = node.block.schedule_memory_with(&allocation.block_slots(id));
+                        Ok(Schedule::new(&node.block, allocation.count, ops)?)
+                    })
+                    .collect()
+            }
+            _ => function
+                .nodes
+                .iter()
+                .map(|node| self.schedule_with(&node.block, cost))
+                .collect(),
+        }
+    }
+}
+
+/// Schedules for slots in memory.
+impl Scheduler for Strategy {
+    fn schedule<'a>(&self, block: &'a Block) -> Result<Schedule<'a>, ScheduleError> {
+        self.schedule_with(block, &SpillCost::MEMORY)
+    }
+
+    fn schedule_function<'a>(
+        &self,
+        function: &'a Function,
+    ) -> Result<Vec<Schedule<'a>>, ScheduleError> {
+        self.schedule_function_with(function, &SpillCost::MEMORY)
+    }
+}
+
+/// Strategy per function of a program, `main` and functions without an entry taking the
+/// default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrategySelection {
+    pub default: Strategy,
+    pub functions: BTreeMap<String, Strategy>,
+}
+
+impl Default for StrategySelection {
+    fn default() -> Self {
+        Self {
+            default: Strategy::Hybrid,
+            functions: BTreeMap::new(),
+        }
+    }
+}
+
+/// Schedules of a program's blocks, laid out like `cfg::Program`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProgramSchedule<'a> {
+    pub main: Vec<Schedule<'a>>,
+    pub functions: BTreeMap<String, Vec<Schedule<'a>>>,
+}
+
+impl StrategySelection {
+    pub fn with(mut self, function: &str, strategy: Strategy) -> Self {
+        self.functions.insert(function.to_owned(), strategy);
+        self
+    }
+
+    pub fn get(&self, function: &str) -> Strategy {
+        self.functions
+            .get(function)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    pub fn schedule_program<'a>(
+        &self,
+        program: &'a Program,
+    ) -> Result<ProgramSchedule<'a>, ScheduleError> {
+        self.schedule_program_with(program, &SpillCost::MEMORY)
+    }
+
+    /// Schedules the program for slots costing `cost`.
+    pub fn schedule_program_with<'a>(
+        &self,
+        program: &'a Program,
+        cost: &SpillCost,
+    ) -> Result<ProgramSchedule<'a>, ScheduleError> {
+        Ok(ProgramSchedule {
+            main: self.default.schedule_function_with(&program.main, cost)?,
+            functions: program
+                .functions
+                .iter()
+                .map(|(name, function)| {
+                    let schedules = self.get(name).schedule_function_with(function, cost)?;
+                    Ok((name.clone(), schedules))
+                })
+                .collect::<Result<_, ScheduleError>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cfg::{Node, Terminator};
+    use crate::ssa_block::Value;
+    use crate::stack_scheduler::test::{assert_schedules_equivalently, random_blocks};
+
+    #[test]
+    fn test_strategies_random() {
+        for (seed, block) in random_blocks(300) {
+            let comparison = Strategy::compare(&block);
+            let candidates = comparison.candidates();
+            assert_eq!(candidates.len(), 2 * Strategy::ALL.len());
+            for candidate in candidates.iter() {
+                let schedule = candidate.schedule.as_ref().unwrap();
+                assert_eq!(
+                    candidate.reordered,
+                    std::ptr::eq(candidate.block, &comparison.reordered)
+                );
+                assert_schedules_equivalently(seed, candidate.block, &schedule.ops);
+                assert_eq!(schedule.heights.len(), schedule.ops.len());
+                assert_eq!(
+                    schedule.heights.last().copied(),
+                    schedule.ops.last().map(|_| block.end_stack.len())
+                );
+            }
+            for half in candidates.chunks(Strategy::ALL.len()) {
+                let gas = |candidate: &Candidate| candidate.schedule.as_ref().unwrap().gas();
+                assert_eq!(half[3].strategy, Strategy::Optimal);
+                assert!(half.iter().all(|candidate| gas(&half[3]) <= gas(candidate)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_stack_heights() {
+        let block = Block {
+            start_stack: vec!["a".to_owned(), "b".to_owned()],
+            statements: vec![Statement::CallAssign {
+                assigns: vec!["x".to_owned().into()],
+                calls: "add".to_owned(),
+                takes: vec![
+                    Value::RefName("a".to_owned().into()),
+                    Value::RefName("b".to_owned().into()),
+                ],
+                span: None,
+            }],
+            end_stack: vec!["x".to_owned()],
+        };
+        let ops = [Op::Swap(1), Op::CallFn("add"), Op::Dup(1), Op::Pop];
+        assert_eq!(stack_heights(&block, &ops), vec![2, 1, 2, 1]);
+    }
+
+    #[test]
+    fn test_invalid_schedule() {
+        let block = Block {
+            start_stack: vec!["a".to_owned(), "b".to_owned()],
+            statements: vec![],
+            end_stack: vec!["b".to_owned(), "a".to_owned()],
+        };
+        assert!(Schedule::new(&block, 0, vec![Op::Swap(1)]).is_ok());
+        assert!(matches!(
+            Schedule::new(&block, 0, vec![]),
+            Err(VerifyError::WrongEndStack { .. })
+        ));
+        let invalid = Schedule::new(&block, 0, vec![Op::Pop]).map_err(ScheduleError::from);
+        let too_deep = Err(ScheduleError::StackTooDeep(StackTooDeep {
+            name: None,
+            depth: 17,
+        }));
+        assert!(matches!(
+            cheapest([too_deep, invalid].into_iter(), &SpillCost::MEMORY),
+            Err(ScheduleError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_select_per_function() {
+        // Reversing 18 values is too deep for the stack strategy alone.
+        let names: Vec<String> = (0..18).map(|i| format!("v{}", i)).collect();
+        let function = |end_stack: Vec<String>| Function {
+            nodes: vec![Node {
+                block: Block {
+                    start_stack: names.clone(),
+                    statements: vec![],
+                    end_stack,
+                },
+                exit: Terminator::Halt,
+            }],
+        };
+        let program = Program {
+            main: function(names.clone()),
+            functions: BTreeMap::from([
+                (
+                    "deep".to_owned(),
+                    function(names.iter().rev().cloned().collect()),
+                ),
+                ("flat".to_owned(), function(names.clone())),
+            ]),
+        };
+
+        let selection = StrategySelection {
+            default: Strategy::Stack,
+            ..Default::default()
+        };
+        assert!(selection.schedule_program(&program).is_err());
+
+        let selection = selection.with("deep", Strategy::Memory);
+        assert_eq!(selection.get("flat"), Strategy::Stack);
+        let schedules = selection.schedule_program(&program).unwrap();
+        assert_eq!(schedules.main[0].ops, vec![]);
+        assert_eq!(schedules.functions["deep"][0].slots, 18);
+        assert_eq!(schedules.functions["flat"][0].slots, 0);
+    }
+}
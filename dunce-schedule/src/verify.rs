@@ -0,0 +1,289 @@
+use crate::scheduler::Op;
+use crate::ssa_block::{Block, Name, Statement, Value};
+use crate::stack_scheduler::MAX_REACH;
+use ir::Literal;
+use std::collections::HashMap;
+
+/// Value a stack item or memory slot symbolically holds. Names can be reassigned, so items are
+/// told apart by where their value came from rather than by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Symbol {
+    /// Item `i` of the block's `start_stack`, counted from the bottom.
+    Start(usize),
+    /// Return value `i` of the `n`th call of the block.
+    Result(usize, usize),
+    Literal(Literal),
+}
+
+/// Mismatch between scheduled ops and the block they were scheduled for, `op` being the index of
+/// the offending op.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    StackUnderflow {
+        op: usize,
+    },
+    OutOfReach {
+        op: usize,
+        depth: usize,
+    },
+    UninitializedSlot {
+        op: usize,
+        slot: usize,
+    },
+    UnexpectedCall {
+        op: usize,
+        calls: String,
+    },
+    WrongOperand {
+        op: usize,
+        arg: usize,
+        expected: Symbol,
+        found: Symbol,
+    },
+    MissingCalls {
+        count: usize,
+    },
+    WrongEndStack {
+        expected: Vec<Symbol>,
+        found: Vec<Symbol>,
+    },
+}
+
+/// Names of the block mapped to the symbols they currently hold, following the statements.
+struct Env<'a> {
+    names: HashMap<Name, Symbol>,
+    statements: std::slice::Iter<'a, Statement>,
+    calls: usize,
+}
+
+fn symbol(names: &HashMap<Name, Symbol>, value: &Value) -> Symbol {
+    match value {
+        Value::RefName(name) => *names
+            .get(name)
+            .unwrap_or_else(|| panic!("Undefined reference {:?}", name)),
+        Value::Literal(lit) => Symbol::Literal(*lit),
+    }
+}
+
+impl<'a> Env<'a> {
+    fn symbol(&self, value: &Value) -> Symbol {
+        symbol(&self.names, value)
+    }
+
+    /// Applies value assignments up to the next call and returns it.
+    fn next_call(&mut self) -> Option<(&'a str, &'a [Value], &'a [Name])> {
+        for stmt in self.statements.by_ref() {
+            match stmt {
+                Statement::ValueAssign { to, value, .. } => {
+                    let symbol = symbol(&self.names, value);
+                    self.names.insert(to.clone(), symbol);
+                }
+                Statement::CallAssign {
+                    assigns,
+                    calls,
+                    takes,
+                    ..
+                } => return Some((calls, takes, assigns)),
+            }
+        }
+        None
+    }
+}
+
+/// Symbolically executes `ops` from the block's `start_stack`, checking that every `CallFn`
+/// is the block's next call and finds its `takes` on top of the stack, first argument on top,
+/// and that the stack ends up holding the `end_stack`.
+pub fn verify(block: &Block, ops: &[Op]) -> Result<(), VerifyError> {
+    let mut env = Env {
+        names: block
+            .start_stack
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.into(), Symbol::Start(i)))
+            .collect(),
+        statements: block.statements.iter(),
+        calls: 0,
+    };
+    let mut stack: Vec<Symbol> = (0..block.start_stack.len()).map(Symbol::Start).collect();
+    let mut memory: HashMap<usize, Symbol> = HashMap::new();
+
+    for (i, op) in ops.iter().enumerate() {
+        let underflow = VerifyError::StackUnderflow { op: i };
+        // Index of the item `below` items under the top, `n` being the immediate of the op.
+        let reach = |n: usize, below: usize, height: usize| match n {
+            _ if n == 0 || n > MAX_REACH => Err(VerifyError::OutOfReach { op: i, depth: n }),
+            _ if below >= height => Err(VerifyError::StackUnderflow { op: i }),
+            _ => Ok(height - 1 - below),
+        };
+        let load = |memory: &HashMap<usize, Symbol>, slot: &usize| {
+            memory
+                .get(slot)
+                .copied()
+                .ok_or(VerifyError::UninitializedSlot { op: i, slot: *slot })
+        };
+        match op {
+            Op::Swap(n) => {
+                let other = reach(*n, *n, stack.len())?;
+                let top = stack.len() - 1;
+                stack.swap(top, other);
+            }
+            Op::Dup(n) => stack.push(stack[reach(*n, n.saturating_sub(1), stack.len())?]),
+            Op::Pop => {
+                stack.pop().ok_or(underflow)?;
+            }
+            Op::Push(lit) => stack.push(Symbol::Literal(*lit)),
+            Op::MemSwap(a, b) => {
+                let (va, vb) = (load(&memory, a)?, load(&memory, b)?);
+                memory.insert(*a, vb);
+                memory.insert(*b, va);
+            }
+            Op::MemVarLoad(slot) => stack.push(load(&memory, slot)?),
+            Op::MemVarStore(slot) => {
+                memory.insert(*slot, stack.pop().ok_or(underflow)?);
+            }
+            Op::MemCopy { from, to } => {
+                let symbol = load(&memory, from)?;
+                memory.insert(*to, symbol);
+            }
+            Op::CallFn(name) => {
+                let (takes, assigns) = match env.next_call() {
+                    Some((calls, takes, assigns)) if calls == *name => (takes, assigns),
+                    _ => {
+                        return Err(VerifyError::UnexpectedCall {
+                            op: i,
+                            calls: name.to_string(),
+                        })
+                    }
+                };
+                for (arg, value) in takes.iter().enumerate() {
+                    let expected = env.symbol(value);
+                    let found = stack.pop().ok_or(underflow.clone())?;
+                    if found != expected {
+                        return Err(VerifyError::WrongOperand {
+                            op: i,
+                            arg,
+                            expected,
+                            found,
+                        });
+                    }
+                }
+                for (j, name) in assigns.iter().enumerate() {
+                    let symbol = Symbol::Result(env.calls, j);
+                    stack.push(symbol);
+                    env.names.insert(name.clone(), symbol);
+                }
+                env.calls += 1;
+            }
+        }
+    }
+
+    if env.next_call().is_some() {
+        return Err(VerifyError::MissingCalls {
+            count: 1 + env
+                .statements
+                .filter(|stmt| matches!(stmt, Statement::CallAssign { .. }))
+                .count(),
+        });
+    }
+    let expected: Vec<Symbol> = block
+        .end_stack
+        .iter()
+        .map(|name| env.symbol(&Value::RefName(name.into())))
+        .collect();
+    if stack != expected {
+        return Err(VerifyError::WrongEndStack {
+            expected,
+            found: stack,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stack_scheduler::test::random_blocks;
+    use crate::strategy::{Scheduler, Strategy};
+
+    fn block() -> Block {
+        // x := sub(a, b), end stack [x, a].
+        Block {
+            start_stack: vec!["a".to_owned(), "b".to_owned()],
+            statements: vec![Statement::CallAssign {
+                assigns: vec!["x".to_owned().into()],
+                calls: "sub".to_owned(),
+                takes: vec![
+                    Value::RefName("a".to_owned().into()),
+                    Value::RefName("b".to_owned().into()),
+                ],
+                span: None,
+            }],
+            end_stack: vec!["x".to_owned(), "a".to_owned()],
+        }
+    }
+
+    #[test]
+    fn test_verify_strategies() {
+        for (seed, block) in random_blocks(300) {
+            for strategy in Strategy::ALL {
+                let schedule = strategy.schedule(&block).unwrap();
+                assert_eq!(verify(&block, &schedule.ops), Ok(()), "Seed {}", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_operands() {
+        let block = block();
+        let ok = [Op::Dup(2), Op::CallFn("sub"), Op::Swap(1)];
+        assert_eq!(verify(&block, &ok), Ok(()));
+        assert_eq!(
+            verify(&block, &[Op::Dup(1), Op::CallFn("sub"), Op::Swap(1)]),
+            Err(VerifyError::WrongOperand {
+                op: 1,
+                arg: 0,
+                expected: Symbol::Start(0),
+                found: Symbol::Start(1),
+            })
+        );
+        assert_eq!(
+            verify(&block, &[Op::CallFn("add")]),
+            Err(VerifyError::UnexpectedCall {
+                op: 0,
+                calls: "add".to_owned()
+            })
+        );
+        assert_eq!(
+            verify(&block, &[Op::Swap(17)]),
+            Err(VerifyError::OutOfReach { op: 0, depth: 17 })
+        );
+    }
+
+    #[test]
+    fn test_verify_end_and_memory() {
+        let block = block();
+        assert_eq!(
+            verify(&block, &[]),
+            Err(VerifyError::MissingCalls { count: 1 })
+        );
+        assert_eq!(
+            verify(&block, &[Op::MemVarLoad(0)]),
+            Err(VerifyError::UninitializedSlot { op: 0, slot: 0 })
+        );
+        let ops = [
+            Op::MemVarStore(0),
+            Op::MemVarStore(1),
+            Op::MemVarLoad(0),
+            Op::MemVarLoad(1),
+            Op::CallFn("sub"),
+            Op::MemVarLoad(0),
+        ];
+        assert_eq!(
+            verify(&block, &ops),
+            Err(VerifyError::WrongEndStack {
+                expected: vec![Symbol::Result(0, 0), Symbol::Start(0)],
+                found: vec![Symbol::Result(0, 0), Symbol::Start(1)],
+            })
+        );
+    }
+}
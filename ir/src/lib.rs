@@ -1,47 +1,88 @@
+pub mod parse;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct YulObject {
     pub code: Block,
     pub objects: Vec<(String, YulObject)>,
     pub data: Vec<(String, Vec<u8>)>,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Block(pub Vec<Statement>);
 
 pub type Literal = [u8; 32];
 
+/// Byte range of a construct in a source file, like solc's `@src` annotations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub length: usize,
+    /// Index of the source file, `None` for generated code.
+    pub source: Option<usize>,
+}
+
+/// Expressions, calls located at their `span` in the source, `None` if unknown.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Expr {
     VarRef(String),
     Literal(Literal),
-    Call { fn_name: String, args: Vec<Expr> },
-    Builtin { fn_name: String, input: String },
+    Call {
+        fn_name: String,
+        args: Vec<Expr>,
+        span: Option<Span>,
+    },
+    Builtin {
+        fn_name: String,
+        input: String,
+        span: Option<Span>,
+    },
+}
+
+impl Expr {
+    /// Where the expression is located, `None` for variables and literals.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Expr::Call { span, .. } | Expr::Builtin { span, .. } => *span,
+            Expr::VarRef(_) | Expr::Literal(_) => None,
+        }
+    }
 }
 
+/// Statements, those evaluating expressions located at their `span` in the source, `None` if
+/// unknown.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Statement {
     Block(Block),
     FnDef(FunctionDefinition),
     Assignment {
         to: Vec<String>,
         expr: Expr,
+        span: Option<Span>,
     },
     If {
         cond: Expr,
         body: Block,
+        span: Option<Span>,
     },
     Switch {
         cond: Expr,
         cases: Vec<(Literal, Block)>,
         default: Option<Block>,
+        span: Option<Span>,
     },
     ForLoop {
         setup: Block,
         cond: Expr,
         on_iter: Block,
         body: Block,
+        span: Option<Span>,
     },
     Leave,
     Break,
     Continue,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FunctionDefinition {
     pub name: String,
     pub args: Vec<String>,
@@ -0,0 +1,736 @@
+use crate::{Block, Expr, FunctionDefinition, Literal, Span, Statement, YulObject};
+use ruint::aliases::U256;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Builtins taking a literal string, parsed into `Expr::Builtin`. `setimmutable` also takes
+/// other arguments, which `Expr::Builtin` cannot hold, so it is rejected.
+const LITERAL_BUILTINS: &[&str] = &["dataoffset", "datasize", "loadimmutable", "linkersymbol"];
+
+const KEYWORDS: &[&str] = &[
+    "function", "let", "if", "switch", "case", "default", "for", "break", "continue", "leave",
+    "true", "false", "hex",
+];
+
+/// Longest first, so `:=` is not taken for `:`.
+const PUNCTUATION: &[&str] = &[":=", "->", "{", "}", "(", ")", ",", ":"];
+
+/// Yul source that does not parse.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset in the source the error was found at.
+    pub offset: usize,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    /// Identifiers and keywords.
+    Ident(String),
+    Number(Literal),
+    /// Bytes of a string literal, those of `hex` strings decoded.
+    String(Vec<u8>),
+    Punct(&'static str),
+}
+
+fn error<T>(offset: usize, message: impl Into<String>) -> Result<T, ParseError> {
+    Err(ParseError {
+        offset,
+        message: message.into(),
+    })
+}
+
+fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_' || c == b'$'
+}
+
+fn is_ident_part(c: u8) -> bool {
+    is_ident_start(c) || c.is_ascii_digit() || c == b'.'
+}
+
+/// Splits the source into tokens with their byte ranges, skipping whitespace and comments.
+fn tokenize(source: &str) -> Result<Vec<(Range<usize>, Token)>, ParseError> {
+    let bytes = source.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let rest = &source[i..];
+        let token = match bytes[i] {
+            c if c.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            _ if rest.starts_with("//") => {
+                i += rest.find('\n').unwrap_or(rest.len());
+                continue;
+            }
+            _ if rest.starts_with("/*") => match rest.find("*/") {
+                Some(end) => {
+                    i += end + 2;
+                    continue;
+                }
+                None => return error(start, "Unterminated comment"),
+            },
+            c if is_ident_start(c) => {
+                while i < bytes.len() && is_ident_part(bytes[i]) {
+                    i += 1;
+                }
+                match &source[start..i] {
+                    "hex" if matches!(bytes.get(i), Some(b'"' | b'\'')) => {
+                        let (contents, end) = quoted(source, i)?;
+                        i = end;
+                        Token::String(decode_hex(&contents, start)?)
+                    }
+                    ident => Token::Ident(ident.to_owned()),
+                }
+            }
+            c if c.is_ascii_digit() => {
+                while i < bytes.len() && bytes[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+                let digits = &source[start..i];
+                let value = match digits.strip_prefix("0x") {
+                    Some(hex) => U256::from_str_radix(hex, 16),
+                    None => U256::from_str_radix(digits, 10),
+                };
+                match value {
+                    Ok(value) => Token::Number(value.to_be_bytes()),
+                    Err(_) => return error(start, format!("Invalid number {}", digits)),
+                }
+            }
+            b'"' | b'\'' => {
+                let (contents, end) = quoted(source, i)?;
+                i = end;
+                Token::String(unescape(&contents, start)?)
+            }
+            _ => match PUNCTUATION.iter().find(|punct| rest.starts_with(*punct)) {
+                Some(&punct) => {
+                    i += punct.len();
+                    Token::Punct(punct)
+                }
+                None => {
+                    let c = rest.chars().next().unwrap_or_default();
+                    return error(start, format!("Unexpected character {:?}", c));
+                }
+            },
+        };
+        tokens.push((start..i, token));
+    }
+    Ok(tokens)
+}
+
+/// Contents of the string literal quoted at `start`, escapes kept, and the offset past it.
+fn quoted(source: &str, start: usize) -> Result<(String, usize), ParseError> {
+    let bytes = source.as_bytes();
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() && bytes[i] != quote {
+        if bytes[i] == b'\n' {
+            break;
+        }
+        i += if bytes[i] == b'\\' { 2 } else { 1 };
+    }
+    match bytes.get(i) {
+        Some(c) if *c == quote => Ok((source[start + 1..i].to_owned(), i + 1)),
+        _ => error(start, "Unterminated string literal"),
+    }
+}
+
+fn unescape(contents: &str, offset: usize) -> Result<Vec<u8>, ParseError> {
+    let mut bytes = vec![];
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some(c @ ('\\' | '"' | '\'')) => bytes.push(c as u8),
+            Some('x') => {
+                let digits: String = chars.by_ref().take(2).collect();
+                bytes.extend(decode_hex(&digits, offset)?);
+            }
+            other => return error(offset, format!("Invalid escape {:?}", other)),
+        }
+    }
+    Ok(bytes)
+}
+
+fn decode_hex(digits: &str, offset: usize) -> Result<Vec<u8>, ParseError> {
+    let digits: Vec<u8> = digits.bytes().filter(|digit| *digit != b'_').collect();
+    if !digits.len().is_multiple_of(2) {
+        return error(offset, "Odd number of hex digits");
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .map_or_else(|| error(offset, "Invalid hex digit"), Ok)
+        })
+        .collect()
+}
+
+struct Parser {
+    tokens: Vec<(Range<usize>, Token)>,
+    pos: usize,
+    /// Length of the source, the offset of errors at its end.
+    end: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Self, ParseError> {
+        Ok(Self {
+            tokens: tokenize(source)?,
+            pos: 0,
+            end: source.len(),
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.end, |(range, _)| range.start)
+    }
+
+    /// Span from `start` to the end of the last token consumed, in source 0.
+    fn span_from(&self, start: usize) -> Option<Span> {
+        let end = self.tokens[self.pos - 1].0.end;
+        Some(Span {
+            start,
+            length: end - start,
+            source: Some(0),
+        })
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, ParseError> {
+        let found = match self.peek() {
+            Some(token) => format!("{:?}", token),
+            None => "end of source".to_owned(),
+        };
+        error(
+            self.offset(),
+            format!("Expected {}, found {}", expected, found),
+        )
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword)
+    }
+
+    /// Consumes `punct` if it comes next.
+    fn eat(&mut self, punct: &str) -> bool {
+        let found = self.is_punct(punct);
+        self.pos += found as usize;
+        found
+    }
+
+    /// Consumes the keyword if it comes next.
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        self.pos += found as usize;
+        found
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), ParseError> {
+        match self.eat(punct) {
+            true => Ok(()),
+            false => self.unexpected(&format!("`{}`", punct)),
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        match self.eat_keyword(keyword) {
+            true => Ok(()),
+            false => self.unexpected(&format!("`{}`", keyword)),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(Token::Ident(ident)) if !KEYWORDS.contains(&ident.as_str()) => {
+                let ident = ident.clone();
+                self.pos += 1;
+                Ok(ident)
+            }
+            _ => self.unexpected("identifier"),
+        }
+    }
+
+    /// Identifier with an optional type, like `x:u256`, the type ignored.
+    fn typed_ident(&mut self) -> Result<String, ParseError> {
+        let ident = self.ident()?;
+        if self.eat(":") {
+            self.ident()?;
+        }
+        Ok(ident)
+    }
+
+    /// Comma separated identifiers, at least one.
+    fn typed_idents(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut idents = vec![self.typed_ident()?];
+        while self.eat(",") {
+            idents.push(self.typed_ident()?);
+        }
+        Ok(idents)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, ParseError> {
+        match self.peek() {
+            Some(Token::String(bytes)) => {
+                let bytes = bytes.clone();
+                self.pos += 1;
+                Ok(bytes)
+            }
+            _ => self.unexpected("string literal"),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, ParseError> {
+        let offset = self.offset();
+        String::from_utf8(self.string()?).or_else(|_| error(offset, "Name is not UTF-8"))
+    }
+
+    /// `object "name" { code { ... } ... }` with its sub-objects and data sections.
+    fn object(&mut self) -> Result<(String, YulObject), ParseError> {
+        self.expect_keyword("object")?;
+        let name = self.name()?;
+        self.expect("{")?;
+        self.expect_keyword("code")?;
+        let mut object = YulObject {
+            code: self.block()?,
+            objects: vec![],
+            data: vec![],
+        };
+        while !self.eat("}") {
+            if self.is_keyword("object") {
+                object.objects.push(self.object()?);
+            } else if self.eat_keyword("data") {
+                object.data.push((self.name()?, self.string()?));
+            } else {
+                return self.unexpected("`object`, `data` or `}`");
+            }
+        }
+        Ok((name, object))
+    }
+
+    fn block(&mut self) -> Result<Block, ParseError> {
+        self.expect("{")?;
+        let mut statements = vec![];
+        while !self.eat("}") {
+            self.statement(&mut statements)?;
+        }
+        Ok(Block(statements))
+    }
+
+    /// Parses a statement into `out`, `let` of several variables without a value becoming an
+    /// assignment of zero to each.
+    fn statement(&mut self, out: &mut Vec<Statement>) -> Result<(), ParseError> {
+        let start = self.offset();
+        let keyword = match self.peek() {
+            Some(Token::Punct("{")) => {
+                out.push(Statement::Block(self.block()?));
+                return Ok(());
+            }
+            Some(Token::Ident(ident)) => ident.clone(),
+            _ => return self.unexpected("statement"),
+        };
+        let statement = match keyword.as_str() {
+            "function" => {
+                self.pos += 1;
+                let name = self.ident()?;
+                self.expect("(")?;
+                let args = match self.is_punct(")") {
+                    true => vec![],
+                    false => self.typed_idents()?,
+                };
+                self.expect(")")?;
+                let rets = match self.eat("->") {
+                    true => self.typed_idents()?,
+                    false => vec![],
+                };
+                Statement::FnDef(FunctionDefinition {
+                    name,
+                    args,
+                    rets,
+                    body: self.block()?,
+                })
+            }
+            "let" => {
+                self.pos += 1;
+                let to = self.typed_idents()?;
+                if !self.eat(":=") {
+                    let span = self.span_from(start);
+                    out.extend(to.into_iter().map(|name| Statement::Assignment {
+                        to: vec![name],
+                        expr: Expr::Literal([0; 32]),
+                        span,
+                    }));
+                    return Ok(());
+                }
+                let expr = self.expr()?;
+                Statement::Assignment {
+                    to,
+                    expr,
+                    span: self.span_from(start),
+                }
+            }
+            "if" => {
+                self.pos += 1;
+                let cond = self.expr()?;
+                let body = self.block()?;
+                Statement::If {
+                    cond,
+                    body,
+                    span: self.span_from(start),
+                }
+            }
+            "switch" => {
+                self.pos += 1;
+                let cond = self.expr()?;
+                let mut cases = vec![];
+                while self.eat_keyword("case") {
+                    cases.push((self.literal()?, self.block()?));
+                }
+                let default = match self.eat_keyword("default") {
+                    true => Some(self.block()?),
+                    false => None,
+                };
+                if cases.is_empty() && default.is_none() {
+                    return self.unexpected("`case` or `default`");
+                }
+                Statement::Switch {
+                    cond,
+                    cases,
+                    default,
+                    span: self.span_from(start),
+                }
+            }
+            "for" => {
+                self.pos += 1;
+                let setup = self.block()?;
+                let cond = self.expr()?;
+                let on_iter = self.block()?;
+                let body = self.block()?;
+                Statement::ForLoop {
+                    setup,
+                    cond,
+                    on_iter,
+                    body,
+                    span: self.span_from(start),
+                }
+            }
+            "break" | "continue" | "leave" => {
+                self.pos += 1;
+                match keyword.as_str() {
+                    "break" => Statement::Break,
+                    "continue" => Statement::Continue,
+                    _ => Statement::Leave,
+                }
+            }
+            _ if matches!(self.tokens.get(self.pos + 1), Some((_, Token::Punct("(")))) => {
+                let expr = self.expr()?;
+                Statement::Assignment {
+                    to: vec![],
+                    expr,
+                    span: self.span_from(start),
+                }
+            }
+            _ => {
+                let mut to = vec![self.ident()?];
+                while self.eat(",") {
+                    to.push(self.ident()?);
+                }
+                self.expect(":=")?;
+                let expr = self.expr()?;
+                Statement::Assignment {
+                    to,
+                    expr,
+                    span: self.span_from(start),
+                }
+            }
+        };
+        out.push(statement);
+        Ok(())
+    }
+
+    /// Number, string, `true` or `false` with an optional type, strings left aligned.
+    fn literal(&mut self) -> Result<Literal, ParseError> {
+        let offset = self.offset();
+        let literal = match self.peek() {
+            Some(Token::Number(literal)) => *literal,
+            Some(Token::String(bytes)) if bytes.len() > 32 => {
+                return error(offset, "String literal longer than 32 bytes")
+            }
+            Some(Token::String(bytes)) => {
+                let mut literal = [0; 32];
+                literal[..bytes.len()].copy_from_slice(bytes);
+                literal
+            }
+            Some(Token::Ident(ident)) if ident == "true" || ident == "false" => {
+                let mut literal = [0; 32];
+                literal[31] = (ident == "true") as u8;
+                literal
+            }
+            _ => return self.unexpected("literal"),
+        };
+        self.pos += 1;
+        if self.eat(":") {
+            self.ident()?;
+        }
+        Ok(literal)
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        let offset = self.offset();
+        let fn_name = match self.peek() {
+            Some(Token::Ident(ident)) if ident != "true" && ident != "false" => self.ident()?,
+            _ => return Ok(Expr::Literal(self.literal()?)),
+        };
+        if !self.eat("(") {
+            return Ok(Expr::VarRef(fn_name));
+        }
+        if LITERAL_BUILTINS.contains(&fn_name.as_str()) {
+            let input = self.name()?;
+            self.expect(")")?;
+            return Ok(Expr::Builtin {
+                fn_name,
+                input,
+                span: self.span_from(offset),
+            });
+        }
+        if fn_name == "setimmutable" {
+            return error(offset, "setimmutable is not supported");
+        }
+        let mut args = vec![];
+        if !self.eat(")") {
+            args.push(self.expr()?);
+            while self.eat(",") {
+                args.push(self.expr()?);
+            }
+            self.expect(")")?;
+        }
+        Ok(Expr::Call {
+            fn_name,
+            args,
+            span: self.span_from(offset),
+        })
+    }
+
+    fn finish<T>(&self, parsed: T) -> Result<T, ParseError> {
+        match self.peek() {
+            None => Ok(parsed),
+            Some(_) => self.unexpected("end of source"),
+        }
+    }
+}
+
+/// Parses an `object` with its sub-objects and data sections, or a plain block as the code of
+/// an object without any.
+/// Spans are byte ranges of the parsed text, as source 0.
+impl FromStr for YulObject {
+    type Err = ParseError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(source)?;
+        let object = match parser.is_keyword("object") {
+            true => parser.object()?.1,
+            false => YulObject {
+                code: parser.block()?,
+                objects: vec![],
+                data: vec![],
+            },
+        };
+        parser.finish(object)
+    }
+}
+
+impl FromStr for Block {
+    type Err = ParseError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(source)?;
+        let block = parser.block()?;
+        parser.finish(block)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn number(value: u64) -> Expr {
+        Expr::Literal(U256::from(value).to_be_bytes())
+    }
+
+    fn call(fn_name: &str, args: Vec<Expr>, span: Option<Span>) -> Expr {
+        Expr::Call {
+            fn_name: fn_name.to_owned(),
+            args,
+            span,
+        }
+    }
+
+    /// Span of the first occurrence of `text` in `source`.
+    fn find(source: &str, text: &str) -> Option<Span> {
+        Some(Span {
+            start: source.find(text).unwrap(),
+            length: text.len(),
+            source: Some(0),
+        })
+    }
+
+    #[test]
+    fn test_parse_object() {
+        let source = r#"
+            /* Deploys "runtime". */
+            object "C" {
+                code { return(0, datasize("runtime")) }
+                object "runtime" {
+                    code { stop() }
+                    data "meta" hex"aa_bb"
+                }
+                data "text" "a\x62\n"
+            }
+        "#;
+        let object: YulObject = source.parse().unwrap();
+        let at = |text| find(source, text);
+        let size = Expr::Builtin {
+            fn_name: "datasize".to_owned(),
+            input: "runtime".to_owned(),
+            span: at(r#"datasize("runtime")"#),
+        };
+        let expr_statement = |expr, span| Statement::Assignment {
+            to: vec![],
+            expr,
+            span,
+        };
+        let ret = r#"return(0, datasize("runtime"))"#;
+        assert_eq!(
+            object.code,
+            Block(vec![expr_statement(
+                call("return", vec![number(0), size], at(ret)),
+                at(ret)
+            )])
+        );
+        assert_eq!(object.data, vec![("text".to_owned(), b"ab\n".to_vec())]);
+        let (name, runtime) = &object.objects[0];
+        assert_eq!(name, "runtime");
+        assert_eq!(
+            runtime.code,
+            Block(vec![expr_statement(
+                call("stop", vec![], at("stop()")),
+                at("stop()")
+            )])
+        );
+        assert_eq!(runtime.data, vec![("meta".to_owned(), vec![0xaa, 0xbb])]);
+
+        // A plain block is the code of an object without sub-objects.
+        let plain: YulObject = "{ }".parse().unwrap();
+        assert_eq!(plain.code, Block(vec![]));
+        assert!(plain.objects.is_empty());
+    }
+
+    #[test]
+    fn test_parse_statements() {
+        let source = r#"{
+            let a, b:u256
+            a, b := f(0x10, "ab", true)
+            function f(x, y, z) -> r, s { leave }
+            for { let i := 0 } lt(i, 10) { i := add(i, 1) } { break continue }
+            switch a case 1 { } default { }
+            if b { }
+        }"#;
+        let block: Block = source.parse().unwrap();
+        let at = |text| find(source, text);
+        let mut ab = [0; 32];
+        ab[..2].copy_from_slice(b"ab");
+        let assign = |to: &[&str], expr, span| Statement::Assignment {
+            to: to.iter().map(|name| name.to_string()).collect(),
+            expr,
+            span,
+        };
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        assert_eq!(
+            block.0,
+            vec![
+                assign(&["a"], number(0), at("let a, b:u256")),
+                assign(&["b"], number(0), at("let a, b:u256")),
+                assign(
+                    &["a", "b"],
+                    call(
+                        "f",
+                        vec![number(16), Expr::Literal(ab), number(1)],
+                        at(r#"f(0x10, "ab", true)"#)
+                    ),
+                    at(r#"a, b := f(0x10, "ab", true)"#)
+                ),
+                Statement::FnDef(FunctionDefinition {
+                    name: "f".to_owned(),
+                    args: names(&["x", "y", "z"]),
+                    rets: names(&["r", "s"]),
+                    body: Block(vec![Statement::Leave]),
+                }),
+                Statement::ForLoop {
+                    setup: Block(vec![assign(&["i"], number(0), at("let i := 0"))]),
+                    cond: call(
+                        "lt",
+                        vec![Expr::VarRef("i".to_owned()), number(10)],
+                        at("lt(i, 10)")
+                    ),
+                    on_iter: Block(vec![assign(
+                        &["i"],
+                        call(
+                            "add",
+                            vec![Expr::VarRef("i".to_owned()), number(1)],
+                            at("add(i, 1)")
+                        ),
+                        at("i := add(i, 1)")
+                    )]),
+                    body: Block(vec![Statement::Break, Statement::Continue]),
+                    span: at("for { let i := 0 } lt(i, 10) { i := add(i, 1) } { break continue }"),
+                },
+                Statement::Switch {
+                    cond: Expr::VarRef("a".to_owned()),
+                    cases: vec![(U256::from(1).to_be_bytes(), Block(vec![]))],
+                    default: Some(Block(vec![])),
+                    span: at("switch a case 1 { } default { }"),
+                },
+                Statement::If {
+                    cond: Expr::VarRef("b".to_owned()),
+                    body: Block(vec![]),
+                    span: at("if b { }"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let offset = |source: &str| source.parse::<Block>().unwrap_err().offset;
+        assert_eq!(offset("{ let x := }"), 11);
+        assert_eq!(offset("{ let if := 1 }"), 6);
+        assert_eq!(offset("{ x := \"open }"), 7);
+        assert_eq!(offset("{ setimmutable(0, \"x\", 1) }"), 2);
+        assert_eq!(offset("{ x := 0x1g }"), 7);
+        assert_eq!(offset("{ } }"), 4);
+        assert_eq!(offset("{ switch x }"), 11);
+        let err = "{".parse::<Block>().unwrap_err();
+        assert_eq!(err.offset, 1);
+        assert_eq!(err.message, "Expected statement, found end of source");
+    }
+}